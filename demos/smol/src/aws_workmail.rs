@@ -7,11 +7,10 @@ cargo run -p async-smtp-lite-demo-smol --bin aws_workmail us-west-2 foo@example.
 use std::env;
 use std::io;
 
-use async_net::TcpStream;
 use futures_lite::future::block_on;
 
 use async_smtp_lite::lettre::{ClientId, Credentials, Message, DEFAULT_MECHANISMS};
-use async_smtp_lite::{AsyncClient, AsyncConnection, AsyncTlsClientTlsUpgrader};
+use async_smtp_lite::{AsyncClient, AsyncTlsClientTlsUpgrader, ConnectOptions, SmolRuntime};
 
 fn main() -> io::Result<()> {
     block_on(run())
@@ -28,7 +27,6 @@ async fn run() -> io::Result<()> {
         .nth(3)
         .unwrap_or_else(|| env::var("PASSWORD").unwrap_or_else(|_| "123456".to_owned()));
 
-    let is_smtps = true;
     let hello_name = ClientId::new(username.clone());
     let mechanisms = DEFAULT_MECHANISMS;
     let credentials = Credentials::new(username.clone(), password.clone());
@@ -37,27 +35,24 @@ async fn run() -> io::Result<()> {
     let endpoint = format!("smtp.mail.{}.awsapps.com", region);
     let port: u16 = 465;
 
-    println!("endpoint: {}", endpoint);
-    let addr = format!("{}:{}", endpoint.clone(), port);
-    println!("addr: {}", addr);
+    println!("endpoint: {} port: {}", endpoint, port);
 
-    let stream = TcpStream::connect(addr).await?;
+    // Implicit TLS on 465
+    let options = ConnectOptions::<SmolRuntime>::new(hello_name);
 
-    let connection = AsyncConnection::with_async_tls_upgrader(
-        stream,
+    let mut client = AsyncClient::connect(
+        &endpoint,
+        port,
         AsyncTlsClientTlsUpgrader::new(Default::default(), endpoint.clone()),
-    );
-    let mut client = AsyncClient::new(connection);
-
-    client
-        .handshake(is_smtps, hello_name)
-        .await
-        .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+        &options,
+    )
+    .await
+    .map_err(io::Error::other)?;
 
     let mut session = client
         .auth(mechanisms, &credentials)
         .await
-        .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+        .map_err(io::Error::other)?;
 
     println!("server_info: {}", session.connection.server_info());
 
@@ -68,10 +63,7 @@ async fn run() -> io::Result<()> {
         .body("foo")
         .unwrap();
 
-    session
-        .send(&email)
-        .await
-        .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+    session.send(&email).await.map_err(io::Error::other)?;
 
     println!("done");
 
@@ -17,11 +17,10 @@ cargo run -p async-smtp-lite-demo-smol --bin gmail xxx@gmail.com '123456'
 use std::env;
 use std::io;
 
-use async_net::TcpStream;
 use futures_lite::future::block_on;
 
 use async_smtp_lite::lettre::{ClientId, Credentials, Message, DEFAULT_MECHANISMS};
-use async_smtp_lite::{AsyncClient, AsyncConnection, AsyncTlsClientTlsUpgrader};
+use async_smtp_lite::{AsyncClient, AsyncTlsClientTlsUpgrader, ConnectOptions, SmolRuntime};
 
 fn main() -> io::Result<()> {
     block_on(run())
@@ -37,32 +36,29 @@ async fn run() -> io::Result<()> {
 
     //
     for port in [465_u16, 587].iter() {
-        let is_smtps = port == &465;
         let hello_name = ClientId::new("lettre".to_owned());
         let credentials = Credentials::new(username.clone(), password.clone());
         let mechanisms = DEFAULT_MECHANISMS;
 
         let endpoint = "smtp.gmail.com".to_owned();
-        let addr = format!("{}:{}", endpoint.clone(), port);
-        println!("addr: {}", addr);
+        println!("endpoint: {} port: {}", endpoint, port);
 
-        let stream = TcpStream::connect(addr).await?;
+        // Implicit TLS on 465, STARTTLS on 587
+        let options = ConnectOptions::<SmolRuntime>::new(hello_name);
 
-        let connection = AsyncConnection::with_async_tls_upgrader(
-            stream,
+        let mut client = AsyncClient::connect(
+            &endpoint,
+            *port,
             AsyncTlsClientTlsUpgrader::new(Default::default(), endpoint.clone()),
-        );
-        let mut client = AsyncClient::new(connection);
-
-        client
-            .handshake(is_smtps, hello_name)
-            .await
-            .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+            &options,
+        )
+        .await
+        .map_err(io::Error::other)?;
 
         let mut session = client
             .auth(mechanisms, &credentials)
             .await
-            .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+            .map_err(io::Error::other)?;
 
         println!("server_info: {}", session.connection.server_info());
 
@@ -73,10 +69,7 @@ async fn run() -> io::Result<()> {
             .body("foo")
             .unwrap();
 
-        session
-            .send(&email)
-            .await
-            .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+        session.send(&email).await.map_err(io::Error::other)?;
     }
 
     println!("done");
@@ -0,0 +1,464 @@
+use std::io;
+
+use crate::dkim::{
+    body_hash, canonicalize_header, canonicalize_headers, sign_header, signed_names, split_message,
+    timestamp, DkimCanonicalization, DkimKey, Header, DEFAULT_HEADERS,
+};
+
+// ref https://tools.ietf.org/html/rfc8617#section-4.2.1
+const MAX_INSTANCE: u32 = 50;
+
+const AUTHENTICATION_RESULTS: &str = "ARC-Authentication-Results";
+const MESSAGE_SIGNATURE: &str = "ARC-Message-Signature";
+const SEAL: &str = "ARC-Seal";
+
+/// The caller's validation of the incoming chain, see RFC 8617 section 5.2.
+// ref https://tools.ietf.org/html/rfc8617#section-4.1.3
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ArcChainValidation {
+    /// No ARC set in the incoming message.
+    None,
+    Pass,
+    Fail,
+}
+
+impl ArcChainValidation {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::None => "none",
+            Self::Pass => "pass",
+            Self::Fail => "fail",
+        }
+    }
+}
+
+/// Adds an ARC set, e.g. before relaying a message, with the same keys as DKIM.
+// ref https://tools.ietf.org/html/rfc8617#section-5.1
+#[derive(Debug)]
+pub struct ArcSealer {
+    authserv_id: String,
+    domain: String,
+    selector: String,
+    key: DkimKey,
+    headers: Vec<String>,
+}
+
+impl ArcSealer {
+    /// `authserv_id` is the name in the ARC-Authentication-Results, usually the host name.
+    pub fn new(
+        authserv_id: impl Into<String>,
+        domain: impl Into<String>,
+        selector: impl Into<String>,
+        key: DkimKey,
+    ) -> Self {
+        let mut headers: Vec<String> = DEFAULT_HEADERS.iter().map(ToString::to_string).collect();
+        headers.push("DKIM-Signature".to_owned());
+        Self {
+            authserv_id: authserv_id.into(),
+            domain: domain.into(),
+            selector: selector.into(),
+            key,
+            headers,
+        }
+    }
+
+    /// The headers the ARC-Message-Signature signs when present, `From` is always signed.
+    pub fn headers(mut self, headers: &[&str]) -> Self {
+        self.headers = headers.iter().map(ToString::to_string).collect();
+        self
+    }
+
+    /// The message with the new ARC set prepended. `results` are the authentication results
+    /// of the incoming message, e.g. `spf=pass smtp.mailfrom=example.com; dkim=pass
+    /// header.d=example.com`.
+    pub fn seal(
+        &self,
+        message: &[u8],
+        chain: ArcChainValidation,
+        results: &str,
+    ) -> io::Result<Vec<u8>> {
+        let (headers, body) = split_message(message);
+        let sets = arc_sets(&headers)?;
+
+        if let Some((_, _, seal)) = sets.last() {
+            if tag(seal.raw, "cv").as_deref() == Some("fail") {
+                return Err(invalid("the ARC chain has failed, it can not be extended"));
+            }
+        }
+        let instance = sets.len() as u32 + 1;
+        if instance > MAX_INSTANCE {
+            return Err(invalid("too many ARC sets"));
+        }
+        // The first set has no chain to validate
+        match (instance, chain) {
+            (1, ArcChainValidation::None) => {}
+            (1, _) => return Err(invalid("there is no incoming ARC chain to validate")),
+            (_, ArcChainValidation::None) => {
+                return Err(invalid("the incoming ARC chain must be validated"));
+            }
+            _ => {}
+        }
+
+        let canonicalization = DkimCanonicalization::Relaxed;
+
+        // ref https://tools.ietf.org/html/rfc8617#section-4.1.1
+        let authentication_results = format!(
+            "{}: i={}; {};\r\n\t{}\r\n",
+            AUTHENTICATION_RESULTS, instance, self.authserv_id, results
+        );
+
+        // ref https://tools.ietf.org/html/rfc8617#section-4.1.2
+        let names = signed_names(&headers, &self.headers, false);
+        let message_signature = sign_header(
+            MESSAGE_SIGNATURE,
+            &[
+                format!("i={}", instance),
+                format!("a={}", self.key.algorithm()),
+                "c=relaxed/relaxed".to_owned(),
+                format!("d={}", self.domain),
+                format!("s={}", self.selector),
+                format!("t={}", timestamp()),
+                format!("h={}", names.join(":").to_ascii_lowercase()),
+                format!("bh={}", body_hash(body, canonicalization)),
+            ],
+            canonicalize_headers(&headers, &names, canonicalization),
+            canonicalization,
+            &self.key,
+        )?;
+
+        // All the sets in the instance order, then the new one
+        // ref https://tools.ietf.org/html/rfc8617#section-5.1.1
+        let mut data = vec![];
+        for (authentication_results, message_signature, seal) in &sets {
+            for header in &[authentication_results, message_signature, seal] {
+                data.extend_from_slice(
+                    canonicalize_header(header.raw, canonicalization).as_bytes(),
+                );
+            }
+        }
+        for header in &[&authentication_results, &message_signature] {
+            data.extend_from_slice(canonicalize_header(header, canonicalization).as_bytes());
+        }
+        let seal = sign_header(
+            SEAL,
+            &[
+                format!("i={}", instance),
+                format!("a={}", self.key.algorithm()),
+                format!("cv={}", chain.as_str()),
+                format!("d={}", self.domain),
+                format!("s={}", self.selector),
+                format!("t={}", timestamp()),
+            ],
+            data,
+            canonicalization,
+            &self.key,
+        )?;
+
+        let mut sealed = seal.into_bytes();
+        sealed.extend_from_slice(message_signature.as_bytes());
+        sealed.extend_from_slice(authentication_results.as_bytes());
+        sealed.extend_from_slice(message);
+        Ok(sealed)
+    }
+}
+
+type ArcSet<'a, 'b> = (&'b Header<'a>, &'b Header<'a>, &'b Header<'a>);
+
+// The existing sets by instance, they must be complete and numbered from 1 without gaps
+// ref https://tools.ietf.org/html/rfc8617#section-5.1.1
+fn arc_sets<'a, 'b>(headers: &'b [Header<'a>]) -> io::Result<Vec<ArcSet<'a, 'b>>> {
+    let find = |name: &str, instance: u32| -> io::Result<Option<&'b Header<'a>>> {
+        let mut found = headers
+            .iter()
+            .filter(|header| header.is(name) && instance_of(header) == Some(instance));
+        match (found.next(), found.next()) {
+            (Some(_), Some(_)) => Err(invalid("duplicate ARC header")),
+            (header, _) => Ok(header),
+        }
+    };
+
+    // Up to the highest instance of any of the three, so none is left over
+    let mut count = 0;
+    for header in headers.iter().filter(|header| {
+        header.is(AUTHENTICATION_RESULTS) || header.is(MESSAGE_SIGNATURE) || header.is(SEAL)
+    }) {
+        match instance_of(header) {
+            Some(instance) if (1..=MAX_INSTANCE).contains(&instance) => count = count.max(instance),
+            _ => return Err(invalid("invalid ARC instance")),
+        }
+    }
+
+    let mut sets = vec![];
+    for instance in 1..=count {
+        match (
+            find(AUTHENTICATION_RESULTS, instance)?,
+            find(MESSAGE_SIGNATURE, instance)?,
+            find(SEAL, instance)?,
+        ) {
+            (Some(results), Some(signature), Some(seal)) => sets.push((results, signature, seal)),
+            _ => return Err(invalid("incomplete ARC set")),
+        }
+    }
+    Ok(sets)
+}
+
+fn instance_of(header: &Header<'_>) -> Option<u32> {
+    tag(header.raw, "i")?.parse().ok()
+}
+
+// `tag=value` in a tag list, `i=1; authserv-id` for the ARC-Authentication-Results
+fn tag(raw: &str, name: &str) -> Option<String> {
+    let (_, value) = raw.split_once(':')?;
+    value.split(';').find_map(|tag| {
+        let (tag_name, tag_value) = tag.split_once('=')?;
+        if tag_name.trim() == name {
+            Some(tag_value.split_whitespace().collect())
+        } else {
+            None
+        }
+    })
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use ring::digest::{digest, SHA256};
+    use ring::signature::{KeyPair, UnparsedPublicKey, ED25519};
+
+    use super::*;
+
+    const SEED: [u8; 32] = [7; 32];
+    const MESSAGE: &[u8] = b"From: a@example.com\r\n\
+To: b@example.org\r\n\
+Subject: Hello\r\n\
+\r\n\
+Hi there\r\n";
+    const RESULTS: &str = "spf=pass smtp.mailfrom=example.com";
+
+    fn sealer() -> ArcSealer {
+        ArcSealer::new(
+            "mx.example.org",
+            "example.org",
+            "arc",
+            DkimKey::ed25519_seed(&SEED).unwrap(),
+        )
+    }
+
+    fn header<'a, 'b>(headers: &'b [Header<'a>], name: &str, instance: u32) -> &'b Header<'a> {
+        headers
+            .iter()
+            .find(|header| header.is(name) && instance_of(header) == Some(instance))
+            .unwrap()
+    }
+
+    // Ed25519 signs the SHA-256 of the data, the header itself is signed with an empty `b=`
+    fn verify_signature(header: &Header<'_>, mut data: Vec<u8>) -> bool {
+        let unsigned = &header.raw[..header.raw.rfind("b=").unwrap() + 2];
+        data.extend_from_slice(
+            canonicalize_header(unsigned, DkimCanonicalization::Relaxed)
+                .trim_end_matches("\r\n")
+                .as_bytes(),
+        );
+
+        let key_pair = ring::signature::Ed25519KeyPair::from_seed_unchecked(&SEED).unwrap();
+        let public_key = UnparsedPublicKey::new(&ED25519, key_pair.public_key().as_ref());
+        let b = base64::decode(tag(header.raw, "b").unwrap()).unwrap();
+        public_key
+            .verify(digest(&SHA256, &data).as_ref(), &b)
+            .is_ok()
+    }
+
+    // The ARC-Message-Signature and the ARC-Seal of `instance`
+    // ref https://tools.ietf.org/html/rfc8617#section-5.2
+    fn verify(message: &[u8], instance: u32) -> bool {
+        let (headers, body) = split_message(message);
+
+        let message_signature = header(&headers, MESSAGE_SIGNATURE, instance);
+        if tag(message_signature.raw, "bh").unwrap()
+            != body_hash(body, DkimCanonicalization::Relaxed)
+        {
+            return false;
+        }
+        let h = tag(message_signature.raw, "h").unwrap();
+        let names: Vec<&str> = h.split(':').collect();
+        let data = canonicalize_headers(&headers, &names, DkimCanonicalization::Relaxed);
+        if !verify_signature(message_signature, data) {
+            return false;
+        }
+
+        let seal = header(&headers, SEAL, instance);
+        let mut data = vec![];
+        for i in 1..=instance {
+            for name in &[AUTHENTICATION_RESULTS, MESSAGE_SIGNATURE, SEAL] {
+                let header = header(&headers, name, i);
+                if i < instance || *name != SEAL {
+                    data.extend_from_slice(
+                        canonicalize_header(header.raw, DkimCanonicalization::Relaxed).as_bytes(),
+                    );
+                }
+            }
+        }
+        verify_signature(seal, data)
+    }
+
+    fn seal_error(message: &[u8], chain: ArcChainValidation) -> String {
+        let err = sealer().seal(message, chain, RESULTS).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        err.to_string()
+    }
+
+    #[test]
+    fn seals_and_verifies() {
+        let sealed = sealer()
+            .seal(MESSAGE, ArcChainValidation::None, RESULTS)
+            .unwrap();
+
+        let (headers, _) = split_message(&sealed);
+        assert!(headers[0].is(SEAL));
+        assert!(headers[1].is(MESSAGE_SIGNATURE));
+        assert_eq!(
+            headers[2].raw,
+            "ARC-Authentication-Results: i=1; mx.example.org;\r\n\tspf=pass smtp.mailfrom=example.com\r\n"
+        );
+        assert_eq!(tag(headers[0].raw, "cv").as_deref(), Some("none"));
+        assert_eq!(tag(headers[1].raw, "h").as_deref(), Some("from:subject:to"));
+        assert!(sealed.ends_with(MESSAGE));
+        assert!(verify(&sealed, 1));
+
+        // A change to the body or to a signed header breaks the set
+        let tampered = [&sealed[..], b"More\r\n"].concat();
+        assert!(!verify(&tampered, 1));
+        let text = String::from_utf8(sealed.clone()).unwrap();
+        assert!(!verify(
+            text.replace("Subject: Hello", "Subject: Bye").as_bytes(),
+            1
+        ));
+    }
+
+    #[test]
+    fn extends_the_chain() {
+        let first = sealer()
+            .seal(MESSAGE, ArcChainValidation::None, RESULTS)
+            .unwrap();
+        let second = sealer()
+            .seal(&first, ArcChainValidation::Pass, "arc=pass")
+            .unwrap();
+
+        let (headers, _) = split_message(&second);
+        assert_eq!(arc_sets(&headers).unwrap().len(), 2);
+        assert_eq!(
+            tag(header(&headers, SEAL, 2).raw, "cv").as_deref(),
+            Some("pass")
+        );
+        assert!(verify(&second, 1));
+        assert!(verify(&second, 2));
+
+        // The seal covers the previous sets
+        let text = String::from_utf8(second).unwrap();
+        let tampered = text.replace("i=1; mx.example.org", "i=1; mx.example.net");
+        assert!(!verify(tampered.as_bytes(), 2));
+    }
+
+    #[test]
+    fn rejects_validation_without_a_chain() {
+        for chain in &[ArcChainValidation::Pass, ArcChainValidation::Fail] {
+            assert_eq!(
+                seal_error(MESSAGE, *chain),
+                "there is no incoming ARC chain to validate"
+            );
+        }
+    }
+
+    #[test]
+    fn requires_validation_to_extend() {
+        let sealed = sealer()
+            .seal(MESSAGE, ArcChainValidation::None, RESULTS)
+            .unwrap();
+        assert_eq!(
+            seal_error(&sealed, ArcChainValidation::None),
+            "the incoming ARC chain must be validated"
+        );
+    }
+
+    #[test]
+    fn does_not_extend_a_failed_chain() {
+        let first = sealer()
+            .seal(MESSAGE, ArcChainValidation::None, RESULTS)
+            .unwrap();
+        let failed = sealer()
+            .seal(&first, ArcChainValidation::Fail, "arc=fail")
+            .unwrap();
+        let (headers, _) = split_message(&failed);
+        assert_eq!(
+            tag(header(&headers, SEAL, 2).raw, "cv").as_deref(),
+            Some("fail")
+        );
+
+        assert_eq!(
+            seal_error(&failed, ArcChainValidation::Fail),
+            "the ARC chain has failed, it can not be extended"
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_sets() {
+        let sealed = sealer()
+            .seal(MESSAGE, ArcChainValidation::None, RESULTS)
+            .unwrap();
+        let text = String::from_utf8(sealed).unwrap();
+        let (headers, _) = split_message(text.as_bytes());
+        let [seal, message_signature, results] =
+            [&headers[0], &headers[1], &headers[2]].map(|header| header.raw.to_owned());
+        let renumbered =
+            |raw: &str, instance: u32| raw.replacen("i=1", &format!("i={}", instance), 1);
+
+        for (message, error) in [
+            // A set without its seal
+            (
+                format!("{}{}", results, String::from_utf8_lossy(MESSAGE)),
+                "incomplete ARC set",
+            ),
+            // A gap
+            (
+                format!(
+                    "{}{}{}{}",
+                    renumbered(&seal, 2),
+                    renumbered(&message_signature, 2),
+                    renumbered(&results, 2),
+                    String::from_utf8_lossy(MESSAGE)
+                ),
+                "incomplete ARC set",
+            ),
+            // An extra ARC-Message-Signature
+            (
+                format!("{}{}", renumbered(&message_signature, 2), text),
+                "incomplete ARC set",
+            ),
+            // An extra ARC-Authentication-Results
+            (
+                format!("{}{}", renumbered(&results, 2), text),
+                "incomplete ARC set",
+            ),
+            (
+                format!("{}{}", message_signature, text),
+                "duplicate ARC header",
+            ),
+            (
+                format!("{}{}", renumbered(&seal, 0), text),
+                "invalid ARC instance",
+            ),
+            (
+                format!("{}{}", renumbered(&seal, 51), text),
+                "invalid ARC instance",
+            ),
+        ] {
+            assert_eq!(
+                seal_error(message.as_bytes(), ArcChainValidation::Pass),
+                error
+            );
+        }
+    }
+}
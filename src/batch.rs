@@ -0,0 +1,166 @@
+use std::future::Future;
+use std::result;
+use std::sync::Arc;
+
+use async_stream_packed::TlsClientUpgrader;
+use futures_util::io::{AsyncRead, AsyncWrite};
+use futures_util::lock::Mutex;
+use futures_util::stream::{self, Stream, StreamExt};
+use lettre::message::Message;
+use lettre::transport::smtp::error::Error;
+use lettre::Envelope;
+
+use crate::connection::AsyncConnection;
+use crate::receipt::SendReceipt;
+
+#[derive(Debug)]
+pub struct BatchOutcome {
+    /// The position of the message in the input stream.
+    pub index: usize,
+    pub envelope: Envelope,
+    pub result: result::Result<SendReceipt, Error>,
+}
+
+/// Spreads messages over several connections, `connect` should return a connection ready to
+/// send, i.e. handshaked and authenticated.
+pub struct BatchSender<F> {
+    connect: F,
+    connections: usize,
+    messages_per_connection: usize,
+}
+
+impl<F, Fut, S, STU> BatchSender<F>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = result::Result<AsyncConnection<S, STU>, Error>>,
+    STU: TlsClientUpgrader<S> + Unpin,
+    S: AsyncRead + AsyncWrite + Unpin,
+    STU::Output: AsyncRead + AsyncWrite + Unpin,
+{
+    pub fn new(connect: F) -> Self {
+        Self {
+            connect,
+            connections: 1,
+            messages_per_connection: 100,
+        }
+    }
+
+    pub fn connections(mut self, connections: usize) -> Self {
+        self.connections = connections.max(1);
+        self
+    }
+
+    /// The connection is closed and a new one is made after that many messages.
+    pub fn messages_per_connection(mut self, messages_per_connection: usize) -> Self {
+        self.messages_per_connection = messages_per_connection.max(1);
+        self
+    }
+
+    /// The outcomes are in completion order, a failed message does not stop the batch. A
+    /// connection is replaced after a failure.
+    pub fn send<M>(self, messages: M) -> impl Stream<Item = BatchOutcome>
+    where
+        M: Stream<Item = Message> + Unpin,
+    {
+        let connect = Arc::new(self.connect);
+        let messages = Arc::new(Mutex::new((messages, 0)));
+        let messages_per_connection = self.messages_per_connection;
+
+        stream::select_all((0..self.connections).map(|_| {
+            let worker = Worker {
+                connect: connect.clone(),
+                messages: messages.clone(),
+                connection: None,
+                sent: 0,
+                messages_per_connection,
+            };
+            Box::pin(stream::unfold(worker, Worker::next))
+        }))
+    }
+}
+
+struct Worker<F, M, S, STU>
+where
+    STU: TlsClientUpgrader<S>,
+{
+    connect: Arc<F>,
+    messages: Arc<Mutex<(M, usize)>>,
+    connection: Option<AsyncConnection<S, STU>>,
+    sent: usize,
+    messages_per_connection: usize,
+}
+
+impl<F, Fut, M, S, STU> Worker<F, M, S, STU>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = result::Result<AsyncConnection<S, STU>, Error>>,
+    M: Stream<Item = Message> + Unpin,
+    STU: TlsClientUpgrader<S> + Unpin,
+    S: AsyncRead + AsyncWrite + Unpin,
+    STU::Output: AsyncRead + AsyncWrite + Unpin,
+{
+    async fn next(mut self) -> Option<(BatchOutcome, Self)> {
+        let next = {
+            let mut messages = self.messages.lock().await;
+            let (stream, index) = &mut *messages;
+            stream.next().await.map(|message| {
+                *index += 1;
+                (*index - 1, message)
+            })
+        };
+
+        let (index, message) = match next {
+            Some(next) => next,
+            None => {
+                self.close().await;
+                return None;
+            }
+        };
+
+        let envelope = message.envelope().clone();
+        let result = self.send(&envelope, &message.formatted()).await;
+
+        Some((
+            BatchOutcome {
+                index,
+                envelope,
+                result,
+            },
+            self,
+        ))
+    }
+
+    async fn send(
+        &mut self,
+        envelope: &Envelope,
+        email: &[u8],
+    ) -> result::Result<SendReceipt, Error> {
+        if self.connection.as_ref().is_some_and(|connection| {
+            connection.has_broken()
+                || self.sent >= self.messages_per_connection
+                || connection.message_limit_reached()
+        }) {
+            self.close().await;
+        }
+
+        let connection = match &mut self.connection {
+            Some(connection) => connection,
+            None => {
+                let connection = (self.connect)().await?;
+                self.sent = 0;
+                self.connection.insert(connection)
+            }
+        };
+
+        self.sent += 1;
+        connection.send_with_receipt(envelope, email).await
+    }
+
+    async fn close(&mut self) {
+        if let Some(mut connection) = self.connection.take() {
+            if !connection.has_broken() {
+                let _ = connection.quit().await;
+            }
+        }
+    }
+}
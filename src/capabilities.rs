@@ -0,0 +1,257 @@
+use std::fmt;
+
+use lettre::transport::smtp::response::Response;
+
+// ref https://tools.ietf.org/html/rfc5321#section-4.1.1.1
+#[derive(Default, Clone, PartialEq, Eq, Debug)]
+pub struct Capabilities {
+    name: String,
+    /// The keywords uppercased, the parameters as sent.
+    keywords: Vec<(String, Vec<String>)>,
+}
+
+impl Capabilities {
+    /// From the EHLO (or LHLO) response, the first line is the server name and the greeting.
+    pub fn from_response(response: &Response) -> Self {
+        let mut lines = response.message.iter();
+        let name = lines
+            .next()
+            .and_then(|line| line.split_whitespace().next())
+            .unwrap_or_default()
+            .to_owned();
+
+        let keywords = lines
+            .filter_map(|line| {
+                let mut words = line.split_whitespace();
+                let keyword = words.next()?;
+                // `AUTH=LOGIN PLAIN`, sent by some servers for old clients
+                let (keyword, first) = match keyword.split_once('=') {
+                    Some((keyword, first)) => (keyword, Some(first)),
+                    None => (keyword, None),
+                };
+                let params = first
+                    .into_iter()
+                    .chain(words)
+                    .filter(|param| !param.is_empty())
+                    .map(ToOwned::to_owned)
+                    .collect();
+                Some((keyword.to_ascii_uppercase(), params))
+            })
+            .fold(
+                vec![],
+                |mut keywords: Vec<(String, Vec<String>)>, (keyword, params)| {
+                    match keywords.iter_mut().find(|(k, _)| k == &keyword) {
+                        Some((_, existing)) => {
+                            for param in params {
+                                if !existing.contains(&param) {
+                                    existing.push(param);
+                                }
+                            }
+                        }
+                        None => keywords.push((keyword, params)),
+                    }
+                    keywords
+                },
+            );
+
+        Self { name, keywords }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn keywords(&self) -> impl Iterator<Item = &str> {
+        self.keywords.iter().map(|(keyword, _)| keyword.as_str())
+    }
+
+    pub fn has(&self, keyword: &str) -> bool {
+        self.params(keyword).is_some()
+    }
+
+    /// `None` when the keyword is not advertised.
+    pub fn params(&self, keyword: &str) -> Option<&[String]> {
+        self.keywords
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(keyword))
+            .map(|(_, params)| params.as_slice())
+    }
+
+    /// 0 means no fixed limit.
+    // ref https://tools.ietf.org/html/rfc1870#section-4
+    pub fn size(&self) -> Option<u64> {
+        let params = self.params("SIZE")?;
+        Some(
+            params
+                .first()
+                .and_then(|size| size.parse().ok())
+                .unwrap_or(0),
+        )
+    }
+
+    /// Uppercased, e.g. `["LOGIN", "PLAIN", "XOAUTH2"]`.
+    pub fn auth_mechanisms(&self) -> Vec<String> {
+        self.params("AUTH")
+            .unwrap_or_default()
+            .iter()
+            .map(|mechanism| mechanism.to_ascii_uppercase())
+            .collect()
+    }
+
+    pub fn starttls(&self) -> bool {
+        self.has("STARTTLS")
+    }
+
+    pub fn pipelining(&self) -> bool {
+        self.has("PIPELINING")
+    }
+
+    pub fn chunking(&self) -> bool {
+        self.has("CHUNKING")
+    }
+
+    pub fn binary_mime(&self) -> bool {
+        self.has("BINARYMIME")
+    }
+
+    pub fn eight_bit_mime(&self) -> bool {
+        self.has("8BITMIME")
+    }
+
+    pub fn smtputf8(&self) -> bool {
+        self.has("SMTPUTF8")
+    }
+
+    pub fn dsn(&self) -> bool {
+        self.has("DSN")
+    }
+
+    pub fn enhanced_status_codes(&self) -> bool {
+        self.has("ENHANCEDSTATUSCODES")
+    }
+
+    pub fn requiretls(&self) -> bool {
+        self.has("REQUIRETLS")
+    }
+}
+
+// `mx.example.com [SIZE 35882577, 8BITMIME, AUTH LOGIN PLAIN]`
+impl fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} [", self.name)?;
+        for (i, (keyword, params)) in self.keywords.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}", keyword)?;
+            for param in params {
+                write!(f, " {}", param)?;
+            }
+        }
+        write!(f, "]")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn capabilities(lines: &[&str]) -> Capabilities {
+        let last = lines.len() - 1;
+        let response = lines
+            .iter()
+            .enumerate()
+            .map(|(i, line)| format!("250{}{}\r\n", if i == last { ' ' } else { '-' }, line))
+            .collect::<String>();
+        Capabilities::from_response(&response.parse().unwrap())
+    }
+
+    #[test]
+    fn parses_the_keywords() {
+        let capabilities = capabilities(&[
+            "mx.example.com Hello client.example.com",
+            "SIZE 35882577",
+            "8BITMIME",
+            "pipelining",
+            "STARTTLS",
+            "ENHANCEDSTATUSCODES",
+            "SMTPUTF8",
+        ]);
+
+        assert_eq!(capabilities.name(), "mx.example.com");
+        assert_eq!(capabilities.size(), Some(35882577));
+        assert!(capabilities.eight_bit_mime());
+        assert!(capabilities.pipelining());
+        assert!(capabilities.starttls());
+        assert!(capabilities.enhanced_status_codes());
+        assert!(capabilities.smtputf8());
+        assert!(!capabilities.chunking());
+        assert!(!capabilities.binary_mime());
+        assert!(!capabilities.dsn());
+        assert!(!capabilities.requiretls());
+        assert_eq!(
+            capabilities.keywords().collect::<Vec<_>>(),
+            [
+                "SIZE",
+                "8BITMIME",
+                "PIPELINING",
+                "STARTTLS",
+                "ENHANCEDSTATUSCODES",
+                "SMTPUTF8"
+            ]
+        );
+        assert_eq!(
+            capabilities.to_string(),
+            "mx.example.com [SIZE 35882577, 8BITMIME, PIPELINING, STARTTLS, \
+             ENHANCEDSTATUSCODES, SMTPUTF8]"
+        );
+    }
+
+    #[test]
+    fn merges_the_auth_keywords() {
+        let capabilities =
+            capabilities(&["mx.example.com", "AUTH=LOGIN", "AUTH LOGIN PLAIN xoauth2"]);
+
+        assert_eq!(
+            capabilities.auth_mechanisms(),
+            ["LOGIN", "PLAIN", "XOAUTH2"]
+        );
+        assert_eq!(capabilities.keywords().count(), 1);
+    }
+
+    #[test]
+    fn size_without_a_limit() {
+        assert_eq!(capabilities(&["mx.example.com", "SIZE"]).size(), Some(0));
+        assert_eq!(capabilities(&["mx.example.com", "SIZE 0"]).size(), Some(0));
+        assert_eq!(capabilities(&["mx.example.com", "SIZE x"]).size(), Some(0));
+        assert_eq!(capabilities(&["mx.example.com"]).size(), None);
+    }
+
+    #[test]
+    fn looks_up_case_insensitively() {
+        let capabilities = capabilities(&["mx.example.com", "dsn", "Chunking"]);
+
+        assert!(capabilities.has("DSN"));
+        assert!(capabilities.has("dsn"));
+        assert!(capabilities.dsn());
+        assert!(capabilities.chunking());
+        assert_eq!(capabilities.params("chunking"), Some(&[][..]));
+        assert_eq!(capabilities.params("BINARYMIME"), None);
+    }
+
+    #[test]
+    fn keeps_vendor_keywords() {
+        let capabilities = capabilities(&[
+            "mx.example.com",
+            "XCLIENT NAME ADDR PROTO HELO",
+            "X-EXPS GSSAPI NTLM",
+        ]);
+
+        assert_eq!(
+            capabilities.params("xclient").unwrap(),
+            ["NAME", "ADDR", "PROTO", "HELO"]
+        );
+        assert!(capabilities.has("X-EXPS"));
+        assert!(capabilities.auth_mechanisms().is_empty());
+    }
+}
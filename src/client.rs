@@ -9,6 +9,7 @@ use lettre::transport::smtp::{
 };
 
 use crate::connection::AsyncConnection;
+use crate::connector::{ConnectOptions, Runtime};
 use crate::session::AsyncSession;
 
 pub struct AsyncClient<S, STU>
@@ -25,6 +26,14 @@ where
     pub fn new(connection: AsyncConnection<S, STU>) -> Self {
         Self { connection }
     }
+
+    pub fn connection(&self) -> &AsyncConnection<S, STU> {
+        &self.connection
+    }
+
+    pub fn into_connection(self) -> AsyncConnection<S, STU> {
+        self.connection
+    }
 }
 
 impl<S, STU> AsyncClient<S, STU>
@@ -33,6 +42,40 @@ where
     S: AsyncRead + AsyncWrite + Unpin,
     STU::Output: AsyncRead + AsyncWrite + Unpin,
 {
+    /// Resolves `host`, connects and handshakes, the upgrader should be for `host`.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(name = "smtp.connect", skip_all, fields(host = %host, port = port))
+    )]
+    pub async fn connect<R>(
+        host: &str,
+        port: u16,
+        upgrader: STU,
+        options: &ConnectOptions<R>,
+    ) -> result::Result<Self, Error>
+    where
+        R: Runtime<TcpStream = S> + 'static,
+    {
+        let permit = options.connection_permit(host).await;
+        let stream = options.connect_tcp(host, port).await?;
+
+        let local_addr = R::local_addr(&stream).ok();
+
+        let mut connection = AsyncConnection::new(stream, upgrader);
+        if let Some(addr) = local_addr {
+            connection.set_local_addr(addr);
+        }
+        options.configure(host, &mut connection);
+        connection.set_connection_permit(permit);
+
+        let mut client = Self::new(connection);
+        client
+            .handshake(options.tls.is_smtps(port), options.hello_name.clone())
+            .await?;
+
+        Ok(client)
+    }
+
     pub async fn handshake(
         &mut self,
         is_smtps: bool,
@@ -41,8 +84,8 @@ where
         self.connection.handshake(is_smtps, hello_name).await
     }
 
-    pub async fn auth<'a>(
-        &'a mut self,
+    pub async fn auth(
+        &mut self,
         mechanisms: &[Mechanism],
         credentials: &Credentials,
     ) -> result::Result<AsyncSession<'_, S, STU>, Error> {
@@ -1,10 +1,17 @@
+#[cfg(feature = "dkim")]
+use std::borrow::Cow;
 use std::fmt;
 use std::io;
+use std::net::SocketAddr;
+#[cfg(all(unix, any(feature = "smol", feature = "tokio")))]
+use std::path::Path;
 use std::result;
 use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 
-use async_stream_packed::{TlsClientUpgrader, UpgradableAsyncStream};
-use futures_util::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use async_stream_packed::TlsClientUpgrader;
+use futures_util::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use lettre::transport::smtp::{
     authentication::{Credentials, Mechanism},
     commands::*,
@@ -12,16 +19,41 @@ use lettre::transport::smtp::{
     extension::{ClientId, Extension, MailBodyParameter, MailParameter, ServerInfo},
     response::Response,
 };
-use lettre::Envelope;
+use lettre::{Address, Envelope};
 
 #[cfg(feature = "async_native_tls")]
 pub use async_stream_tls_upgrader::AsyncNativeTlsClientTlsUpgrader;
 #[cfg(feature = "async_tls")]
 pub use async_stream_tls_upgrader::AsyncTlsClientTlsUpgrader;
 
+use crate::capabilities::Capabilities;
+use crate::connector::Runtime;
+#[cfg(feature = "dkim")]
+use crate::dkim::DkimSigner;
+use crate::metrics::{reply_code, Metrics, MetricsPhase};
+use crate::mta_sts::{MtaStsEnforcement, MtaStsFailure, MtaStsMode, MtaStsPolicy, MtaStsTlsFailed};
+use crate::proxy_protocol::ProxyHeader;
+use crate::rate_limit::{ConnectionPermit, RateLimiter, Throttle};
+use crate::receipt::SendReceipt;
+#[cfg(feature = "rustls_tls")]
+use crate::rustls_tls::RustlsClientTlsUpgrader;
+use crate::stream::{AsyncStream, NoTls};
+#[cfg(feature = "tokio_native_tls")]
+use crate::tokio_io::TokioNativeTlsClientTlsUpgrader;
+#[cfg(feature = "tokio_rustls")]
+use crate::tokio_io::TokioRustlsClientTlsUpgrader;
+#[cfg(feature = "tokio")]
+use crate::tokio_io::TokioStream;
+use crate::transcript::{
+    redact_auth, TranscriptDirection, TranscriptEntry, TranscriptHook, REDACTED,
+};
+use crate::verification::{CertificateChain, PeerCertificateVerifier, PeerCertificates};
+use crate::verp::Verp;
+
 use self::codec::ClientCodec;
 
-pub type AsyncStream<S, STU> = UpgradableAsyncStream<S, STU>;
+// Set with the verifier or the MTA-STS policy, where the TLS stream is known to have them
+type PeerCertificatesFn<T> = fn(&T) -> Option<CertificateChain>;
 
 // ref https://github.com/lettre/lettre/blob/v0.10.0-alpha.1/src/transport/smtp/client/mod.rs#L99-L107
 pub struct AsyncConnection<S, STU>
@@ -29,8 +61,47 @@ where
     STU: TlsClientUpgrader<S>,
 {
     stream: AsyncStream<S, STU>,
+    read_buf: Vec<u8>,
     panic: bool,
+    lmtp: bool,
+    starttls_policy: StartTlsPolicy,
+    transcript_hook: Option<Box<dyn TranscriptHook>>,
+    transcript_data: bool,
+    metrics: Option<Arc<dyn Metrics>>,
+    current_command: String,
+    rate_limiter: Option<(Arc<dyn Throttle>, String)>,
+    connection_permit: Option<ConnectionPermit>,
+    messages_sent: usize,
+    mail_started: Option<Instant>,
+    #[cfg(feature = "dkim")]
+    dkim_signer: Option<Arc<DkimSigner>>,
+    verp: Option<Verp>,
     server_info_: ServerInfo,
+    capabilities: Capabilities,
+    greeting: Option<Response>,
+    peer_certificates: Option<PeerCertificatesFn<STU::Output>>,
+    peer_certificate_verifier: Option<Box<dyn PeerCertificateVerifier>>,
+    mta_sts: Option<MtaStsEnforcement>,
+    proxy_header: Option<ProxyHeader>,
+    local_addr: Option<SocketAddr>,
+}
+
+/// The final response for one recipient.
+#[derive(Debug)]
+pub struct RecipientResult {
+    pub recipient: Address,
+    pub result: result::Result<Response, Error>,
+}
+
+#[derive(Default, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum StartTlsPolicy {
+    /// STARTTLS when advertised and the upgrader can upgrade, plaintext otherwise.
+    #[default]
+    Opportunistic,
+    /// Fails the handshake instead of continuing in plaintext.
+    Required,
+    /// Never STARTTLS, even when advertised.
+    Disabled,
 }
 
 impl<S, STU> AsyncConnection<S, STU>
@@ -42,17 +113,222 @@ where
         &self.server_info_
     }
 
+    /// Of the last EHLO (or LHLO).
+    pub fn capabilities(&self) -> &Capabilities {
+        &self.capabilities
+    }
+
+    /// The server's banner, read by `handshake`.
+    pub fn greeting(&self) -> Option<&Response> {
+        self.greeting.as_ref()
+    }
+
     fn from_parts(stream: AsyncStream<S, STU>) -> Self {
         Self {
             stream,
+            read_buf: vec![],
             panic: false,
+            lmtp: false,
+            starttls_policy: StartTlsPolicy::default(),
+            transcript_hook: None,
+            transcript_data: false,
+            metrics: None,
+            current_command: "CONNECT".to_owned(),
+            rate_limiter: None,
+            connection_permit: None,
+            messages_sent: 0,
+            mail_started: None,
+            #[cfg(feature = "dkim")]
+            dkim_signer: None,
+            verp: None,
             server_info_: Default::default(),
+            capabilities: Capabilities::default(),
+            greeting: None,
+            peer_certificates: None,
+            peer_certificate_verifier: None,
+            mta_sts: None,
+            proxy_header: None,
+            local_addr: None,
         }
     }
 
     pub fn new(stream: S, upgrader: STU) -> Self {
         Self::from_parts(AsyncStream::new(stream, upgrader))
     }
+
+    /// Receives every command and response line. AUTH payloads are always redacted, DATA
+    /// bodies unless `set_transcript_data(true)`.
+    pub fn set_transcript_hook<H>(&mut self, hook: H)
+    where
+        H: TranscriptHook + 'static,
+    {
+        self.transcript_hook = Some(Box::new(hook));
+    }
+
+    pub fn set_transcript_data(&mut self, enabled: bool) {
+        self.transcript_data = enabled;
+    }
+
+    fn transcribe(&self, direction: TranscriptDirection, text: &str) {
+        let hook = match &self.transcript_hook {
+            Some(hook) => hook,
+            None => return,
+        };
+
+        let timestamp = SystemTime::now();
+        for line in text.trim_end_matches("\r\n").split("\r\n") {
+            hook.record(&TranscriptEntry {
+                timestamp,
+                direction,
+                line: line.to_owned(),
+            });
+        }
+    }
+
+    pub fn set_metrics(&mut self, metrics: Arc<dyn Metrics>) {
+        self.metrics = Some(metrics);
+    }
+
+    /// `host` is the key of the limits, usually the host connected to.
+    pub fn set_rate_limiter<R>(&mut self, limiter: Arc<RateLimiter<R>>, host: &str)
+    where
+        R: Runtime + 'static,
+    {
+        self.rate_limiter = Some((limiter, host.to_ascii_lowercase()));
+    }
+
+    pub(crate) fn set_connection_permit(&mut self, permit: Option<ConnectionPermit>) {
+        self.connection_permit = permit;
+    }
+
+    /// The transactions started on this connection.
+    pub fn messages_sent(&self) -> usize {
+        self.messages_sent
+    }
+
+    /// When the rate limiter's max messages per connection is reached, the sends then fail
+    /// until a new connection is made.
+    pub fn message_limit_reached(&self) -> bool {
+        self.rate_limiter
+            .as_ref()
+            .and_then(|(limiter, _)| limiter.max_messages_per_connection())
+            .is_some_and(|max| self.messages_sent >= max)
+    }
+
+    /// Signs the messages before the MAIL command.
+    #[cfg(feature = "dkim")]
+    pub fn set_dkim_signer(&mut self, signer: Arc<DkimSigner>) {
+        self.dkim_signer = Some(signer);
+    }
+
+    #[cfg(feature = "dkim")]
+    fn dkim_sign<'e>(&self, email: &'e [u8]) -> result::Result<Cow<'e, [u8]>, Error> {
+        match &self.dkim_signer {
+            Some(signer) => Ok(Cow::Owned(signer.sign(email)?)),
+            None => Ok(Cow::Borrowed(email)),
+        }
+    }
+
+    /// One transaction per recipient, the reverse-path encoding the recipient. Messages with
+    /// the null reverse-path are sent as they are. `send` then fails with the first rejected
+    /// recipient although the others got the message, `send_with_receipt` has them all.
+    pub fn set_verp(&mut self, verp: Verp) {
+        self.verp = Some(verp);
+    }
+
+    fn verp(&self, envelope: &Envelope) -> Option<Verp> {
+        self.verp.filter(|_| envelope.from().is_some())
+    }
+
+    // A new connection is needed, the VERP transactions of one message are not split
+    fn check_message_limit(&self) -> result::Result<(), Error> {
+        if self.message_limit_reached() {
+            return Err(Error::Client("Max messages per connection reached"));
+        }
+        Ok(())
+    }
+
+    async fn acquire_message(&mut self, recipients: usize) {
+        if let Some((limiter, host)) = &self.rate_limiter {
+            limiter.acquire_message(host, recipients).await;
+        }
+        self.messages_sent += 1;
+        // The first transaction of the message, for SendReceipt::elapsed
+        self.mail_started.get_or_insert_with(Instant::now);
+    }
+
+    fn mail_elapsed(&self) -> Duration {
+        self.mail_started
+            .map(|started| started.elapsed())
+            .unwrap_or_default()
+    }
+
+    fn observe(&self, phase: MetricsPhase, started: Instant, success: bool) {
+        if let Some(metrics) = &self.metrics {
+            metrics.latency(phase, started.elapsed(), success);
+        }
+    }
+
+    /// Only used when the stream is not already encrypted.
+    pub fn set_starttls_policy(&mut self, policy: StartTlsPolicy) {
+        self.starttls_policy = policy;
+    }
+
+    pub fn starttls_policy(&self) -> StartTlsPolicy {
+        self.starttls_policy
+    }
+
+    /// LMTP (RFC 2033), LHLO instead of EHLO and one final response per recipient.
+    pub fn set_lmtp(&mut self, lmtp: bool) {
+        self.lmtp = lmtp;
+    }
+
+    pub fn is_lmtp(&self) -> bool {
+        self.lmtp
+    }
+
+    /// The source address, known when connected by `AsyncClient::connect` or `DirectDelivery`.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
+    }
+
+    pub fn set_local_addr(&mut self, addr: SocketAddr) {
+        self.local_addr = Some(addr);
+    }
+
+    // Sent by handshake, before the greeting is read
+    pub fn set_proxy_header(&mut self, header: ProxyHeader) {
+        self.proxy_header = Some(header);
+    }
+
+    pub fn mta_sts_failures(&self) -> &[MtaStsFailure] {
+        self.mta_sts
+            .as_ref()
+            .map(|mta_sts| &mta_sts.failures[..])
+            .unwrap_or_default()
+    }
+
+    fn mta_sts_testing(&self) -> bool {
+        self.mta_sts
+            .as_ref()
+            .is_some_and(|mta_sts| mta_sts.policy.mode == MtaStsMode::Testing)
+    }
+
+    fn mta_sts_failure(&mut self, failure: MtaStsFailure) -> result::Result<(), Error> {
+        let mta_sts = match self.mta_sts.as_mut() {
+            Some(mta_sts) => mta_sts,
+            None => return Ok(()),
+        };
+
+        match mta_sts.policy.mode {
+            MtaStsMode::Enforce => Err(Error::Client(failure.as_str())),
+            MtaStsMode::Testing => {
+                mta_sts.failures.push(failure);
+                Ok(())
+            }
+            MtaStsMode::None => Ok(()),
+        }
+    }
 }
 
 impl<S> AsyncConnection<S, ()>
@@ -60,7 +336,32 @@ where
     S: Send + 'static,
 {
     pub fn with_tls_stream(stream: S) -> Self {
-        Self::from_parts(AsyncStream::with_upgraded_stream(stream))
+        Self::from_parts(AsyncStream::with_upgraded_stream_and_upgrader(stream, ()))
+    }
+}
+
+impl<S> AsyncConnection<S, NoTls>
+where
+    S: Send + 'static,
+{
+    pub fn plain(stream: S) -> Self {
+        Self::new(stream, NoTls)
+    }
+}
+
+#[cfg(all(unix, feature = "smol"))]
+impl AsyncConnection<async_net::unix::UnixStream, NoTls> {
+    pub async fn connect_unix(path: impl AsRef<Path>) -> io::Result<Self> {
+        let stream = async_net::unix::UnixStream::connect(path.as_ref()).await?;
+        Ok(Self::plain(stream))
+    }
+}
+
+#[cfg(all(unix, feature = "tokio"))]
+impl AsyncConnection<TokioStream<tokio::net::UnixStream>, NoTls> {
+    pub async fn connect_tokio_unix(path: impl AsRef<Path>) -> io::Result<Self> {
+        let stream = tokio::net::UnixStream::connect(path).await?;
+        Ok(Self::plain(TokioStream::new(stream)))
     }
 }
 
@@ -87,12 +388,79 @@ where
     }
 }
 
+#[cfg(feature = "rustls_tls")]
+impl<S> AsyncConnection<S, RustlsClientTlsUpgrader>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    pub fn with_rustls_upgrader(stream: S, upgrader: RustlsClientTlsUpgrader) -> Self {
+        Self::new(stream, upgrader)
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<T, STU> AsyncConnection<TokioStream<T>, STU>
+where
+    STU: TlsClientUpgrader<TokioStream<T>>,
+{
+    pub fn with_tokio_stream(stream: T, upgrader: STU) -> Self {
+        Self::new(TokioStream::new(stream), upgrader)
+    }
+}
+
+#[cfg(feature = "tokio_rustls")]
+impl<T> AsyncConnection<TokioStream<T>, TokioRustlsClientTlsUpgrader>
+where
+    T: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+{
+    pub fn with_tokio_rustls_upgrader(stream: T, upgrader: TokioRustlsClientTlsUpgrader) -> Self {
+        Self::with_tokio_stream(stream, upgrader)
+    }
+}
+
+#[cfg(feature = "tokio_native_tls")]
+impl<T> AsyncConnection<TokioStream<T>, TokioNativeTlsClientTlsUpgrader>
+where
+    T: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+{
+    pub fn with_tokio_native_tls_upgrader(
+        stream: T,
+        upgrader: TokioNativeTlsClientTlsUpgrader,
+    ) -> Self {
+        Self::with_tokio_stream(stream, upgrader)
+    }
+}
+
 impl<S, STU> AsyncConnection<S, STU>
 where
     STU: TlsClientUpgrader<S>,
+    STU::Output: PeerCertificates,
 {
-    pub async fn stream_tls_upgrade(&mut self) -> result::Result<(), Error> {
-        self.stream.upgrade().await.map_err(|err| err.into())
+    pub fn peer_certificates(&self) -> Option<CertificateChain> {
+        self.stream
+            .get_upgraded_ref()
+            .and_then(|stream| stream.peer_certificates())
+    }
+
+    // Invoked by stream_tls_upgrade, so also by handshake for both SMTPS and STARTTLS
+    pub fn set_peer_certificate_verifier<V>(&mut self, verifier: V)
+    where
+        V: PeerCertificateVerifier + 'static,
+    {
+        self.peer_certificates = Some(<STU::Output as PeerCertificates>::peer_certificates);
+        self.peer_certificate_verifier = Some(Box::new(verifier));
+    }
+
+    // Checked by handshake, `mx_host` is the MX host this connection goes to, the certificate
+    // must be valid for it. In testing mode the failures are only recorded, see
+    // mta_sts_failures, except a failed TLS negotiation, see MtaStsTlsFailed.
+    pub fn set_mta_sts_policy(&mut self, policy: MtaStsPolicy, mx_host: impl Into<String>) {
+        self.peer_certificates = Some(<STU::Output as PeerCertificates>::peer_certificates);
+        self.mta_sts = Some(MtaStsEnforcement {
+            policy,
+            mx_host: mx_host.into(),
+            failures: vec![],
+        });
     }
 }
 
@@ -120,39 +488,238 @@ where
     S: AsyncRead + AsyncWrite + Unpin,
     STU::Output: AsyncRead + AsyncWrite + Unpin,
 {
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(name = "smtp.tls_upgrade", skip_all)
+    )]
+    pub async fn stream_tls_upgrade(&mut self) -> result::Result<(), Error> {
+        // Anything received before the upgrade must not be read after it
+        // ref https://tools.ietf.org/html/rfc3207#section-6
+        self.read_buf.clear();
+
+        let upgraded = self.stream.upgrade().await;
+        smtp_event!(tls = upgraded.is_ok(), "TLS upgrade");
+        if let Err(err) = upgraded {
+            if let Some(metrics) = &self.metrics {
+                metrics.tls_upgrade(false);
+            }
+            // The stream is gone with the failed negotiation, in testing mode the caller
+            // reconnects without TLS
+            // ref https://tools.ietf.org/html/rfc8461#section-5
+            let err = match self.mta_sts_failure(MtaStsFailure::TlsFailed) {
+                Ok(()) if self.mta_sts_testing() => {
+                    io::Error::new(err.kind(), MtaStsTlsFailed(err))
+                }
+                _ => err,
+            };
+            try_smtp!(Err(err), self);
+        }
+
+        let chain = match (self.stream.get_upgraded_ref(), self.peer_certificates) {
+            (Some(stream), Some(peer_certificates)) => peer_certificates(stream),
+            _ => None,
+        };
+
+        if let Some(verifier) = &self.peer_certificate_verifier {
+            let verified = match &chain {
+                Some(chain) => verifier.verify(chain),
+                None => Err(Error::Client("Peer certificates are not available")),
+            };
+            if let (Some(metrics), Err(_)) = (&self.metrics, &verified) {
+                metrics.tls_upgrade(false);
+            }
+            try_smtp!(verified, self);
+        }
+
+        // ref https://tools.ietf.org/html/rfc8461#section-4.2
+        if let Some(mta_sts) = &self.mta_sts {
+            let matched = chain
+                .as_ref()
+                .and_then(|chain| chain.first())
+                .is_some_and(|leaf| mta_sts.certificate_matches(leaf));
+            if !matched {
+                let failed = self.mta_sts_failure(MtaStsFailure::CertificateMismatch);
+                if let (Some(metrics), Err(_)) = (&self.metrics, &failed) {
+                    metrics.tls_upgrade(false);
+                }
+                try_smtp!(failed, self);
+            }
+        }
+
+        if let Some(metrics) = &self.metrics {
+            metrics.tls_upgrade(true);
+        }
+
+        Ok(())
+    }
+
     // ref https://github.com/lettre/lettre/blob/v0.10.0-alpha.1/src/transport/smtp/client/mod.rs#L187-L191
     // ref https://github.com/lettre/lettre/blob/v0.10.0-alpha.1/src/transport/smtp/mod.rs#L441-L475
     // ref https://github.com/lettre/lettre/blob/v0.10.0-alpha.1/src/transport/smtp/client/mod.rs#L119-L141
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(name = "smtp.handshake", skip_all, fields(smtps = is_smtps, lmtp = self.lmtp))
+    )]
     pub async fn handshake(
         &mut self,
         is_smtps: bool,
         hello_name: ClientId,
     ) -> result::Result<(), Error> {
+        let started = Instant::now();
+        let result = self.handshake_steps(is_smtps, hello_name).await;
+        self.observe(MetricsPhase::Handshake, started, result.is_ok());
+        result
+    }
+
+    async fn handshake_steps(
+        &mut self,
+        is_smtps: bool,
+        hello_name: ClientId,
+    ) -> result::Result<(), Error> {
+        // ref https://tools.ietf.org/html/rfc8461#section-4
+        if let Some(mta_sts) = &self.mta_sts {
+            if !mta_sts.policy.matches_mx(&mta_sts.mx_host) {
+                try_smtp!(self.mta_sts_failure(MtaStsFailure::MxMismatch), self);
+            }
+        }
+
+        if let Some(header) = self.proxy_header.take() {
+            let bytes = try_smtp!(header.to_bytes(), self);
+            try_smtp!(self.write(&bytes).await, self);
+        }
+
         if is_smtps && !self.stream.is_upgraded() {
             self.stream_tls_upgrade().await?;
         }
 
-        let _ = self.read_response().await?;
+        self.greeting = Some(self.read_response().await?);
 
-        self.ehlo(&hello_name).await?;
+        self.hello(&hello_name).await?;
 
-        if self.can_starttls() {
-            self.starttls().await?;
+        if self.can_starttls() && self.starttls_policy != StartTlsPolicy::Disabled {
+            // A refused STARTTLS leaves the session in plaintext
+            // ref https://tools.ietf.org/html/rfc3207#section-4
+            match self.command(Starttls).await {
+                Ok(_) => {
+                    self.stream_tls_upgrade().await?;
 
-            self.stream_tls_upgrade().await?;
+                    self.hello(&hello_name).await?;
+                }
+                Err(Error::Transient(_)) | Err(Error::Permanent(_)) => {}
+                Err(err) => try_smtp!(Err(err), self),
+            }
+        }
 
-            self.ehlo(&hello_name).await?;
+        if !self.is_encrypted() {
+            if self.starttls_policy == StartTlsPolicy::Required {
+                try_smtp!(
+                    Err(Error::Client("STARTTLS is required but not available")),
+                    self
+                );
+            }
+            try_smtp!(
+                self.mta_sts_failure(MtaStsFailure::StarttlsNotSupported),
+                self
+            );
         }
 
         Ok(())
     }
 
     // ref https://github.com/lettre/lettre/blob/v0.10.0-alpha.1/src/transport/smtp/client/mod.rs#L143-L166
+    /// In LMTP or VERP mode the last response when all the recipients succeed, otherwise the
+    /// first failure, though the message may have been delivered to the other recipients. Use
+    /// `send_with_receipt` for the result of every recipient.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(name = "smtp.send", skip_all, fields(size = email.len(), recipients = envelope.to().len()))
+    )]
     pub async fn send(
         &mut self,
         envelope: &Envelope,
         email: &[u8],
     ) -> result::Result<Response, Error> {
+        self.check_message_limit()?;
+
+        if self.lmtp || self.verp(envelope).is_some() {
+            let mut last = Err(Error::Client("No recipient"));
+            for recipient_result in self.send_per_recipient(envelope, email).await? {
+                last = Ok(recipient_result.result?);
+            }
+            return last;
+        }
+
+        self.send_smtp(envelope, email, None).await
+    }
+
+    /// Like `send`, with the recipient results and the queue ID. A rejected recipient does
+    /// not fail the others, the transaction fails when no recipient is accepted.
+    pub async fn send_with_receipt(
+        &mut self,
+        envelope: &Envelope,
+        email: &[u8],
+    ) -> result::Result<SendReceipt, Error> {
+        self.check_message_limit()?;
+        self.mail_started = None;
+
+        if self.lmtp || self.verp(envelope).is_some() {
+            let recipients = self.send_per_recipient(envelope, email).await?;
+            let response = recipients
+                .iter()
+                .rev()
+                .find_map(|recipient_result| recipient_result.result.as_ref().ok())
+                .cloned();
+            return match response {
+                Some(response) => Ok(SendReceipt::new(
+                    response,
+                    email.len(),
+                    self.mail_elapsed(),
+                    recipients,
+                )),
+                None => Err(recipients
+                    .into_iter()
+                    .find_map(|recipient_result| recipient_result.result.err())
+                    .unwrap_or(Error::Client("No recipient"))),
+            };
+        }
+
+        let mut recipients = vec![];
+        let response = self
+            .send_smtp(envelope, email, Some(&mut recipients))
+            .await?;
+        Ok(SendReceipt::new(
+            response,
+            email.len(),
+            self.mail_elapsed(),
+            recipients,
+        ))
+    }
+
+    async fn send_per_recipient(
+        &mut self,
+        envelope: &Envelope,
+        email: &[u8],
+    ) -> result::Result<Vec<RecipientResult>, Error> {
+        match self.verp(envelope) {
+            Some(verp) => self.send_verp(verp, envelope, email).await,
+            None => self.send_lmtp(envelope, email).await,
+        }
+    }
+
+    // Without `recipients`, the first rejected recipient fails the transaction
+    async fn send_smtp(
+        &mut self,
+        envelope: &Envelope,
+        email: &[u8],
+        mut recipients: Option<&mut Vec<RecipientResult>>,
+    ) -> result::Result<Response, Error> {
+        #[cfg(feature = "dkim")]
+        let signed = self.dkim_sign(email)?;
+        #[cfg(feature = "dkim")]
+        let email = signed.as_ref();
+
+        self.acquire_message(envelope.to().len()).await;
+
         // Mail
         let mut mail_options = vec![];
 
@@ -167,18 +734,205 @@ where
 
         // Recipient
         for to_address in envelope.to() {
-            try_smtp!(
+            let result = match (
                 self.command(Rcpt::new(to_address.clone(), vec![])).await,
-                self
-            );
+                recipients.as_mut(),
+            ) {
+                (Ok(response), _) => Ok(response),
+                (Err(err @ Error::Transient(_)), Some(_))
+                | (Err(err @ Error::Permanent(_)), Some(_)) => Err(err),
+                (Err(err), _) => try_smtp!(Err(err), self),
+            };
+            if let Some(recipients) = recipients.as_mut() {
+                recipients.push(RecipientResult {
+                    recipient: to_address.clone(),
+                    result,
+                });
+            }
+        }
+
+        if let Some(recipients) = recipients {
+            if recipients
+                .iter()
+                .all(|recipient_result| recipient_result.result.is_err())
+            {
+                try_smtp!(self.command(Rset).await, self);
+                return Err(recipients
+                    .drain(..)
+                    .find_map(|recipient_result| recipient_result.result.err())
+                    .unwrap_or(Error::Client("No recipient")));
+            }
+        }
+
+        // Data
+        let started = Instant::now();
+        let result = match self.command(Data).await {
+            // Message content
+            Ok(_) => self.message(email).await,
+            Err(err) => Err(err),
+        };
+        self.observe(MetricsPhase::Data, started, result.is_ok());
+        let result = try_smtp!(result, self);
+        Ok(result)
+    }
+
+    // Rejected recipients do not fail the transaction
+    // ref https://tools.ietf.org/html/rfc2033#section-4.2
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(name = "smtp.send", skip_all, fields(size = email.len(), recipients = envelope.to().len()))
+    )]
+    pub async fn send_lmtp(
+        &mut self,
+        envelope: &Envelope,
+        email: &[u8],
+    ) -> result::Result<Vec<RecipientResult>, Error> {
+        self.check_message_limit()?;
+
+        #[cfg(feature = "dkim")]
+        let signed = self.dkim_sign(email)?;
+        #[cfg(feature = "dkim")]
+        let email = signed.as_ref();
+
+        self.acquire_message(envelope.to().len()).await;
+
+        // Mail
+        let mut mail_options = vec![];
+
+        if self.server_info().supports_feature(Extension::EightBitMime) {
+            mail_options.push(MailParameter::Body(MailBodyParameter::EightBitMime));
+        }
+        try_smtp!(
+            self.command(Mail::new(envelope.from().cloned(), mail_options,))
+                .await,
+            self
+        );
+
+        // Recipient
+        let mut results = vec![];
+        let mut accepted = vec![];
+        for to_address in envelope.to() {
+            match self.command(Rcpt::new(to_address.clone(), vec![])).await {
+                Ok(_) => accepted.push(to_address.clone()),
+                Err(err @ Error::Transient(_)) | Err(err @ Error::Permanent(_)) => {
+                    results.push(RecipientResult {
+                        recipient: to_address.clone(),
+                        result: Err(err),
+                    })
+                }
+                Err(err) => try_smtp!(Err(err), self),
+            }
+        }
+
+        if accepted.is_empty() {
+            try_smtp!(self.command(Rset).await, self);
+            return Ok(results);
         }
 
         // Data
+        let started = Instant::now();
         try_smtp!(self.command(Data).await, self);
 
-        // Message content
-        let result = try_smtp!(self.message(email).await, self);
-        Ok(result)
+        // Message content, then one response per accepted recipient
+        try_smtp!(self.write_message(email).await, self);
+
+        for recipient in accepted {
+            let result = match self.read_response().await {
+                Ok(response) => Ok(response),
+                Err(err @ Error::Transient(_)) | Err(err @ Error::Permanent(_)) => Err(err),
+                Err(err) => try_smtp!(Err(err), self),
+            };
+            results.push(RecipientResult { recipient, result });
+        }
+
+        // Successful when delivered to at least one recipient
+        let delivered = results
+            .iter()
+            .any(|recipient_result| recipient_result.result.is_ok());
+        self.observe(MetricsPhase::Data, started, delivered);
+
+        // In the envelope order
+        results.sort_by_key(|recipient_result| {
+            envelope
+                .to()
+                .iter()
+                .position(|to_address| to_address == &recipient_result.recipient)
+        });
+
+        Ok(results)
+    }
+
+    // A rejected recipient does not fail the others
+    async fn send_verp(
+        &mut self,
+        verp: Verp,
+        envelope: &Envelope,
+        email: &[u8],
+    ) -> result::Result<Vec<RecipientResult>, Error> {
+        #[cfg(feature = "dkim")]
+        let signed = self.dkim_sign(email)?;
+        #[cfg(feature = "dkim")]
+        let email = signed.as_ref();
+
+        let return_path = envelope.from().ok_or(Error::Client("No reverse-path"))?;
+
+        let mut results = vec![];
+        for to_address in envelope.to() {
+            let result = match verp.encode(return_path, to_address) {
+                Ok(from) => self.verp_transaction(from, to_address, email).await?,
+                Err(_) => Err(Error::Client("Invalid VERP address")),
+            };
+            results.push(RecipientResult {
+                recipient: to_address.clone(),
+                result,
+            });
+        }
+
+        Ok(results)
+    }
+
+    // The outer error is the connection's, the inner one the recipient's
+    async fn verp_transaction(
+        &mut self,
+        from: Address,
+        to_address: &Address,
+        email: &[u8],
+    ) -> result::Result<result::Result<Response, Error>, Error> {
+        self.acquire_message(1).await;
+
+        // Mail
+        let mut mail_options = vec![];
+
+        if self.server_info().supports_feature(Extension::EightBitMime) {
+            mail_options.push(MailParameter::Body(MailBodyParameter::EightBitMime));
+        }
+        let result = match self.command(Mail::new(Some(from), mail_options)).await {
+            // Recipient
+            Ok(_) => match self.command(Rcpt::new(to_address.clone(), vec![])).await {
+                // Data
+                Ok(_) => {
+                    let started = Instant::now();
+                    let result = match self.command(Data).await {
+                        // Message content, a single response in LMTP mode too
+                        Ok(_) => self.message(email).await,
+                        Err(err) => Err(err),
+                    };
+                    self.observe(MetricsPhase::Data, started, result.is_ok());
+                    result
+                }
+                Err(err) => Err(err),
+            },
+            Err(err) => Err(err),
+        };
+
+        match result {
+            Ok(response) => Ok(Ok(response)),
+            Err(err @ Error::Transient(_)) | Err(err @ Error::Permanent(_)) => {
+                try_smtp!(self.command(Rset).await, self);
+                Ok(Err(err))
+            }
+            Err(err) => try_smtp!(Err(err), self),
+        }
     }
 
     // ref https://github.com/lettre/lettre/blob/v0.10.0-alpha.1/src/transport/smtp/client/mod.rs#L168-L170
@@ -188,7 +942,9 @@ where
 
     // ref https://github.com/lettre/lettre/blob/v0.10.0-alpha.1/src/transport/smtp/client/mod.rs#L172-L175
     pub fn can_starttls(&self) -> bool {
-        !self.is_encrypted() && self.server_info().supports_feature(Extension::StartTls)
+        !self.is_encrypted()
+            && self.stream.upgrade_required()
+            && self.server_info().supports_feature(Extension::StartTls)
     }
 
     // ref https://github.com/lettre/lettre/blob/v0.10.0-alpha.1/src/transport/smtp/client/mod.rs#L178-L201
@@ -209,10 +965,37 @@ where
             self
         );
         self.server_info_ = try_smtp!(ServerInfo::from_response(&ehlo_response), self);
+        self.capabilities = Capabilities::from_response(&ehlo_response);
+        smtp_event!(
+            server = %self.server_info_.name,
+            tls = self.is_encrypted(),
+            "EHLO"
+        );
+        Ok(())
+    }
+
+    pub async fn lhlo(&mut self, hello_name: &ClientId) -> result::Result<(), Error> {
+        let lhlo_response = try_smtp!(self.command(Lhlo(hello_name)).await, self);
+        self.server_info_ = try_smtp!(ServerInfo::from_response(&lhlo_response), self);
+        self.capabilities = Capabilities::from_response(&lhlo_response);
+        smtp_event!(
+            server = %self.server_info_.name,
+            tls = self.is_encrypted(),
+            "LHLO"
+        );
         Ok(())
     }
 
+    async fn hello(&mut self, hello_name: &ClientId) -> result::Result<(), Error> {
+        if self.lmtp {
+            self.lhlo(hello_name).await
+        } else {
+            self.ehlo(hello_name).await
+        }
+    }
+
     // ref https://github.com/lettre/lettre/blob/v0.10.0-alpha.1/src/transport/smtp/client/mod.rs#L213-L215
+    #[cfg_attr(feature = "tracing", tracing::instrument(name = "smtp.quit", skip_all))]
     pub async fn quit(&mut self) -> result::Result<Response, Error> {
         Ok(try_smtp!(self.command(Quit).await, self))
     }
@@ -221,7 +1004,6 @@ where
     pub fn abort(&mut self) {
         if !self.panic {
             self.panic = true;
-            let _ = self.command(Quit);
         }
     }
 
@@ -236,10 +1018,22 @@ where
     }
 
     // ref https://github.com/lettre/lettre/blob/v0.10.0-alpha.1/src/transport/smtp/client/mod.rs#L247-L282
+    #[cfg_attr(feature = "tracing", tracing::instrument(name = "smtp.auth", skip_all))]
     pub async fn auth(
         &mut self,
         mechanisms: &[Mechanism],
         credentials: &Credentials,
+    ) -> result::Result<Response, Error> {
+        let started = Instant::now();
+        let result = self.auth_steps(mechanisms, credentials).await;
+        self.observe(MetricsPhase::Auth, started, result.is_ok());
+        result
+    }
+
+    async fn auth_steps(
+        &mut self,
+        mechanisms: &[Mechanism],
+        credentials: &Credentials,
     ) -> result::Result<Response, Error> {
         let mechanism = match self.server_info_.get_auth_mechanism(mechanisms) {
             Some(m) => m,
@@ -250,6 +1044,8 @@ where
             }
         };
 
+        smtp_event!(mechanism = %mechanism, "AUTH");
+
         // Limit challenges to avoid blocking
         let mut challenges = 10;
         let mut response = self
@@ -259,7 +1055,7 @@ where
         while challenges > 0 && response.has_code(334) {
             challenges -= 1;
             response = try_smtp!(
-                self.command(Auth::new_from_response(
+                self.secret_command(Auth::new_from_response(
                     mechanism,
                     credentials.clone(),
                     &response,
@@ -278,12 +1074,31 @@ where
 
     // ref https://github.com/lettre/lettre/blob/v0.10.0-alpha.1/src/transport/smtp/client/mod.rs#L285-L292
     pub async fn message(&mut self, message: &[u8]) -> result::Result<Response, Error> {
+        self.write_message(message).await?;
+        self.read_response().await
+    }
+
+    async fn write_message(&mut self, message: &[u8]) -> result::Result<(), Error> {
         let mut out_buf: Vec<u8> = vec![];
         let mut codec = ClientCodec::new();
         codec.encode(message, &mut out_buf)?;
+
+        if self.transcript_data {
+            self.transcribe(
+                TranscriptDirection::Sent,
+                &String::from_utf8_lossy(&out_buf),
+            );
+        } else {
+            self.transcribe(
+                TranscriptDirection::Sent,
+                &format!("{} ({} bytes)", REDACTED, message.len()),
+            );
+        }
+        self.transcribe(TranscriptDirection::Sent, ".");
+        self.current_command = ".".to_owned();
+
         self.write(out_buf.as_slice()).await?;
-        self.write(b"\r\n.\r\n").await?;
-        self.read_response().await
+        self.write(b"\r\n.\r\n").await
     }
 
     // ref https://github.com/lettre/lettre/blob/v0.10.0-alpha.1/src/transport/smtp/client/mod.rs#L295-L298
@@ -291,6 +1106,30 @@ where
         &mut self,
         command: C,
     ) -> result::Result<Response, Error> {
+        let command = command.to_string();
+        self.current_command = command
+            .split([' ', '\r'])
+            .next()
+            .unwrap_or_default()
+            .to_ascii_uppercase();
+        smtp_event!(command = %self.current_command, "command");
+        if command.len() >= 5 && command[..5].eq_ignore_ascii_case("AUTH ") {
+            self.transcribe(TranscriptDirection::Sent, &redact_auth(&command));
+        } else {
+            self.transcribe(TranscriptDirection::Sent, &command);
+        }
+
+        self.write(command.as_bytes()).await?;
+        self.read_response().await
+    }
+
+    // AUTH challenge responses
+    async fn secret_command<C: fmt::Display>(
+        &mut self,
+        command: C,
+    ) -> result::Result<Response, Error> {
+        self.transcribe(TranscriptDirection::Sent, REDACTED);
+
         self.write(command.to_string().as_bytes()).await?;
         self.read_response().await
     }
@@ -300,6 +1139,10 @@ where
         self.stream.write_all(string).await?;
         self.stream.flush().await?;
 
+        if let Some(metrics) = &self.metrics {
+            metrics.bytes_written(string.len());
+        }
+
         Ok(())
     }
 
@@ -307,11 +1150,18 @@ where
     pub async fn read_response(&mut self) -> result::Result<Response, Error> {
         let mut buffer = String::with_capacity(100);
 
-        let mut buf_reader = BufReader::new(&mut self.stream);
-
-        while buf_reader.read_line(&mut buffer).await? > 0 {
+        while self.read_line(&mut buffer).await? > 0 {
             match Response::from_str(&buffer) {
                 Ok(response) => {
+                    self.transcribe(TranscriptDirection::Received, &buffer);
+                    smtp_event!(code = %response.code, "reply");
+                    if let Some(metrics) = &self.metrics {
+                        metrics.reply(&self.current_command, reply_code(&response.code));
+                    }
+                    if let Some((limiter, host)) = &self.rate_limiter {
+                        limiter.record(host, &self.current_command, reply_code(&response.code));
+                    }
+
                     if response.is_positive() {
                         return Ok(response);
                     }
@@ -321,11 +1171,58 @@ where
                 // ref https://github.com/lettre/lettre/blob/v0.10.0-alpha.1/src/transport/smtp/client/mod.rs#L328-L334
                 // ref https://github.com/lettre/lettre/blob/v0.10.0-alpha.1/src/transport/smtp/error.rs#L104-L112
                 Err(Error::Parsing(nom::error::ErrorKind::Complete)) => { /* read more */ }
-                Err(err) => return Err(err),
+                Err(err) => {
+                    self.transcribe(TranscriptDirection::Received, &buffer);
+                    return Err(err);
+                }
             }
         }
 
-        Err(io::Error::new(io::ErrorKind::Other, "incomplete").into())
+        Err(io::Error::other("incomplete").into())
+    }
+
+    // Kept across calls, one read may return several responses (e.g. LMTP)
+    async fn read_line(&mut self, line: &mut String) -> io::Result<usize> {
+        loop {
+            let end = match self.read_buf.iter().position(|b| *b == b'\n') {
+                Some(i) => i + 1,
+                None => {
+                    let mut buf = [0; 1024];
+                    let n = self.stream.read(&mut buf).await?;
+                    if let Some(metrics) = &self.metrics {
+                        metrics.bytes_read(n);
+                    }
+                    if n > 0 {
+                        self.read_buf.extend_from_slice(&buf[..n]);
+                        continue;
+                    }
+                    self.read_buf.len()
+                }
+            };
+
+            let bytes: Vec<u8> = self.read_buf.drain(..end).collect();
+            line.push_str(&String::from_utf8_lossy(&bytes));
+            return Ok(bytes.len());
+        }
+    }
+}
+
+// ref https://tools.ietf.org/html/rfc2033#section-4.1
+struct Lhlo<'a>(&'a ClientId);
+
+impl fmt::Display for Lhlo<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "LHLO {}\r\n", self.0)
+    }
+}
+
+impl<S> AsyncConnection<S, NoTls>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    /// Handshake without implicit TLS nor STARTTLS.
+    pub async fn handshake_plain(&mut self, hello_name: ClientId) -> result::Result<(), Error> {
+        self.handshake(false, hello_name).await
     }
 }
 
@@ -366,6 +1263,8 @@ mod codec {
                     for (idx, byte) in frame.iter().enumerate() {
                         match self.escape_count {
                             0 => self.escape_count = if *byte == b'\r' { 1 } else { 0 },
+                            // `\r\n\r\n.`, the empty line must not reset the state
+                            1 | 2 if *byte == b'\r' => self.escape_count = 1,
                             1 => self.escape_count = if *byte == b'\n' { 2 } else { 0 },
                             2 => self.escape_count = if *byte == b'.' { 3 } else { 0 },
                             _ => unreachable!(),
@@ -383,4 +1282,50 @@ mod codec {
             }
         }
     }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn encode(frames: &[&[u8]]) -> Vec<u8> {
+            let mut codec = ClientCodec::new();
+            let mut buf = vec![];
+            for frame in frames {
+                codec.encode(frame, &mut buf).unwrap();
+            }
+            buf
+        }
+
+        #[test]
+        fn escapes_dot_lines() {
+            assert_eq!(encode(&[b"a\r\n.b\r\n", b""]), b"a\r\n..b\r\n.\r\n");
+            assert_eq!(encode(&[b"a\r\n..\r\n", b""]), b"a\r\n...\r\n.\r\n");
+            assert_eq!(encode(&[b"a\r\nb.\r\n", b""]), b"a\r\nb.\r\n.\r\n");
+        }
+
+        #[test]
+        fn escapes_dot_after_empty_line() {
+            assert_eq!(encode(&[b"a\r\n\r\n.b\r\n", b""]), b"a\r\n\r\n..b\r\n.\r\n");
+            assert_eq!(
+                encode(&[b"a\r\n\r\n\r\n.\r\n", b""]),
+                b"a\r\n\r\n\r\n..\r\n.\r\n"
+            );
+        }
+
+        #[test]
+        fn escapes_across_frames() {
+            assert_eq!(encode(&[b"a\r", b"\n.b\r\n", b""]), b"a\r\n..b\r\n.\r\n");
+            assert_eq!(
+                encode(&[b"a\r\n\r", b"\n", b".b\r\n", b""]),
+                b"a\r\n\r\n..b\r\n.\r\n"
+            );
+        }
+
+        #[test]
+        fn terminates_data() {
+            assert_eq!(encode(&[b"a", b""]), b"a\r\n.\r\n");
+            assert_eq!(encode(&[b"a\r", b""]), b"a\r\n.\r\n");
+            assert_eq!(encode(&[b"a\r\n", b""]), b"a\r\n.\r\n");
+        }
+    }
 }
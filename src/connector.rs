@@ -0,0 +1,672 @@
+use std::io;
+use std::marker::PhantomData;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_stream_packed::TlsClientUpgrader;
+use async_trait::async_trait;
+use futures_util::future::{self, Either};
+use futures_util::io::{AsyncRead, AsyncWrite};
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use lettre::transport::smtp::extension::ClientId;
+
+use crate::connection::{AsyncConnection, StartTlsPolicy};
+#[cfg(feature = "dkim")]
+use crate::dkim::DkimSigner;
+use crate::metrics::Metrics;
+use crate::proxy::Proxy;
+use crate::proxy_protocol::ProxyHeader;
+use crate::rate_limit::{ConnectionPermit, RateLimiter};
+use crate::source_address::SourceAddressPool;
+use crate::verp::Verp;
+
+// ref https://tools.ietf.org/html/rfc8305#section-8
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[async_trait]
+pub trait Runtime {
+    type TcpStream: AsyncRead + AsyncWrite + Unpin + Send + 'static;
+
+    async fn resolve(host: &str, port: u16) -> io::Result<Vec<SocketAddr>>;
+
+    async fn connect(addr: SocketAddr) -> io::Result<Self::TcpStream>;
+
+    /// Connects from `local`, any port.
+    async fn connect_from(local: IpAddr, addr: SocketAddr) -> io::Result<Self::TcpStream>;
+
+    fn local_addr(stream: &Self::TcpStream) -> io::Result<SocketAddr>;
+
+    async fn sleep(duration: Duration);
+}
+
+#[cfg(feature = "smol")]
+pub struct SmolRuntime;
+
+#[cfg(feature = "smol")]
+#[async_trait]
+impl Runtime for SmolRuntime {
+    type TcpStream = async_net::TcpStream;
+
+    async fn resolve(host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        async_net::resolve((host, port)).await
+    }
+
+    async fn connect(addr: SocketAddr) -> io::Result<Self::TcpStream> {
+        async_net::TcpStream::connect(addr).await
+    }
+
+    async fn connect_from(local: IpAddr, addr: SocketAddr) -> io::Result<Self::TcpStream> {
+        use socket2::{Domain, Protocol, Socket, Type};
+
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+        socket.bind(&SocketAddr::new(local, 0).into())?;
+        socket.set_nonblocking(true)?;
+        match socket.connect(&addr.into()) {
+            Ok(()) => {}
+            #[cfg(unix)]
+            Err(err) if err.raw_os_error() == Some(libc::EINPROGRESS) => {}
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+            Err(err) => return Err(err),
+        }
+
+        let stream = async_io::Async::new(std::net::TcpStream::from(socket))?;
+        stream.writable().await?;
+        if let Some(err) = stream.get_ref().take_error()? {
+            return Err(err);
+        }
+        Ok(stream.into())
+    }
+
+    fn local_addr(stream: &Self::TcpStream) -> io::Result<SocketAddr> {
+        stream.local_addr()
+    }
+
+    async fn sleep(duration: Duration) {
+        async_io::Timer::after(duration).await;
+    }
+}
+
+#[cfg(feature = "tokio")]
+pub struct TokioRuntime;
+
+#[cfg(feature = "tokio")]
+#[async_trait]
+impl Runtime for TokioRuntime {
+    type TcpStream = crate::tokio_io::TokioStream<tokio::net::TcpStream>;
+
+    async fn resolve(host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        Ok(tokio::net::lookup_host((host, port)).await?.collect())
+    }
+
+    async fn connect(addr: SocketAddr) -> io::Result<Self::TcpStream> {
+        let stream = tokio::net::TcpStream::connect(addr).await?;
+        Ok(crate::tokio_io::TokioStream::new(stream))
+    }
+
+    async fn connect_from(local: IpAddr, addr: SocketAddr) -> io::Result<Self::TcpStream> {
+        let socket = match addr {
+            SocketAddr::V4(_) => tokio::net::TcpSocket::new_v4()?,
+            SocketAddr::V6(_) => tokio::net::TcpSocket::new_v6()?,
+        };
+        socket.bind(SocketAddr::new(local, 0))?;
+        let stream = socket.connect(addr).await?;
+        Ok(crate::tokio_io::TokioStream::new(stream))
+    }
+
+    fn local_addr(stream: &Self::TcpStream) -> io::Result<SocketAddr> {
+        stream.get_ref().local_addr()
+    }
+
+    async fn sleep(duration: Duration) {
+        tokio::time::sleep(duration).await;
+    }
+}
+
+//
+//
+//
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TlsMode {
+    /// Implicit TLS on port 465, STARTTLS otherwise.
+    Auto,
+    Implicit,
+    StartTls,
+}
+
+impl TlsMode {
+    pub(crate) fn is_smtps(&self, port: u16) -> bool {
+        match self {
+            Self::Auto => port == 465,
+            Self::Implicit => true,
+            Self::StartTls => false,
+        }
+    }
+}
+
+pub struct ConnectOptions<R> {
+    pub(crate) hello_name: ClientId,
+    pub(crate) tls: TlsMode,
+    pub(crate) connect_timeout: Duration,
+    pub(crate) connection_attempt_delay: Duration,
+    pub(crate) proxy: Option<Proxy>,
+    proxy_header: Option<ProxyHeader>,
+    starttls_policy: StartTlsPolicy,
+    metrics: Option<Arc<dyn Metrics>>,
+    rate_limiter: Option<Arc<RateLimiter<R>>>,
+    #[cfg(feature = "dkim")]
+    dkim_signer: Option<Arc<DkimSigner>>,
+    source_address_pool: Option<Arc<SourceAddressPool>>,
+    verp: Option<Verp>,
+    runtime: PhantomData<R>,
+}
+
+impl<R> ConnectOptions<R>
+where
+    R: Runtime,
+{
+    pub fn new(hello_name: ClientId) -> Self {
+        Self {
+            hello_name,
+            tls: TlsMode::Auto,
+            connect_timeout: CONNECT_TIMEOUT,
+            connection_attempt_delay: CONNECTION_ATTEMPT_DELAY,
+            proxy: None,
+            proxy_header: None,
+            starttls_policy: StartTlsPolicy::default(),
+            metrics: None,
+            rate_limiter: None,
+            #[cfg(feature = "dkim")]
+            dkim_signer: None,
+            source_address_pool: None,
+            verp: None,
+            runtime: PhantomData,
+        }
+    }
+
+    pub fn tls(mut self, tls: TlsMode) -> Self {
+        self.tls = tls;
+        self
+    }
+
+    /// Per address.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// Happy Eyeballs, how long to wait before also trying the next address.
+    pub fn connection_attempt_delay(mut self, delay: Duration) -> Self {
+        self.connection_attempt_delay = delay;
+        self
+    }
+
+    pub fn starttls_policy(mut self, policy: StartTlsPolicy) -> Self {
+        self.starttls_policy = policy;
+        self
+    }
+
+    pub fn metrics(mut self, metrics: Arc<dyn Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// The target host is resolved by the proxy.
+    pub fn proxy(mut self, proxy: Proxy) -> Self {
+        self.proxy = Some(proxy);
+        self
+    }
+
+    /// PROXY protocol header for the load balancer in front of the server.
+    pub fn proxy_header(mut self, header: ProxyHeader) -> Self {
+        self.proxy_header = Some(header);
+        self
+    }
+
+    /// Can be shared with other options, the limits are per host.
+    pub fn rate_limiter(mut self, limiter: Arc<RateLimiter<R>>) -> Self {
+        self.rate_limiter = Some(limiter);
+        self
+    }
+
+    #[cfg(feature = "dkim")]
+    pub fn dkim_signer(mut self, signer: Arc<DkimSigner>) -> Self {
+        self.dkim_signer = Some(signer);
+        self
+    }
+
+    /// See `AsyncConnection::set_verp`.
+    pub fn verp(mut self, verp: Verp) -> Self {
+        self.verp = Some(verp);
+        self
+    }
+
+    pub fn source_address(self, addr: IpAddr) -> Self {
+        self.source_address_pool(Arc::new(SourceAddressPool::single(addr)))
+    }
+
+    /// Can be shared with other options, to keep one rotation.
+    pub fn source_address_pool(mut self, pool: Arc<SourceAddressPool>) -> Self {
+        self.source_address_pool = Some(pool);
+        self
+    }
+
+    /// Waits for a connection slot when there is a rate limiter.
+    pub(crate) async fn connection_permit(&self, host: &str) -> Option<ConnectionPermit> {
+        match &self.rate_limiter {
+            Some(limiter) => Some(limiter.acquire_connection(host).await),
+            None => None,
+        }
+    }
+
+    pub(crate) fn configure<STU>(
+        &self,
+        host: &str,
+        connection: &mut AsyncConnection<R::TcpStream, STU>,
+    ) where
+        R: 'static,
+        STU: TlsClientUpgrader<R::TcpStream>,
+    {
+        connection.set_starttls_policy(self.starttls_policy);
+        if let Some(limiter) = &self.rate_limiter {
+            connection.set_rate_limiter(limiter.clone(), host);
+        }
+        #[cfg(feature = "dkim")]
+        if let Some(signer) = &self.dkim_signer {
+            connection.set_dkim_signer(signer.clone());
+        }
+        if let Some(verp) = self.verp {
+            connection.set_verp(verp);
+        }
+        if let Some(metrics) = &self.metrics {
+            connection.set_metrics(metrics.clone());
+        }
+        if let Some(header) = &self.proxy_header {
+            connection.set_proxy_header(header.clone());
+        }
+    }
+
+    // `host` is also the destination key of the source address pool
+    pub(crate) async fn connect_tcp(&self, host: &str, port: u16) -> io::Result<R::TcpStream> {
+        if let Some(proxy) = &self.proxy {
+            return self.connect_proxy(proxy, host, host, port).await;
+        }
+
+        let addrs = match host
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse::<IpAddr>()
+        {
+            Ok(ip) => vec![SocketAddr::new(ip, port)],
+            Err(_) => R::resolve(host, port).await?,
+        };
+
+        self.happy_eyeballs(host, sort_addrs(addrs)).await
+    }
+
+    // `host` is the MX host the addresses are of, see connect_tcp
+    pub(crate) async fn connect_ips(
+        &self,
+        host: &str,
+        ips: &[IpAddr],
+        port: u16,
+    ) -> io::Result<R::TcpStream> {
+        if let Some(proxy) = &self.proxy {
+            let mut last_err = None;
+            for ip in ips {
+                match self.connect_proxy(proxy, host, &ip.to_string(), port).await {
+                    Ok(stream) => return Ok(stream),
+                    Err(err) => last_err = Some(err),
+                }
+            }
+            return Err(last_err
+                .unwrap_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address found")));
+        }
+
+        let addrs = ips.iter().map(|ip| SocketAddr::new(*ip, port)).collect();
+
+        self.happy_eyeballs(host, sort_addrs(addrs)).await
+    }
+}
+
+impl<R> ConnectOptions<R>
+where
+    R: Runtime,
+{
+    // ref https://tools.ietf.org/html/rfc8305#section-5
+    async fn happy_eyeballs(&self, host: &str, addrs: Vec<SocketAddr>) -> io::Result<R::TcpStream> {
+        let mut addrs = addrs.into_iter().peekable();
+        let mut attempts = FuturesUnordered::new();
+        let mut last_err = None;
+
+        loop {
+            if let Some(addr) = addrs.next() {
+                let source = self
+                    .source_address_pool
+                    .as_ref()
+                    .map(|pool| pool.select(host, &addr));
+                attempts.push(async move {
+                    let connect = match source {
+                        None => R::connect(addr),
+                        Some(Ok(local)) => R::connect_from(local, addr),
+                        Some(Err(err)) => return Err(err),
+                    };
+                    match future::select(connect, R::sleep(self.connect_timeout)).await {
+                        Either::Left((result, _)) => result,
+                        Either::Right(_) => Err(io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            format!("connect to {} timed out", addr),
+                        )),
+                    }
+                });
+            }
+
+            if attempts.is_empty() {
+                return Err(last_err.unwrap_or_else(|| {
+                    io::Error::new(io::ErrorKind::NotFound, "no address found")
+                }));
+            }
+
+            let next_attempt = if addrs.peek().is_some() {
+                Either::Left(R::sleep(self.connection_attempt_delay))
+            } else {
+                Either::Right(future::pending())
+            };
+
+            // A failed attempt starts the next one right away
+            match future::select(attempts.next(), next_attempt).await {
+                Either::Left((Some(Ok(stream)), _)) => return Ok(stream),
+                Either::Left((Some(Err(err)), _)) => last_err = Some(err),
+                Either::Left((None, _)) | Either::Right(_) => {}
+            }
+        }
+    }
+
+    async fn connect_proxy(
+        &self,
+        proxy: &Proxy,
+        destination: &str,
+        host: &str,
+        port: u16,
+    ) -> io::Result<R::TcpStream> {
+        let addrs = match proxy.host.parse::<IpAddr>() {
+            Ok(ip) => vec![SocketAddr::new(ip, proxy.port)],
+            Err(_) => R::resolve(&proxy.host, proxy.port).await?,
+        };
+        let mut stream = self.happy_eyeballs(destination, sort_addrs(addrs)).await?;
+
+        match future::select(
+            Box::pin(proxy.tunnel(&mut stream, host, port)),
+            R::sleep(self.connect_timeout),
+        )
+        .await
+        {
+            Either::Left((result, _)) => result?,
+            Either::Right(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("proxy tunnel to {}:{} timed out", host, port),
+                ))
+            }
+        }
+
+        Ok(stream)
+    }
+}
+
+// Alternates address families, IPv6 first
+// ref https://tools.ietf.org/html/rfc8305#section-4
+fn sort_addrs(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let (v6, v4): (Vec<_>, Vec<_>) = addrs
+        .into_iter()
+        .partition(|addr| matches!(addr.ip(), IpAddr::V6(_)));
+
+    let mut sorted = Vec::with_capacity(v6.len() + v4.len());
+    let mut v6 = v6.into_iter();
+    let mut v4 = v4.into_iter();
+    loop {
+        match (v6.next(), v4.next()) {
+            (None, None) => break,
+            (a, b) => sorted.extend(a.into_iter().chain(b)),
+        }
+    }
+    sorted
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::time::Instant;
+
+    use futures_util::io::Cursor;
+
+    use super::*;
+
+    #[derive(Clone, Copy, Debug)]
+    pub(crate) struct Attempt {
+        pub(crate) addr: SocketAddr,
+        pub(crate) local: Option<IpAddr>,
+        pub(crate) at: Instant,
+    }
+
+    #[derive(Default)]
+    struct Network {
+        hosts: HashMap<String, Vec<IpAddr>>,
+        // `None` never connects, the addresses not listed refuse the connection
+        accepts: HashMap<SocketAddr, Option<Duration>>,
+        attempts: Vec<Attempt>,
+        sleeps: Vec<Duration>,
+    }
+
+    thread_local! {
+        static NETWORK: RefCell<Network> = RefCell::new(Network::default());
+    }
+
+    pub(crate) fn resolves(host: &str, ips: &[IpAddr]) {
+        NETWORK.with(|network| {
+            network
+                .borrow_mut()
+                .hosts
+                .insert(host.to_owned(), ips.to_vec())
+        });
+    }
+
+    pub(crate) fn accepts(addr: SocketAddr, delay: Duration) {
+        NETWORK.with(|network| network.borrow_mut().accepts.insert(addr, Some(delay)));
+    }
+
+    pub(crate) fn hangs(addr: SocketAddr) {
+        NETWORK.with(|network| network.borrow_mut().accepts.insert(addr, None));
+    }
+
+    pub(crate) fn attempts() -> Vec<Attempt> {
+        NETWORK.with(|network| network.borrow().attempts.clone())
+    }
+
+    pub(crate) fn sleeps() -> Vec<Duration> {
+        NETWORK.with(|network| network.borrow().sleeps.clone())
+    }
+
+    /// In-memory network of the thread, the sleeps are real.
+    pub(crate) struct TestRuntime;
+
+    #[async_trait]
+    impl Runtime for TestRuntime {
+        type TcpStream = Cursor<Vec<u8>>;
+
+        async fn resolve(host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+            let ips = NETWORK.with(|network| network.borrow().hosts.get(host).cloned());
+            match ips {
+                Some(ips) => Ok(ips
+                    .into_iter()
+                    .map(|ip| SocketAddr::new(ip, port))
+                    .collect()),
+                None => Err(io::Error::new(io::ErrorKind::NotFound, "host not found")),
+            }
+        }
+
+        async fn connect(addr: SocketAddr) -> io::Result<Self::TcpStream> {
+            connect(None, addr).await
+        }
+
+        async fn connect_from(local: IpAddr, addr: SocketAddr) -> io::Result<Self::TcpStream> {
+            connect(Some(local), addr).await
+        }
+
+        fn local_addr(_stream: &Self::TcpStream) -> io::Result<SocketAddr> {
+            Err(io::ErrorKind::Unsupported.into())
+        }
+
+        async fn sleep(duration: Duration) {
+            NETWORK.with(|network| network.borrow_mut().sleeps.push(duration));
+            async_io::Timer::after(duration).await;
+        }
+    }
+
+    async fn connect(local: Option<IpAddr>, addr: SocketAddr) -> io::Result<Cursor<Vec<u8>>> {
+        let accepts = NETWORK.with(|network| {
+            let mut network = network.borrow_mut();
+            network.attempts.push(Attempt {
+                addr,
+                local,
+                at: Instant::now(),
+            });
+            network.accepts.get(&addr).cloned()
+        });
+        match accepts {
+            Some(Some(delay)) => {
+                async_io::Timer::after(delay).await;
+                Ok(Cursor::new(vec![]))
+            }
+            Some(None) => future::pending().await,
+            None => Err(io::ErrorKind::ConnectionRefused.into()),
+        }
+    }
+
+    fn options() -> ConnectOptions<TestRuntime> {
+        ConnectOptions::new(ClientId::Domain("client.example.com".to_owned()))
+    }
+
+    fn addr(addr: &str) -> SocketAddr {
+        addr.parse().unwrap()
+    }
+
+    fn connect_tcp(options: &ConnectOptions<TestRuntime>, host: &str) -> io::Result<Vec<Attempt>> {
+        async_io::block_on(options.connect_tcp(host, 25))?;
+        Ok(attempts())
+    }
+
+    #[test]
+    fn alternates_the_address_families() {
+        let sorted = sort_addrs(vec![
+            addr("192.0.2.1:25"),
+            addr("192.0.2.2:25"),
+            addr("[2001:db8::1]:25"),
+            addr("[2001:db8::2]:25"),
+            addr("[2001:db8::3]:25"),
+        ]);
+        assert_eq!(
+            sorted,
+            vec![
+                addr("[2001:db8::1]:25"),
+                addr("192.0.2.1:25"),
+                addr("[2001:db8::2]:25"),
+                addr("192.0.2.2:25"),
+                addr("[2001:db8::3]:25"),
+            ]
+        );
+        assert_eq!(
+            sort_addrs(vec![addr("192.0.2.1:25"), addr("192.0.2.2:25")]),
+            vec![addr("192.0.2.1:25"), addr("192.0.2.2:25")]
+        );
+    }
+
+    #[test]
+    fn selects_the_tls_mode() {
+        assert!(TlsMode::Auto.is_smtps(465));
+        assert!(!TlsMode::Auto.is_smtps(587));
+        assert!(!TlsMode::Auto.is_smtps(25));
+        assert!(TlsMode::Implicit.is_smtps(587));
+        assert!(!TlsMode::StartTls.is_smtps(465));
+    }
+
+    #[test]
+    fn falls_back_after_a_refused_connection() {
+        resolves(
+            "mx.example.com",
+            &["192.0.2.1".parse().unwrap(), "2001:db8::1".parse().unwrap()],
+        );
+        accepts(addr("192.0.2.1:25"), Duration::ZERO);
+        let options = options().connection_attempt_delay(Duration::from_secs(10));
+
+        let started = Instant::now();
+        let attempts = connect_tcp(&options, "mx.example.com").unwrap();
+
+        // IPv6 first, the refusal starts the next attempt without the delay
+        let addrs: Vec<_> = attempts.iter().map(|attempt| attempt.addr).collect();
+        assert_eq!(addrs, vec![addr("[2001:db8::1]:25"), addr("192.0.2.1:25")]);
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn falls_back_after_a_timeout() {
+        hangs(addr("[2001:db8::1]:25"));
+        accepts(addr("192.0.2.1:25"), Duration::ZERO);
+        resolves(
+            "mx.example.com",
+            &["2001:db8::1".parse().unwrap(), "192.0.2.1".parse().unwrap()],
+        );
+        let options = options()
+            .connect_timeout(Duration::from_millis(50))
+            .connection_attempt_delay(Duration::from_secs(10));
+
+        let attempts = connect_tcp(&options, "mx.example.com").unwrap();
+
+        assert_eq!(attempts.len(), 2);
+        assert!(attempts[1].at - attempts[0].at >= Duration::from_millis(50));
+        assert!(attempts[1].at - attempts[0].at < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn waits_the_connection_attempt_delay() {
+        accepts(addr("[2001:db8::1]:25"), Duration::from_millis(500));
+        accepts(addr("192.0.2.1:25"), Duration::ZERO);
+        resolves(
+            "mx.example.com",
+            &["2001:db8::1".parse().unwrap(), "192.0.2.1".parse().unwrap()],
+        );
+        let racing = options().connection_attempt_delay(Duration::from_millis(50));
+
+        let attempts = connect_tcp(&racing, "mx.example.com").unwrap();
+
+        // The slow first attempt is raced by the next one
+        assert_eq!(attempts.len(), 2);
+        let delay = attempts[1].at - attempts[0].at;
+        assert!(delay >= Duration::from_millis(50), "{:?}", delay);
+        assert!(delay < Duration::from_millis(500), "{:?}", delay);
+        assert!(sleeps().contains(&Duration::from_millis(50)));
+
+        // No other attempt once connected
+        NETWORK.with(|network| network.borrow_mut().attempts.clear());
+        accepts(addr("[2001:db8::1]:25"), Duration::from_millis(10));
+        let options = options().connection_attempt_delay(Duration::from_millis(200));
+        let attempts = connect_tcp(&options, "mx.example.com").unwrap();
+        assert_eq!(attempts.len(), 1);
+    }
+
+    #[test]
+    fn returns_the_last_error() {
+        resolves("mx.example.com", &["192.0.2.1".parse().unwrap()]);
+        let err = connect_tcp(&options(), "mx.example.com").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+
+        let err = connect_tcp(&options(), "missing.example.com").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+
+        // An address literal is not resolved
+        accepts(addr("[2001:db8::9]:25"), Duration::ZERO);
+        connect_tcp(&options(), "[2001:db8::9]").unwrap();
+    }
+}
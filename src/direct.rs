@@ -0,0 +1,439 @@
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::result;
+
+use async_stream_packed::TlsClientUpgrader;
+use async_trait::async_trait;
+use futures_util::io::{AsyncRead, AsyncWrite};
+use lettre::transport::smtp::{
+    error::Error,
+    response::{Category, Code, Detail, Response, Severity},
+};
+use lettre::{Address, Envelope};
+
+use crate::connection::AsyncConnection;
+use crate::connector::{ConnectOptions, Runtime};
+
+const SMTP_PORT: u16 = 25;
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Mx {
+    pub preference: u16,
+    pub exchange: String,
+}
+
+impl Mx {
+    pub fn new(preference: u16, exchange: impl Into<String>) -> Self {
+        Self {
+            preference,
+            exchange: exchange.into(),
+        }
+    }
+
+    // ref https://tools.ietf.org/html/rfc7505#section-3
+    pub fn is_null(&self) -> bool {
+        self.preference == 0 && (self.exchange.is_empty() || self.exchange == ".")
+    }
+}
+
+/// `Ok(vec![])` when the name has no records of that type, `io::ErrorKind::NotFound` when the
+/// name does not exist.
+#[async_trait]
+pub trait Resolver: Send + Sync {
+    async fn mx(&self, domain: &str) -> io::Result<Vec<Mx>>;
+
+    async fn ip(&self, host: &str) -> io::Result<Vec<IpAddr>>;
+}
+
+/// Fixed records, names without any record are reported as not existing.
+#[derive(Default, Clone, Debug)]
+pub struct StaticResolver {
+    mx: HashMap<String, Vec<Mx>>,
+    ip: HashMap<String, Vec<IpAddr>>,
+}
+
+impl StaticResolver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn mx(mut self, domain: &str, preference: u16, exchange: &str) -> Self {
+        self.mx
+            .entry(normalize(domain))
+            .or_default()
+            .push(Mx::new(preference, exchange));
+        self
+    }
+
+    pub fn ip(mut self, host: &str, ip: IpAddr) -> Self {
+        self.ip.entry(normalize(host)).or_default().push(ip);
+        self
+    }
+
+    fn exists(&self, name: &str) -> bool {
+        self.mx.contains_key(name) || self.ip.contains_key(name)
+    }
+}
+
+#[async_trait]
+impl Resolver for StaticResolver {
+    async fn mx(&self, domain: &str) -> io::Result<Vec<Mx>> {
+        let domain = normalize(domain);
+        if !self.exists(&domain) {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "domain not found"));
+        }
+        Ok(self.mx.get(&domain).cloned().unwrap_or_default())
+    }
+
+    async fn ip(&self, host: &str) -> io::Result<Vec<IpAddr>> {
+        let host = normalize(host);
+        if !self.exists(&host) {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "host not found"));
+        }
+        Ok(self.ip.get(&host).cloned().unwrap_or_default())
+    }
+}
+
+fn normalize(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+//
+//
+//
+#[derive(Debug)]
+pub struct DomainOutcome {
+    pub domain: String,
+    pub recipients: Vec<Address>,
+    /// The last host tried.
+    pub mx_host: Option<String>,
+    /// The source address of the connection to `mx_host`.
+    pub local_addr: Option<SocketAddr>,
+    pub result: result::Result<Response, Error>,
+}
+
+/// Delivers to the MX hosts of each recipient domain, without a smarthost.
+pub struct DirectDelivery<R, RS> {
+    resolver: RS,
+    options: ConnectOptions<R>,
+    port: u16,
+}
+
+impl<R, RS> DirectDelivery<R, RS>
+where
+    R: Runtime + 'static,
+    RS: Resolver,
+{
+    pub fn new(resolver: RS, options: ConnectOptions<R>) -> Self {
+        Self {
+            resolver,
+            options,
+            port: SMTP_PORT,
+        }
+    }
+
+    pub fn port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    /// MX hosts in the order they should be tried, empty when the domain accepts no mail. Hosts
+    /// of the same preference are shuffled.
+    // ref https://tools.ietf.org/html/rfc5321#section-5.1
+    pub async fn lookup(&self, domain: &str) -> result::Result<Vec<String>, Error> {
+        let mut mxs = match self.resolver.mx(domain).await {
+            Ok(mxs) => mxs,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                return Err(permanent(Detail::Zero, "5.1.2 Recipient domain not found"))
+            }
+            Err(err) => return Err(err.into()),
+        };
+
+        if mxs.is_empty() {
+            // Implicit MX
+            return Ok(vec![normalize(domain)]);
+        }
+        // A null MX is the only record, it is ignored among others
+        // ref https://tools.ietf.org/html/rfc7505#section-3
+        if let [mx] = &mxs[..] {
+            if mx.is_null() {
+                return Ok(vec![]);
+            }
+        }
+        mxs.retain(|mx| !mx.is_null());
+
+        // The keys of a new RandomState are random, hashing with them spreads the load
+        let state = RandomState::new();
+        mxs.sort_by_cached_key(|mx| (mx.preference, state.hash_one(&mx.exchange)));
+        Ok(mxs.into_iter().map(|mx| normalize(&mx.exchange)).collect())
+    }
+
+    pub async fn send<STU, F>(
+        &self,
+        envelope: &Envelope,
+        email: &[u8],
+        upgrader: F,
+    ) -> Vec<DomainOutcome>
+    where
+        F: Fn(&str) -> STU,
+        STU: TlsClientUpgrader<R::TcpStream> + Unpin,
+        STU::Output: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut outcomes = vec![];
+
+        for (domain, recipients) in group_by_domain(envelope.to()) {
+            let envelope =
+                Envelope::new(envelope.from().cloned(), recipients.clone()).expect("never");
+
+            let mut outcome = DomainOutcome {
+                domain,
+                recipients,
+                mx_host: None,
+                local_addr: None,
+                result: Err(Error::Client("No MX host was reachable")),
+            };
+            self.send_to_domain(&mut outcome, &envelope, email, &upgrader)
+                .await;
+            outcomes.push(outcome);
+        }
+
+        outcomes
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(name = "smtp.direct", skip_all, fields(domain = %outcome.domain))
+    )]
+    async fn send_to_domain<STU, F>(
+        &self,
+        outcome: &mut DomainOutcome,
+        envelope: &Envelope,
+        email: &[u8],
+        upgrader: &F,
+    ) where
+        F: Fn(&str) -> STU,
+        STU: TlsClientUpgrader<R::TcpStream> + Unpin,
+        STU::Output: AsyncRead + AsyncWrite + Unpin,
+    {
+        let hosts = match self.lookup(&outcome.domain).await {
+            Ok(hosts) if hosts.is_empty() => {
+                // ref https://tools.ietf.org/html/rfc7505#section-4.1
+                outcome.result = Err(permanent(
+                    Detail::Six,
+                    "5.1.10 Recipient address has null MX",
+                ));
+                return;
+            }
+            Ok(hosts) => hosts,
+            Err(err) => {
+                outcome.result = Err(err);
+                return;
+            }
+        };
+
+        for host in hosts {
+            let ips = match self.resolver.ip(&host).await {
+                Ok(ips) if !ips.is_empty() => ips,
+                Ok(_) => continue,
+                Err(err) => {
+                    outcome.result = Err(err.into());
+                    continue;
+                }
+            };
+
+            smtp_event!(mx = %host, "trying MX host");
+            outcome.mx_host = Some(host.clone());
+            outcome.local_addr = None;
+
+            let permit = self.options.connection_permit(&host).await;
+            let stream = match self.options.connect_ips(&host, &ips, self.port).await {
+                Ok(stream) => stream,
+                Err(err) => {
+                    outcome.result = Err(err.into());
+                    continue;
+                }
+            };
+
+            outcome.local_addr = R::local_addr(&stream).ok();
+
+            let mut connection = AsyncConnection::new(stream, upgrader(&host));
+            if let Some(addr) = outcome.local_addr {
+                connection.set_local_addr(addr);
+            }
+            self.options.configure(&host, &mut connection);
+            connection.set_connection_permit(permit);
+            let result = async {
+                connection
+                    .handshake(
+                        self.options.tls.is_smtps(self.port),
+                        self.options.hello_name.clone(),
+                    )
+                    .await?;
+                let response = connection.send(envelope, email).await?;
+                let _ = connection.quit().await;
+                result::Result::<_, Error>::Ok(response)
+            }
+            .await;
+
+            // The other hosts would reject it too
+            let done = matches!(result, Ok(_) | Err(Error::Permanent(_)));
+            outcome.result = result;
+            if done {
+                return;
+            }
+        }
+    }
+}
+
+// Keeps the order in which the domains first appear
+fn group_by_domain(recipients: &[Address]) -> Vec<(String, Vec<Address>)> {
+    let mut groups: Vec<(String, Vec<Address>)> = vec![];
+
+    for recipient in recipients {
+        let domain = normalize(&recipient.domain);
+        match groups.iter_mut().find(|(d, _)| d == &domain) {
+            Some((_, addresses)) => addresses.push(recipient.clone()),
+            None => groups.push((domain, vec![recipient.clone()])),
+        }
+    }
+
+    groups
+}
+
+fn permanent(detail: Detail, message: &str) -> Error {
+    Error::Permanent(Response::new(
+        Code::new(
+            Severity::PermanentNegativeCompletion,
+            Category::MailSystem,
+            detail,
+        ),
+        vec![message.to_owned()],
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use lettre::transport::smtp::extension::ClientId;
+
+    use super::*;
+    use crate::connector::tests::TestRuntime;
+
+    fn delivery(resolver: StaticResolver) -> DirectDelivery<TestRuntime, StaticResolver> {
+        let options = ConnectOptions::new(ClientId::Domain("client.example.com".to_owned()));
+        DirectDelivery::new(resolver, options)
+    }
+
+    fn lookup(
+        delivery: &DirectDelivery<TestRuntime, StaticResolver>,
+        domain: &str,
+    ) -> result::Result<Vec<String>, Error> {
+        async_io::block_on(delivery.lookup(domain))
+    }
+
+    #[test]
+    fn shuffles_equal_preferences() {
+        let delivery = delivery(
+            StaticResolver::new()
+                .mx("example.com", 20, "backup.example.com")
+                .mx("example.com", 10, "mx1.example.com")
+                .mx("example.com", 10, "MX2.example.com.")
+                .mx("example.com", 10, "mx3.example.com"),
+        );
+
+        let mut orders = HashSet::new();
+        for _ in 0..100 {
+            let hosts = lookup(&delivery, "example.com").unwrap();
+            assert_eq!(hosts.len(), 4);
+            assert_eq!(hosts[3], "backup.example.com");
+            let mut primaries = hosts[..3].to_vec();
+            primaries.sort();
+            assert_eq!(
+                primaries,
+                ["mx1.example.com", "mx2.example.com", "mx3.example.com"]
+            );
+            orders.insert(hosts);
+        }
+        assert!(orders.len() > 1);
+    }
+
+    #[test]
+    fn implicit_and_null_mx() {
+        let delivery = delivery(
+            StaticResolver::new()
+                .ip("Example.com.", IpAddr::from([192, 0, 2, 1]))
+                .mx("null.example.com", 0, ".")
+                .mx("mixed.example.com", 0, ".")
+                .mx("mixed.example.com", 10, "mx.mixed.example.com"),
+        );
+
+        assert_eq!(lookup(&delivery, "Example.com.").unwrap(), ["example.com"]);
+        assert!(lookup(&delivery, "null.example.com").unwrap().is_empty());
+        assert_eq!(
+            lookup(&delivery, "mixed.example.com").unwrap(),
+            ["mx.mixed.example.com"]
+        );
+        assert!(matches!(
+            lookup(&delivery, "missing.example.com"),
+            Err(Error::Permanent(_))
+        ));
+    }
+
+    #[test]
+    fn selects_the_source_address_by_mx_host() {
+        use std::sync::Arc;
+        use std::time::Duration;
+
+        use crate::client::AsyncClient;
+        use crate::connector::tests::{accepts, attempts, resolves};
+        use crate::source_address::{SourceAddressPool, SourceAddressStrategy};
+        use crate::stream::NoTls;
+
+        let sources: Vec<IpAddr> = vec![[192, 0, 2, 1].into(), [192, 0, 2, 2].into()];
+        let pool = Arc::new(SourceAddressPool::new(
+            sources.clone(),
+            SourceAddressStrategy::PerDestination,
+        ));
+        let options =
+            ConnectOptions::<TestRuntime>::new(ClientId::Domain("client.example.com".to_owned()))
+                .source_address_pool(pool);
+        let mx_ip: IpAddr = [198, 51, 100, 1].into();
+        let delivery = DirectDelivery::new(
+            StaticResolver::new()
+                .mx("a.example.com", 10, "mx.example.net")
+                .mx("b.example.com", 10, "mx.example.net")
+                .ip("mx.example.net", mx_ip),
+            options,
+        );
+        accepts(SocketAddr::new(mx_ip, SMTP_PORT), Duration::ZERO);
+        resolves("mx.example.net", &[mx_ip]);
+
+        // The in-memory streams fail the handshake, after the connection
+        let envelope = Envelope::new(
+            None,
+            vec![
+                "a@a.example.com".parse().unwrap(),
+                "b@b.example.com".parse().unwrap(),
+            ],
+        )
+        .unwrap();
+        async_io::block_on(delivery.send(&envelope, b"", |_| NoTls));
+        let _ = async_io::block_on(AsyncClient::connect(
+            "mx.example.net",
+            SMTP_PORT,
+            NoTls,
+            &delivery.options,
+        ));
+
+        // One rotation for the MX host, whatever the domain or the caller
+        let locals: Vec<_> = attempts()
+            .iter()
+            .map(|attempt| attempt.local.unwrap())
+            .collect();
+        assert_eq!(locals, vec![sources[0], sources[1], sources[0]]);
+    }
+}
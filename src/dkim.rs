@@ -0,0 +1,693 @@
+use std::fmt;
+use std::io;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use ring::digest::{digest, SHA256};
+use ring::rand::SystemRandom;
+use ring::signature::{Ed25519KeyPair, RsaKeyPair, RSA_PKCS1_SHA256};
+
+// ref https://tools.ietf.org/html/rfc6376#section-5.4.1
+pub(crate) const DEFAULT_HEADERS: &[&str] = &[
+    "From",
+    "Reply-To",
+    "Subject",
+    "Date",
+    "To",
+    "Cc",
+    "Message-ID",
+    "In-Reply-To",
+    "References",
+    "MIME-Version",
+    "Content-Type",
+    "Content-Transfer-Encoding",
+    "List-Id",
+    "List-Unsubscribe",
+    "List-Unsubscribe-Post",
+];
+
+pub enum DkimKey {
+    Rsa(RsaKeyPair),
+    Ed25519(Ed25519KeyPair),
+}
+
+impl DkimKey {
+    /// PKCS#1 (`RSA PRIVATE KEY`) or PKCS#8 (`PRIVATE KEY`).
+    pub fn rsa_pem(pem: &str) -> io::Result<Self> {
+        let (label, der) = decode_pem(pem)?;
+        match label.as_str() {
+            "RSA PRIVATE KEY" => Self::rsa_der(&der),
+            _ => RsaKeyPair::from_pkcs8(&der)
+                .map(Self::Rsa)
+                .map_err(key_rejected),
+        }
+    }
+
+    /// PKCS#1.
+    pub fn rsa_der(der: &[u8]) -> io::Result<Self> {
+        RsaKeyPair::from_der(der)
+            .map(Self::Rsa)
+            .map_err(key_rejected)
+    }
+
+    /// PKCS#8 (`PRIVATE KEY`), as written by `openssl genpkey -algorithm ed25519`.
+    pub fn ed25519_pem(pem: &str) -> io::Result<Self> {
+        let (_, der) = decode_pem(pem)?;
+        Ed25519KeyPair::from_pkcs8_maybe_unchecked(&der)
+            .map(Self::Ed25519)
+            .map_err(key_rejected)
+    }
+
+    /// The 32 bytes private key.
+    pub fn ed25519_seed(seed: &[u8]) -> io::Result<Self> {
+        Ed25519KeyPair::from_seed_unchecked(seed)
+            .map(Self::Ed25519)
+            .map_err(key_rejected)
+    }
+
+    pub fn algorithm(&self) -> &'static str {
+        match self {
+            Self::Rsa(_) => "rsa-sha256",
+            Self::Ed25519(_) => "ed25519-sha256",
+        }
+    }
+
+    pub(crate) fn sign(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Self::Rsa(key_pair) => {
+                let mut signature = vec![0; key_pair.public_modulus_len()];
+                key_pair
+                    .sign(
+                        &RSA_PKCS1_SHA256,
+                        &SystemRandom::new(),
+                        data,
+                        &mut signature,
+                    )
+                    .map_err(|_| io::Error::other("RSA signing failed"))?;
+                Ok(signature)
+            }
+            // The hash is signed
+            // ref https://tools.ietf.org/html/rfc8463#section-3
+            Self::Ed25519(key_pair) => Ok(key_pair
+                .sign(digest(&SHA256, data).as_ref())
+                .as_ref()
+                .to_vec()),
+        }
+    }
+}
+
+impl fmt::Debug for DkimKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("DkimKey").field(&self.algorithm()).finish()
+    }
+}
+
+fn key_rejected(err: ring::error::KeyRejected) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, err.to_string())
+}
+
+fn decode_pem(pem: &str) -> io::Result<(String, Vec<u8>)> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidInput, "invalid PEM");
+
+    let label = pem
+        .lines()
+        .find_map(|line| {
+            line.trim()
+                .strip_prefix("-----BEGIN ")
+                .and_then(|rest| rest.strip_suffix("-----"))
+        })
+        .ok_or_else(invalid)?
+        .to_owned();
+    let base64: String = pem
+        .lines()
+        .map(str::trim)
+        .skip_while(|line| !line.starts_with("-----BEGIN "))
+        .skip(1)
+        .take_while(|line| !line.starts_with("-----END "))
+        .collect();
+
+    let der = base64::decode(base64).map_err(|_| invalid())?;
+    Ok((label, der))
+}
+
+//
+//
+//
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DkimCanonicalization {
+    Simple,
+    Relaxed,
+}
+
+impl DkimCanonicalization {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Simple => "simple",
+            Self::Relaxed => "relaxed",
+        }
+    }
+}
+
+/// Signs messages with CRLF line endings, e.g. `Message::formatted()`.
+#[derive(Debug)]
+pub struct DkimSigner {
+    domain: String,
+    selector: String,
+    key: DkimKey,
+    header_canonicalization: DkimCanonicalization,
+    body_canonicalization: DkimCanonicalization,
+    headers: Vec<String>,
+    oversign: bool,
+}
+
+impl DkimSigner {
+    /// relaxed/relaxed, the usual headers.
+    pub fn new(domain: impl Into<String>, selector: impl Into<String>, key: DkimKey) -> Self {
+        Self {
+            domain: domain.into(),
+            selector: selector.into(),
+            key,
+            header_canonicalization: DkimCanonicalization::Relaxed,
+            body_canonicalization: DkimCanonicalization::Relaxed,
+            headers: DEFAULT_HEADERS.iter().map(ToString::to_string).collect(),
+            oversign: false,
+        }
+    }
+
+    pub fn canonicalization(
+        mut self,
+        header: DkimCanonicalization,
+        body: DkimCanonicalization,
+    ) -> Self {
+        self.header_canonicalization = header;
+        self.body_canonicalization = body;
+        self
+    }
+
+    /// The headers to sign when present, `From` is always signed.
+    pub fn headers(mut self, headers: &[&str]) -> Self {
+        self.headers = headers.iter().map(ToString::to_string).collect();
+        self
+    }
+
+    /// Also signs the absence of one more instance of each header, so none can be added.
+    // ref https://tools.ietf.org/html/rfc6376#section-8.15
+    pub fn oversign(mut self, oversign: bool) -> Self {
+        self.oversign = oversign;
+        self
+    }
+
+    /// The message with the `DKIM-Signature` header prepended.
+    // ref https://tools.ietf.org/html/rfc6376#section-5
+    pub fn sign(&self, message: &[u8]) -> io::Result<Vec<u8>> {
+        let (headers, body) = split_message(message);
+        if !headers.iter().any(|header| header.is("From")) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the From header is required",
+            ));
+        }
+
+        let body_hash = body_hash(body, self.body_canonicalization);
+        let names = signed_names(&headers, &self.headers, self.oversign);
+
+        let tags = vec![
+            "v=1".to_owned(),
+            format!("a={}", self.key.algorithm()),
+            format!(
+                "c={}/{}",
+                self.header_canonicalization.as_str(),
+                self.body_canonicalization.as_str()
+            ),
+            format!("d={}", self.domain),
+            format!("s={}", self.selector),
+            format!("t={}", timestamp()),
+            format!("h={}", names.join(":").to_ascii_lowercase()),
+            format!("bh={}", body_hash),
+        ];
+
+        let signature_header = sign_header(
+            "DKIM-Signature",
+            &tags,
+            canonicalize_headers(&headers, &names, self.header_canonicalization),
+            self.header_canonicalization,
+            &self.key,
+        )?;
+
+        let mut signed = signature_header.into_bytes();
+        signed.extend_from_slice(message);
+        Ok(signed)
+    }
+}
+
+/// `From` first, then each of `wanted` as many times as present, plus one when oversigning.
+pub(crate) fn signed_names<'a>(
+    headers: &[Header<'_>],
+    wanted: &'a [String],
+    oversign: bool,
+) -> Vec<&'a str> {
+    let mut names = vec![];
+    let from = std::iter::once("From");
+    let others = wanted
+        .iter()
+        .map(String::as_str)
+        .filter(|name| !name.eq_ignore_ascii_case("From"));
+    for name in from.chain(others) {
+        let count = headers.iter().filter(|header| header.is(name)).count();
+        let count = if oversign { count + 1 } else { count };
+        names.extend(std::iter::repeat_n(name, count));
+    }
+    names
+}
+
+pub(crate) fn body_hash(body: &[u8], canonicalization: DkimCanonicalization) -> String {
+    base64::encode(digest(&SHA256, &canonicalize_body(body, canonicalization)))
+}
+
+pub(crate) fn timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// `name: tags; b=<signature>` with the CRLF, `data` is the canonicalized signed headers.
+pub(crate) fn sign_header(
+    name: &str,
+    tags: &[String],
+    mut data: Vec<u8>,
+    canonicalization: DkimCanonicalization,
+    key: &DkimKey,
+) -> io::Result<String> {
+    let unsigned = format!("{}: {};\r\n\tb=", name, tags.join(";\r\n\t"));
+
+    // Without the trailing CRLF
+    // ref https://tools.ietf.org/html/rfc6376#section-3.7
+    data.extend_from_slice(
+        canonicalize_header(&unsigned, canonicalization)
+            .trim_end_matches("\r\n")
+            .as_bytes(),
+    );
+
+    let signature = base64::encode(key.sign(&data)?);
+    let folded: Vec<&str> = signature
+        .as_bytes()
+        .chunks(72)
+        .map(|chunk| std::str::from_utf8(chunk).expect("never"))
+        .collect();
+
+    Ok(format!("{}{}\r\n", unsigned, folded.join("\r\n\t ")))
+}
+
+//
+//
+//
+/// A header field, `raw` includes the continuation lines and the CRLF.
+pub(crate) struct Header<'a> {
+    pub(crate) name: &'a str,
+    pub(crate) raw: &'a str,
+}
+
+impl Header<'_> {
+    pub(crate) fn is(&self, name: &str) -> bool {
+        self.name.eq_ignore_ascii_case(name)
+    }
+}
+
+pub(crate) fn split_message(message: &[u8]) -> (Vec<Header<'_>>, &[u8]) {
+    let (head, body) = match message.windows(4).position(|window| window == b"\r\n\r\n") {
+        Some(i) => (&message[..i + 2], &message[i + 4..]),
+        None => (message, &b""[..]),
+    };
+    // Header fields are ASCII
+    let head = std::str::from_utf8(head).unwrap_or_default();
+
+    let mut headers: Vec<Header<'_>> = vec![];
+    let mut start = 0;
+    let mut offset = 0;
+    for line in head.split_inclusive("\r\n") {
+        if offset > 0 && !line.starts_with([' ', '\t']) {
+            push_header(&mut headers, &head[start..offset]);
+            start = offset;
+        }
+        offset += line.len();
+    }
+    if offset > start {
+        push_header(&mut headers, &head[start..offset]);
+    }
+
+    (headers, body)
+}
+
+fn push_header<'a>(headers: &mut Vec<Header<'a>>, raw: &'a str) {
+    if let Some((name, _)) = raw.split_once(':') {
+        headers.push(Header {
+            name: name.trim_end(),
+            raw,
+        });
+    }
+}
+
+// The instances are taken from the bottom, a missing one is the empty string
+// ref https://tools.ietf.org/html/rfc6376#section-5.4.2
+pub(crate) fn canonicalize_headers(
+    headers: &[Header<'_>],
+    names: &[&str],
+    canonicalization: DkimCanonicalization,
+) -> Vec<u8> {
+    let mut used = vec![false; headers.len()];
+    let mut out = vec![];
+    for name in names {
+        if let Some(i) = (0..headers.len())
+            .rev()
+            .find(|i| !used[*i] && headers[*i].is(name))
+        {
+            used[i] = true;
+            out.extend_from_slice(canonicalize_header(headers[i].raw, canonicalization).as_bytes());
+        }
+    }
+    out
+}
+
+// ref https://tools.ietf.org/html/rfc6376#section-3.4.1
+// ref https://tools.ietf.org/html/rfc6376#section-3.4.2
+pub(crate) fn canonicalize_header(raw: &str, canonicalization: DkimCanonicalization) -> String {
+    match canonicalization {
+        DkimCanonicalization::Simple => raw.to_owned(),
+        DkimCanonicalization::Relaxed => {
+            let (name, value) = raw.split_once(':').unwrap_or((raw, ""));
+            let value = value.replace("\r\n", "");
+            format!(
+                "{}:{}\r\n",
+                name.trim_end().to_ascii_lowercase(),
+                collapse_whitespace(&value).trim_matches(' ')
+            )
+        }
+    }
+}
+
+// ref https://tools.ietf.org/html/rfc6376#section-3.4.3
+// ref https://tools.ietf.org/html/rfc6376#section-3.4.4
+fn canonicalize_body(body: &[u8], canonicalization: DkimCanonicalization) -> Vec<u8> {
+    let mut lines: Vec<Vec<u8>> = body
+        .split_inclusive(|b| *b == b'\n')
+        .map(|line| {
+            let line = line
+                .strip_suffix(b"\n")
+                .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+                .unwrap_or(line);
+            match canonicalization {
+                DkimCanonicalization::Simple => line.to_vec(),
+                DkimCanonicalization::Relaxed => {
+                    let mut out: Vec<u8> = vec![];
+                    for b in line {
+                        if *b == b' ' || *b == b'\t' {
+                            if out.last() != Some(&b' ') {
+                                out.push(b' ');
+                            }
+                        } else {
+                            out.push(*b);
+                        }
+                    }
+                    while out.last() == Some(&b' ') {
+                        out.pop();
+                    }
+                    out
+                }
+            }
+        })
+        .collect();
+    while lines.last().is_some_and(|line| line.is_empty()) {
+        lines.pop();
+    }
+
+    if lines.is_empty() {
+        return match canonicalization {
+            DkimCanonicalization::Simple => b"\r\n".to_vec(),
+            DkimCanonicalization::Relaxed => vec![],
+        };
+    }
+
+    let mut out = vec![];
+    for line in lines {
+        out.extend_from_slice(&line);
+        out.extend_from_slice(b"\r\n");
+    }
+    out
+}
+
+fn collapse_whitespace(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for c in value.chars() {
+        if c == ' ' || c == '\t' {
+            if !out.ends_with(' ') {
+                out.push(' ');
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use ring::signature::{KeyPair, UnparsedPublicKey, ED25519, RSA_PKCS1_2048_8192_SHA256};
+
+    use super::*;
+
+    const RSA_KEY: &str = include_str!("../tests/keys/dkim_rsa.pem");
+
+    // ref https://tools.ietf.org/html/rfc8463#appendix-A
+    const RFC8463_SEED: &str = "nWGxne/9WmC6hEr0kuwsxERJxWl7MmkZcDusAxyuf2A=";
+    const RFC8463_MESSAGE: &[u8] = b"From: Joe SixPack <joe@football.example.com>\r\n\
+To: Suzie Q <suzie@shopping.example.net>\r\n\
+Subject: Is dinner ready?\r\n\
+Date: Fri, 11 Jul 2003 21:00:37 -0700 (PDT)\r\n\
+Message-ID: <20030712040037.46341.5F8J@football.example.com>\r\n\
+\r\n\
+Hi.\r\n\
+\r\n\
+We lost the game.  Are you hungry yet?\r\n\
+\r\n\
+Joe.\r\n";
+
+    const MESSAGE: &[u8] = b"From: a@example.com\r\n\
+To: b@example.com\r\n\
+Subject:  Hello \t world\r\n\
+\r\n\
+Hi  there \r\n\
+\r\n\
+\r\n";
+
+    // The signature over the headers in `h=` and the DKIM-Signature with an empty `b=`
+    fn verify(signed: &[u8], public_key: UnparsedPublicKey<&[u8]>, prehash: bool) {
+        let (headers, body) = split_message(signed);
+        let signature = &headers[0];
+        assert!(signature.is("DKIM-Signature"));
+
+        let tag = |name: &str| {
+            signature.raw.split(';').find_map(|tag| {
+                let (tag_name, value) = tag.split_once('=')?;
+                (tag_name.trim() == name).then(|| value.split_whitespace().collect::<String>())
+            })
+        };
+        let canonicalization = match tag("c").unwrap().as_str() {
+            "simple/simple" => DkimCanonicalization::Simple,
+            "relaxed/relaxed" => DkimCanonicalization::Relaxed,
+            c => panic!("{}", c),
+        };
+        assert_eq!(tag("bh").unwrap(), body_hash(body, canonicalization));
+
+        let h = tag("h").unwrap();
+        let names: Vec<&str> = h.split(':').collect();
+        let mut data = canonicalize_headers(&headers[1..], &names, canonicalization);
+        let unsigned = &signature.raw[..signature.raw.rfind("b=").unwrap() + 2];
+        data.extend_from_slice(
+            canonicalize_header(unsigned, canonicalization)
+                .trim_end_matches("\r\n")
+                .as_bytes(),
+        );
+
+        let data = if prehash {
+            digest(&SHA256, &data).as_ref().to_vec()
+        } else {
+            data
+        };
+        let b = base64::decode(tag("b").unwrap()).unwrap();
+        public_key.verify(&data, &b).unwrap();
+    }
+
+    // ref https://tools.ietf.org/html/rfc6376#section-3.4.5
+    #[test]
+    fn canonicalizes_headers() {
+        let raw = "B : Y\t\r\n\tZ  \r\n";
+        assert_eq!(
+            canonicalize_header("A: X\r\n", DkimCanonicalization::Relaxed),
+            "a:X\r\n"
+        );
+        assert_eq!(
+            canonicalize_header(raw, DkimCanonicalization::Relaxed),
+            "b:Y Z\r\n"
+        );
+        assert_eq!(canonicalize_header(raw, DkimCanonicalization::Simple), raw);
+    }
+
+    // ref https://tools.ietf.org/html/rfc6376#section-3.4.5
+    #[test]
+    fn canonicalizes_body() {
+        let body = b" C \r\nD \t E\r\n\r\n\r\n";
+        assert_eq!(
+            canonicalize_body(body, DkimCanonicalization::Relaxed),
+            b" C\r\nD E\r\n"
+        );
+        assert_eq!(
+            canonicalize_body(body, DkimCanonicalization::Simple),
+            b" C \r\nD \t E\r\n"
+        );
+    }
+
+    // ref https://tools.ietf.org/html/rfc6376#section-3.4.3
+    // ref https://tools.ietf.org/html/rfc6376#section-3.4.4
+    #[test]
+    fn hashes_empty_body() {
+        assert_eq!(
+            body_hash(b"", DkimCanonicalization::Simple),
+            "frcCV1k9oG9oKj3dpUqdJg1PxRT2RSN/XKdLCPjaYaY="
+        );
+        assert_eq!(
+            body_hash(b"\r\n\r\n", DkimCanonicalization::Relaxed),
+            "47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU="
+        );
+    }
+
+    #[test]
+    fn oversigns_headers() {
+        let (headers, _) = split_message(MESSAGE);
+        let wanted: Vec<String> = ["Subject", "To", "Cc"]
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+
+        assert_eq!(
+            signed_names(&headers, &wanted, false),
+            ["From", "Subject", "To"]
+        );
+        assert_eq!(
+            signed_names(&headers, &wanted, true),
+            ["From", "From", "Subject", "Subject", "To", "To", "Cc"]
+        );
+        // The extra instances sign the absence of the header
+        assert_eq!(
+            canonicalize_headers(
+                &headers,
+                &["From", "From", "Cc"],
+                DkimCanonicalization::Relaxed
+            ),
+            b"from:a@example.com\r\n"
+        );
+    }
+
+    #[test]
+    fn ed25519_rfc8463_vector() {
+        let key = DkimKey::ed25519_seed(&base64::decode(RFC8463_SEED).unwrap()).unwrap();
+        let (headers, body) = split_message(RFC8463_MESSAGE);
+        let names = [
+            "from",
+            "to",
+            "subject",
+            "date",
+            "message-id",
+            "from",
+            "subject",
+            "date",
+        ];
+        let canonicalization = DkimCanonicalization::Relaxed;
+        let bh = body_hash(body, canonicalization);
+        assert_eq!(bh, "2jUSOH9NhtVGCQWNr9BrIAPreKQjO6Sn7XIkfJVOzv8=");
+
+        let header = sign_header(
+            "DKIM-Signature",
+            &[
+                "v=1".to_owned(),
+                "a=ed25519-sha256".to_owned(),
+                "c=relaxed/relaxed".to_owned(),
+                "d=football.example.com".to_owned(),
+                "i=@football.example.com".to_owned(),
+                "q=dns/txt".to_owned(),
+                "s=brisbane".to_owned(),
+                "t=1528637909".to_owned(),
+                format!("h={}", names.join(" : ")),
+                format!("bh={}", bh),
+            ],
+            canonicalize_headers(&headers, &names, canonicalization),
+            canonicalization,
+            &key,
+        )
+        .unwrap();
+
+        let b: String = header
+            .split("b=")
+            .last()
+            .unwrap()
+            .split_whitespace()
+            .collect();
+        assert_eq!(
+            b,
+            "/gCrinpcQOoIfuHNQIbq4pgh9kyIK3AQUdt9OdqQehSwhEIug4D11BusFa3bT3FY5OsU7ZbnKELq+eXdp1Q1Dw=="
+        );
+    }
+
+    #[test]
+    fn ed25519_round_trip() {
+        let seed = base64::decode(RFC8463_SEED).unwrap();
+        let public_key = Ed25519KeyPair::from_seed_unchecked(&seed)
+            .unwrap()
+            .public_key()
+            .as_ref()
+            .to_vec();
+
+        for canonicalization in [DkimCanonicalization::Simple, DkimCanonicalization::Relaxed] {
+            let signer = DkimSigner::new(
+                "example.com",
+                "selector",
+                DkimKey::ed25519_seed(&seed).unwrap(),
+            )
+            .canonicalization(canonicalization, canonicalization)
+            .oversign(true);
+            let signed = signer.sign(MESSAGE).unwrap();
+
+            verify(&signed, UnparsedPublicKey::new(&ED25519, &public_key), true);
+        }
+    }
+
+    #[test]
+    fn rsa_round_trip() {
+        let public_key = match DkimKey::rsa_pem(RSA_KEY).unwrap() {
+            DkimKey::Rsa(key_pair) => key_pair.public_key().as_ref().to_vec(),
+            DkimKey::Ed25519(_) => unreachable!(),
+        };
+
+        for canonicalization in [DkimCanonicalization::Simple, DkimCanonicalization::Relaxed] {
+            let signer = DkimSigner::new(
+                "example.com",
+                "selector",
+                DkimKey::rsa_pem(RSA_KEY).unwrap(),
+            )
+            .canonicalization(canonicalization, canonicalization);
+            let signed = signer.sign(MESSAGE).unwrap();
+
+            verify(
+                &signed,
+                UnparsedPublicKey::new(&RSA_PKCS1_2048_8192_SHA256, &public_key),
+                false,
+            );
+        }
+    }
+
+    #[test]
+    fn requires_from() {
+        let key = DkimKey::ed25519_seed(&base64::decode(RFC8463_SEED).unwrap()).unwrap();
+        let signer = DkimSigner::new("example.com", "selector", key);
+        assert!(signer.sign(b"Subject: Hi\r\n\r\nHi\r\n").is_err());
+    }
+}
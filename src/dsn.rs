@@ -0,0 +1,179 @@
+use std::result;
+use std::time::SystemTime;
+
+use lettre::error::Error as EnvelopeError;
+use lettre::message::header::EmailDate;
+use lettre::transport::smtp::error::Error;
+use lettre::{Address, Envelope};
+
+use crate::receipt::enhanced_status_code;
+
+#[derive(Clone, Debug)]
+pub struct FailedRecipient {
+    pub recipient: Address,
+    /// Enhanced status code, e.g. `5.1.1`.
+    pub status: String,
+    /// The server's reply, e.g. `550 5.1.1 User unknown`.
+    pub diagnostic: Option<String>,
+}
+
+impl FailedRecipient {
+    pub fn new(recipient: Address, status: impl Into<String>) -> Self {
+        Self {
+            recipient,
+            status: status.into(),
+            diagnostic: None,
+        }
+    }
+
+    pub fn diagnostic(mut self, diagnostic: impl Into<String>) -> Self {
+        self.diagnostic = Some(diagnostic.into());
+        self
+    }
+
+    /// The status and the diagnostic from the SMTP error, `default_status` when the reply has
+    /// no enhanced status code.
+    pub fn from_error(recipient: Address, error: &Error, default_status: &str) -> Self {
+        match error {
+            Error::Permanent(response) | Error::Transient(response) => {
+                let message = response.message.join(" ");
+                let status = enhanced_status_code(&message).unwrap_or(default_status);
+                Self::new(recipient, status)
+                    .diagnostic(format!("smtp; {} {}", response.code, message))
+            }
+            err => Self::new(recipient, default_status).diagnostic(format!("X-Local; {}", err)),
+        }
+    }
+}
+
+/// A failure report, the envelope has the null reverse-path so it is never bounced back.
+// ref https://tools.ietf.org/html/rfc3464
+// ref https://tools.ietf.org/html/rfc5321#section-4.5.5
+pub struct Dsn<'a> {
+    reporting_mta: &'a str,
+    from: Address,
+    to: Address,
+    arrival_date: Option<SystemTime>,
+    recipients: Vec<FailedRecipient>,
+    original: &'a [u8],
+}
+
+impl<'a> Dsn<'a> {
+    /// `to` is the original sender, `original` the undelivered message, only its headers are
+    /// returned.
+    pub fn new(reporting_mta: &'a str, from: Address, to: Address, original: &'a [u8]) -> Self {
+        Self {
+            reporting_mta,
+            from,
+            to,
+            arrival_date: None,
+            recipients: vec![],
+            original,
+        }
+    }
+
+    pub fn arrival_date(mut self, arrival_date: SystemTime) -> Self {
+        self.arrival_date = Some(arrival_date);
+        self
+    }
+
+    pub fn recipient(mut self, recipient: FailedRecipient) -> Self {
+        self.recipients.push(recipient);
+        self
+    }
+
+    pub fn envelope(&self) -> result::Result<Envelope, EnvelopeError> {
+        Envelope::new(None, vec![self.to.clone()])
+    }
+
+    pub fn formatted(&self, message_id: &str) -> Vec<u8> {
+        let boundary = format!("dsn-{}", message_id.replace(['@', '.'], "-"));
+        let mut out = String::new();
+
+        out.push_str(&format!("From: Mail Delivery System <{}>\r\n", self.from));
+        out.push_str(&format!("To: <{}>\r\n", self.to));
+        out.push_str("Subject: Undelivered Mail Returned to Sender\r\n");
+        out.push_str(&format!("Date: {}\r\n", EmailDate::from(SystemTime::now())));
+        out.push_str(&format!("Message-ID: <{}>\r\n", message_id));
+        // ref https://tools.ietf.org/html/rfc3834#section-5
+        out.push_str("Auto-Submitted: auto-replied\r\n");
+        out.push_str("MIME-Version: 1.0\r\n");
+        out.push_str(&format!(
+            "Content-Type: multipart/report; report-type=delivery-status; boundary=\"{}\"\r\n",
+            boundary
+        ));
+        out.push_str("\r\n");
+
+        // Human readable
+        out.push_str(&format!("--{}\r\n", boundary));
+        out.push_str("Content-Type: text/plain; charset=utf-8\r\n\r\n");
+        out.push_str(&format!(
+            "This is the mail system at {}.\r\n\r\nYour message could not be delivered to the following recipients:\r\n\r\n",
+            self.reporting_mta
+        ));
+        for recipient in &self.recipients {
+            out.push_str(&format!("<{}>", recipient.recipient));
+            if let Some(diagnostic) = &recipient.diagnostic {
+                out.push_str(&format!(": {}", diagnostic));
+            }
+            out.push_str("\r\n");
+        }
+        out.push_str("\r\n");
+
+        // ref https://tools.ietf.org/html/rfc3464#section-2.2
+        out.push_str(&format!("--{}\r\n", boundary));
+        out.push_str("Content-Type: message/delivery-status\r\n\r\n");
+        out.push_str(&format!("Reporting-MTA: dns; {}\r\n", self.reporting_mta));
+        if let Some(arrival_date) = self.arrival_date {
+            out.push_str(&format!(
+                "Arrival-Date: {}\r\n",
+                EmailDate::from(arrival_date)
+            ));
+        }
+        // ref https://tools.ietf.org/html/rfc3464#section-2.3
+        for recipient in &self.recipients {
+            out.push_str("\r\n");
+            out.push_str(&format!(
+                "Final-Recipient: rfc822; {}\r\n",
+                recipient.recipient
+            ));
+            out.push_str("Action: failed\r\n");
+            out.push_str(&format!("Status: {}\r\n", recipient.status));
+            if let Some(diagnostic) = &recipient.diagnostic {
+                out.push_str(&format!("Diagnostic-Code: {}\r\n", single_line(diagnostic)));
+            }
+        }
+        out.push_str("\r\n");
+
+        // ref https://tools.ietf.org/html/rfc6522#section-3
+        out.push_str(&format!("--{}\r\n", boundary));
+        out.push_str("Content-Type: text/rfc822-headers\r\n\r\n");
+        out.push_str(&String::from_utf8_lossy(headers(self.original)));
+        out.push_str("\r\n");
+        out.push_str(&format!("--{}--\r\n", boundary));
+
+        out.into_bytes()
+    }
+}
+
+fn headers(message: &[u8]) -> &[u8] {
+    let end = message
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .map(|i| i + 2)
+        .or_else(|| {
+            message
+                .windows(2)
+                .position(|window| window == b"\n\n")
+                .map(|i| i + 1)
+        })
+        .unwrap_or(message.len());
+    &message[..end]
+}
+
+fn single_line(text: &str) -> String {
+    text.split(['\r', '\n'])
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
@@ -5,17 +5,97 @@ pub mod lettre {
     };
 
     pub use ::lettre::message::Message;
+    pub use ::lettre::{Address, Envelope};
 }
 
+#[macro_use]
+mod trace;
+
+#[cfg(feature = "arc")]
+pub mod arc;
+pub mod batch;
+pub mod capabilities;
 mod client;
 mod connection;
+pub mod connector;
+pub mod direct;
+#[cfg(feature = "dkim")]
+pub mod dkim;
+#[cfg(feature = "spool")]
+pub mod dsn;
+pub mod metrics;
+#[cfg(any(feature = "smime", feature = "openpgp"))]
+mod mime;
+pub mod mta_sts;
+#[cfg(feature = "openpgp")]
+pub mod openpgp;
+pub mod proxy;
+pub mod proxy_protocol;
+pub mod rate_limit;
+pub mod receipt;
+#[cfg(feature = "rustls_tls")]
+mod rustls_tls;
 mod session;
+#[cfg(feature = "smime")]
+pub mod smime;
+pub mod source_address;
+#[cfg(feature = "spool")]
+pub mod spool;
+mod stream;
+#[cfg(feature = "testing")]
+pub mod testing;
+#[cfg(feature = "tokio")]
+mod tokio_io;
+pub mod transcript;
+pub mod verification;
+pub mod verp;
 
+#[cfg(feature = "arc")]
+pub use arc::{ArcChainValidation, ArcSealer};
+pub use batch::{BatchOutcome, BatchSender};
+pub use capabilities::Capabilities;
 pub use client::AsyncClient;
-pub use connection::AsyncConnection;
+pub use connection::{AsyncConnection, RecipientResult, StartTlsPolicy};
+pub use connector::{ConnectOptions, TlsMode};
+pub use direct::{DirectDelivery, Resolver, StaticResolver};
+#[cfg(feature = "dkim")]
+pub use dkim::{DkimCanonicalization, DkimKey, DkimSigner};
+pub use metrics::{Metrics, MetricsPhase};
+pub use mta_sts::{MtaStsPolicy, MtaStsPolicyCache, MtaStsPolicyFetcher};
+#[cfg(feature = "openpgp")]
+pub use openpgp::{PgpEncryptor, PgpSigner};
+pub use proxy::Proxy;
+pub use proxy_protocol::{ProxyHeader, ProxyProtocolVersion};
+pub use rate_limit::{RateLimiter, RateLimits};
+pub use receipt::SendReceipt;
 pub use session::AsyncSession;
+#[cfg(feature = "smime")]
+pub use smime::{SmimeEncryptor, SmimeSigner};
+pub use source_address::{SourceAddressPool, SourceAddressStrategy};
+#[cfg(feature = "spool")]
+pub use spool::{FileSpool, RetrySchedule, SpoolEntry, SpoolOutcome, SpoolStorage, SpoolWorker};
+pub use stream::NoTls;
+pub use transcript::{Transcript, TranscriptDirection, TranscriptEntry, TranscriptHook};
+pub use verification::{PeerCertificateVerifier, PeerCertificates, PinnedPublicKeys, TlsaRecord};
+pub use verp::Verp;
+
+#[cfg(feature = "dane")]
+pub use verification::Dane;
+
+#[cfg(feature = "smol")]
+pub use connector::SmolRuntime;
+#[cfg(feature = "tokio")]
+pub use connector::TokioRuntime;
 
 #[cfg(feature = "async_native_tls")]
 pub use connection::AsyncNativeTlsClientTlsUpgrader;
 #[cfg(feature = "async_tls")]
 pub use connection::AsyncTlsClientTlsUpgrader;
+#[cfg(feature = "rustls_tls")]
+pub use rustls_tls::{RustlsClientTlsUpgrader, RustlsClientTlsUpgraderBuilder};
+#[cfg(feature = "tokio_native_tls")]
+pub use tokio_io::TokioNativeTlsClientTlsUpgrader;
+#[cfg(feature = "tokio_rustls")]
+pub use tokio_io::TokioRustlsClientTlsUpgrader;
+#[cfg(feature = "tokio")]
+pub use tokio_io::TokioStream;
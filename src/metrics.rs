@@ -0,0 +1,52 @@
+use std::time::Duration;
+
+use lettre::transport::smtp::response::Code;
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum MetricsPhase {
+    /// From the greeting to the last EHLO, including the TLS upgrades.
+    Handshake,
+    Auth,
+    /// From the DATA command to the final response(s).
+    Data,
+}
+
+impl MetricsPhase {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Handshake => "handshake",
+            Self::Auth => "auth",
+            Self::Data => "data",
+        }
+    }
+}
+
+/// Fed by `AsyncConnection`, e.g. to export to Prometheus. All the methods default to doing
+/// nothing.
+pub trait Metrics: Send + Sync {
+    fn latency(&self, phase: MetricsPhase, duration: Duration, success: bool) {
+        let _ = (phase, duration, success);
+    }
+
+    /// `command` is the verb, `CONNECT` for the greeting and `.` for the end of the DATA,
+    /// `code / 100` is the class.
+    fn reply(&self, command: &str, code: u16) {
+        let _ = (command, code);
+    }
+
+    fn bytes_written(&self, bytes: usize) {
+        let _ = bytes;
+    }
+
+    fn bytes_read(&self, bytes: usize) {
+        let _ = bytes;
+    }
+
+    fn tls_upgrade(&self, success: bool) {
+        let _ = success;
+    }
+}
+
+pub(crate) fn reply_code(code: &Code) -> u16 {
+    code.severity as u16 * 100 + code.category as u16 * 10 + code.detail as u16
+}
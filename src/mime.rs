@@ -0,0 +1,393 @@
+use std::io;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use sha2::{Digest, Sha256};
+
+/// A formatted message split into the headers which stay on the outside, e.g. `From` and
+/// `Subject`, and the MIME entity which gets signed or encrypted, its `Content-*` headers
+/// and the body. The line endings are CRLF.
+pub(crate) struct Entity {
+    pub(crate) headers: Vec<u8>,
+    pub(crate) entity: Vec<u8>,
+}
+
+impl Entity {
+    pub(crate) fn split(message: &[u8]) -> Self {
+        let (headers, content_headers, body) = split_headers(message);
+
+        let mut entity = content_headers;
+        entity.extend_from_slice(b"\r\n");
+        entity.extend_from_slice(&body);
+
+        Self { headers, entity }
+    }
+
+    /// Like `split`, with a 7bit entity without trailing whitespace, so the signature survives
+    /// the relays. An 8bit body is encoded, quoted-printable for text, base64 otherwise, but
+    /// not in a multipart, whose parts must be encoded first.
+    // ref https://tools.ietf.org/html/rfc8551#section-3.1.2
+    // ref https://tools.ietf.org/html/rfc3156#section-5
+    pub(crate) fn split_seven_bit(message: &[u8]) -> io::Result<Self> {
+        let (headers, content_headers, body) = split_headers(message);
+        if !content_headers.is_ascii() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "8bit content header",
+            ));
+        }
+
+        let mut entity = vec![];
+        for line in content_headers.split_inclusive(|byte| *byte == b'\n') {
+            let line = trim_end(line);
+            if !line.is_empty() {
+                entity.extend_from_slice(line);
+                entity.extend_from_slice(b"\r\n");
+            }
+        }
+
+        if is_seven_bit(&body) {
+            entity.extend_from_slice(b"\r\n");
+            entity.extend_from_slice(&body);
+            return Ok(Self { headers, entity });
+        }
+
+        let media_type = header_value(&entity, b"Content-Type:")
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        if media_type.starts_with("multipart/") || media_type.starts_with("message/") {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "8bit multipart entity, encode its parts",
+            ));
+        }
+        let encoding = header_value(&entity, b"Content-Transfer-Encoding:")
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        if encoding == "base64" || encoding == "quoted-printable" {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid encoded body",
+            ));
+        }
+
+        let mut entity = without_header(&entity, b"Content-Transfer-Encoding:");
+        if media_type.starts_with("text/") {
+            entity.extend_from_slice(b"Content-Transfer-Encoding: quoted-printable\r\n\r\n");
+            entity.extend_from_slice(quoted_printable(&body).as_bytes());
+        } else {
+            entity.extend_from_slice(b"Content-Transfer-Encoding: base64\r\n\r\n");
+            entity.extend_from_slice(base64_lines(&body).as_bytes());
+        }
+
+        Ok(Self { headers, entity })
+    }
+
+    /// The outer headers, then `content_headers` and `body` which end with a CRLF.
+    pub(crate) fn wrap(&self, content_headers: &str, body: &[u8]) -> Vec<u8> {
+        let mut message = self.headers.clone();
+        message.extend_from_slice(b"MIME-Version: 1.0\r\n");
+        message.extend_from_slice(content_headers.as_bytes());
+        message.extend_from_slice(b"\r\n");
+        message.extend_from_slice(body);
+        message
+    }
+
+    /// From the entity and the time, so it is unique per message.
+    pub(crate) fn boundary(&self, prefix: &str) -> String {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        let mut hasher = Sha256::new();
+        hasher.update(nanos.to_be_bytes());
+        hasher.update(&self.entity);
+        let hash = hasher.finalize();
+        let hex: String = hash[..12].iter().map(|b| format!("{:02x}", b)).collect();
+        format!("{}-{}", prefix, hex)
+    }
+}
+
+// The outer headers, the content headers, with the default Content-Type, and the body
+fn split_headers(message: &[u8]) -> (Vec<u8>, Vec<u8>, Vec<u8>) {
+    let message = crlf(message);
+    let (header_block, body) = match message.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(i) => (&message[..i + 2], &message[i + 4..]),
+        None => (&message[..], &[][..]),
+    };
+
+    let mut headers = vec![];
+    let mut content_headers = vec![];
+    let mut target: Option<&mut Vec<u8>> = None;
+    for line in header_block.split_inclusive(|byte| *byte == b'\n') {
+        let continuation = line
+            .first()
+            .is_some_and(|byte| *byte == b' ' || *byte == b'\t');
+        if !continuation {
+            target = if starts_with_ignore_case(line, b"MIME-Version:") {
+                None
+            } else if starts_with_ignore_case(line, b"Content-") {
+                Some(&mut content_headers)
+            } else {
+                Some(&mut headers)
+            };
+        }
+        if let Some(target) = target.as_mut() {
+            target.extend_from_slice(line);
+        }
+    }
+
+    // ref https://tools.ietf.org/html/rfc2045#section-5.2
+    if !content_headers
+        .split(|byte| *byte == b'\n')
+        .any(|line| starts_with_ignore_case(line, b"Content-Type:"))
+    {
+        content_headers.extend_from_slice(b"Content-Type: text/plain; charset=us-ascii\r\n");
+    }
+
+    (headers, content_headers, body.to_vec())
+}
+
+// Bare LF to CRLF, the canonical form of a MIME entity
+// ref https://tools.ietf.org/html/rfc5751#section-3.1.1
+fn crlf(message: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(message.len());
+    for (i, byte) in message.iter().enumerate() {
+        if *byte == b'\n' && (i == 0 || message[i - 1] != b'\r') {
+            out.push(b'\r');
+        }
+        out.push(*byte);
+    }
+    out
+}
+
+fn starts_with_ignore_case(line: &[u8], prefix: &[u8]) -> bool {
+    line.len() >= prefix.len() && line[..prefix.len()].eq_ignore_ascii_case(prefix)
+}
+
+// The unfolded value of the first `name` header
+fn header_value(headers: &[u8], name: &[u8]) -> Option<String> {
+    let mut value: Option<Vec<u8>> = None;
+    for line in headers.split_inclusive(|byte| *byte == b'\n') {
+        let continuation = line
+            .first()
+            .is_some_and(|byte| *byte == b' ' || *byte == b'\t');
+        match value.as_mut() {
+            Some(value) if continuation => value.extend_from_slice(trim_end(line)),
+            Some(_) => break,
+            None if starts_with_ignore_case(line, name) => {
+                value = Some(trim_end(&line[name.len()..]).to_vec())
+            }
+            None => {}
+        }
+    }
+    value.map(|value| String::from_utf8_lossy(&value).trim().to_owned())
+}
+
+fn without_header(headers: &[u8], name: &[u8]) -> Vec<u8> {
+    let mut out = vec![];
+    let mut skip = false;
+    for line in headers.split_inclusive(|byte| *byte == b'\n') {
+        let continuation = line
+            .first()
+            .is_some_and(|byte| *byte == b' ' || *byte == b'\t');
+        if !continuation {
+            skip = starts_with_ignore_case(line, name);
+        }
+        if !skip {
+            out.extend_from_slice(line);
+        }
+    }
+    out
+}
+
+// Without the line ending and the trailing whitespace
+fn trim_end(line: &[u8]) -> &[u8] {
+    let end = line
+        .iter()
+        .rposition(|byte| !matches!(byte, b' ' | b'\t' | b'\r' | b'\n'))
+        .map_or(0, |i| i + 1);
+    &line[..end]
+}
+
+// ref https://tools.ietf.org/html/rfc5322#section-2.1.1
+fn is_seven_bit(body: &[u8]) -> bool {
+    body.split(|byte| *byte == b'\n').all(|line| {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        line.len() <= 998
+            && line
+                .iter()
+                .all(|byte| matches!(byte, 1..=0x7f) && *byte != b'\r')
+            && !line.ends_with(b" ")
+            && !line.ends_with(b"\t")
+    })
+}
+
+// 76 characters per line at most, the trailing whitespace encoded, the line breaks kept
+// ref https://tools.ietf.org/html/rfc2045#section-6.7
+fn quoted_printable(body: &[u8]) -> String {
+    let mut out = String::with_capacity(body.len() * 2);
+    let mut lines = body.split(|byte| *byte == b'\n').peekable();
+    while let Some(line) = lines.next() {
+        let last = lines.peek().is_none();
+        let line = match line.strip_suffix(b"\r") {
+            Some(line) if !last => line,
+            _ => line,
+        };
+
+        let mut len = 0;
+        for (i, byte) in line.iter().enumerate() {
+            let trailing = i + 1 == line.len() && (*byte == b' ' || *byte == b'\t');
+            let encoded = if trailing || !matches!(byte, b' ' | b'\t' | 33..=60 | 62..=126) {
+                format!("={:02X}", byte)
+            } else {
+                (*byte as char).to_string()
+            };
+            // Room for the soft line break
+            if len + encoded.len() > 75 {
+                out.push_str("=\r\n");
+                len = 0;
+            }
+            len += encoded.len();
+            out.push_str(&encoded);
+        }
+        if !last {
+            out.push_str("\r\n");
+        }
+    }
+    out
+}
+
+// 76 characters per line, with the trailing CRLF
+// ref https://tools.ietf.org/html/rfc2045#section-6.8
+pub(crate) fn base64_lines(data: &[u8]) -> String {
+    let encoded = base64::encode(data);
+    let mut out = String::with_capacity(encoded.len() + encoded.len() / 76 * 2 + 2);
+    for chunk in encoded.as_bytes().chunks(76) {
+        out.push_str(std::str::from_utf8(chunk).expect("never"));
+        out.push_str("\r\n");
+    }
+    out
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// The parts of the multipart body of `message`, without the CRLF of the delimiters.
+    pub(crate) fn parts(message: &[u8]) -> Vec<Vec<u8>> {
+        let entity = Entity::split(message);
+        let content_type = header_value(&entity.entity, b"Content-Type:").unwrap();
+        let boundary = content_type
+            .split("boundary=\"")
+            .nth(1)
+            .unwrap()
+            .trim_end_matches('"');
+        let delimiter = format!("\r\n--{}", boundary).into_bytes();
+
+        let body_start = entity
+            .entity
+            .windows(4)
+            .position(|w| w == b"\r\n\r\n")
+            .unwrap()
+            + 2;
+        let mut body = &entity.entity[body_start..];
+        let mut parts = vec![];
+        while let Some(i) = body.windows(delimiter.len()).position(|w| w == delimiter) {
+            parts.push(body[..i].to_vec());
+            body = &body[i + delimiter.len()..];
+        }
+        assert!(body.starts_with(b"--"));
+        // Without the preamble, and the CRLF ending the delimiter lines
+        parts
+            .into_iter()
+            .skip(1)
+            .map(|part| part[2..].to_vec())
+            .collect()
+    }
+
+    fn seven_bit(message: &[u8]) -> String {
+        String::from_utf8(Entity::split_seven_bit(message).unwrap().entity).unwrap()
+    }
+
+    #[test]
+    fn splits_headers() {
+        let entity = Entity::split(
+            b"From: a@example.com\nMIME-Version: 1.0\nContent-Type: text/plain;\n\tcharset=utf-8\nSubject: Hi\n\nHello\n",
+        );
+        assert_eq!(entity.headers, b"From: a@example.com\r\nSubject: Hi\r\n");
+        assert_eq!(
+            entity.entity,
+            b"Content-Type: text/plain;\r\n\tcharset=utf-8\r\n\r\nHello\r\n"
+        );
+
+        let entity = Entity::split(b"Subject: Hi\r\n\r\nHello\r\n");
+        assert_eq!(
+            entity.entity,
+            b"Content-Type: text/plain; charset=us-ascii\r\n\r\nHello\r\n"
+        );
+    }
+
+    #[test]
+    fn keeps_seven_bit_entity() {
+        assert_eq!(
+            seven_bit(b"Subject: Hi\r\nContent-Type: text/plain \r\n\r\nHello\r\n"),
+            "Content-Type: text/plain\r\n\r\nHello\r\n"
+        );
+    }
+
+    #[test]
+    fn encodes_text_as_quoted_printable() {
+        assert_eq!(
+            seven_bit(
+                "Subject: Hi\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Transfer-Encoding: 8bit\r\n\r\nGrüße \r\na=b\t\r\n"
+                    .as_bytes()
+            ),
+            "Content-Type: text/plain; charset=utf-8\r\nContent-Transfer-Encoding: quoted-printable\r\n\r\nGr=C3=BC=C3=9Fe=20\r\na=3Db=09\r\n"
+        );
+
+        // Trailing whitespace only
+        assert_eq!(
+            seven_bit(b"Subject: Hi\r\n\r\nHello \r\n"),
+            "Content-Type: text/plain; charset=us-ascii\r\nContent-Transfer-Encoding: quoted-printable\r\n\r\nHello=20\r\n"
+        );
+    }
+
+    #[test]
+    fn breaks_long_quoted_printable_lines() {
+        let line = "é".repeat(100);
+        let encoded = quoted_printable(format!("{}\r\n", line).as_bytes());
+        assert!(encoded.split("\r\n").all(|line| line.len() <= 76));
+        assert_eq!(
+            encoded.replace("=\r\n", ""),
+            format!("{}\r\n", "=C3=A9".repeat(100))
+        );
+        // No encoded octet split by a soft line break
+        assert!(encoded
+            .split("=\r\n")
+            .all(|line| line.len() % 3 == 0 || line.ends_with("\r\n")));
+    }
+
+    #[test]
+    fn encodes_binary_as_base64() {
+        assert_eq!(
+            seven_bit(
+                b"Subject: Hi\r\nContent-Type: application/octet-stream\r\nContent-Transfer-Encoding: binary\r\n\r\n\x00\xff\r\n"
+            ),
+            "Content-Type: application/octet-stream\r\nContent-Transfer-Encoding: base64\r\n\r\nAP8NCg==\r\n"
+        );
+    }
+
+    #[test]
+    fn rejects_eight_bit_multipart() {
+        for message in &[
+            "Subject: Hi\r\nContent-Type: multipart/mixed; boundary=\"b\"\r\n\r\n--b\r\n\r\nGrüße\r\n--b--\r\n",
+            "Subject: Hi\r\nContent-Type: text/plain\r\nContent-Transfer-Encoding: base64\r\n\r\nGrüße\r\n",
+            "Subject: Hi\r\nContent-Description: Grüße\r\n\r\nHello\r\n",
+        ] {
+            let err = Entity::split_seven_bit(message.as_bytes())
+                .err()
+                .unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        }
+    }
+}
@@ -0,0 +1,374 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::result;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use lettre::transport::smtp::error::Error;
+
+use crate::verification::der;
+
+// ref https://tools.ietf.org/html/rfc8461#section-3.2
+const MAX_AGE_MAX: u64 = 31_557_600;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MtaStsMode {
+    Enforce,
+    Testing,
+    None,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct MtaStsPolicy {
+    pub mode: MtaStsMode,
+    pub mx: Vec<String>,
+    pub max_age: Duration,
+}
+
+impl MtaStsPolicy {
+    // ref https://tools.ietf.org/html/rfc8461#section-4.1
+    pub fn matches_mx(&self, host: &str) -> bool {
+        self.mx.iter().any(|pattern| matches_name(pattern, host))
+    }
+}
+
+// The wildcard matches exactly one label, the same in the policy and in the certificate
+// ref https://tools.ietf.org/html/rfc6125#section-6.4.3
+fn matches_name(pattern: &str, host: &str) -> bool {
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+    let pattern = pattern.trim_end_matches('.').to_ascii_lowercase();
+    match pattern.strip_prefix("*.") {
+        Some(suffix) => match host.split_once('.') {
+            Some((label, rest)) => !label.is_empty() && rest == suffix,
+            None => false,
+        },
+        None => host == pattern,
+    }
+}
+
+// ref https://tools.ietf.org/html/rfc8461#section-3.2
+impl FromStr for MtaStsPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        let mut version = None;
+        let mut mode = None;
+        let mut max_age = None;
+        let mut mx = vec![];
+
+        for line in s.lines() {
+            let line = line.trim_end_matches('\r');
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let (key, value) = line
+                .split_once(':')
+                .ok_or(Error::Client("Invalid MTA-STS policy line"))?;
+            let value = value.trim();
+
+            // Unknown fields are ignored, duplicate fields keep the first value except mx
+            match key.trim() {
+                "version" if version.is_none() => version = Some(value.to_owned()),
+                "mode" if mode.is_none() => {
+                    mode = Some(match value {
+                        "enforce" => MtaStsMode::Enforce,
+                        "testing" => MtaStsMode::Testing,
+                        "none" => MtaStsMode::None,
+                        _ => return Err(Error::Client("Invalid MTA-STS policy mode")),
+                    })
+                }
+                "max_age" if max_age.is_none() => {
+                    let seconds: u64 = value
+                        .parse()
+                        .map_err(|_| Error::Client("Invalid MTA-STS policy max_age"))?;
+                    max_age = Some(Duration::from_secs(seconds.min(MAX_AGE_MAX)));
+                }
+                "mx" => mx.push(value.to_owned()),
+                _ => {}
+            }
+        }
+
+        if version.as_deref() != Some("STSv1") {
+            return Err(Error::Client("Invalid MTA-STS policy version"));
+        }
+        let mode = mode.ok_or(Error::Client("Missing MTA-STS policy mode"))?;
+        let max_age = max_age.ok_or(Error::Client("Missing MTA-STS policy max_age"))?;
+        if mode != MtaStsMode::None && mx.is_empty() {
+            return Err(Error::Client("Missing MTA-STS policy mx"));
+        }
+
+        Ok(Self { mode, mx, max_age })
+    }
+}
+
+//
+//
+//
+
+/// Fetches `https://mta-sts.{domain}/.well-known/mta-sts.txt`, `Ok(None)` when the domain has
+/// no policy.
+#[async_trait]
+pub trait MtaStsPolicyFetcher: Send + Sync {
+    async fn fetch(&self, domain: &str) -> io::Result<Option<String>>;
+}
+
+#[derive(Default)]
+pub struct MtaStsPolicyCache {
+    entries: Mutex<HashMap<String, (MtaStsPolicy, Instant)>>,
+}
+
+impl MtaStsPolicyCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, domain: &str) -> Option<MtaStsPolicy> {
+        let entries = self.entries.lock().expect("never");
+        entries
+            .get(&domain.to_ascii_lowercase())
+            .filter(|(policy, fetched_at)| fetched_at.elapsed() < policy.max_age)
+            .map(|(policy, _)| policy.clone())
+    }
+
+    pub fn insert(&self, domain: &str, policy: MtaStsPolicy) {
+        let mut entries = self.entries.lock().expect("never");
+        entries.insert(domain.to_ascii_lowercase(), (policy, Instant::now()));
+    }
+
+    pub fn remove(&self, domain: &str) {
+        let mut entries = self.entries.lock().expect("never");
+        entries.remove(&domain.to_ascii_lowercase());
+    }
+
+    // The cached policy is used until max_age expires
+    // ref https://tools.ietf.org/html/rfc8461#section-5.1
+    pub async fn get_or_fetch<F>(
+        &self,
+        domain: &str,
+        fetcher: &F,
+    ) -> result::Result<Option<MtaStsPolicy>, Error>
+    where
+        F: MtaStsPolicyFetcher + ?Sized,
+    {
+        if let Some(policy) = self.get(domain) {
+            return Ok(Some(policy));
+        }
+
+        match fetcher.fetch(domain).await? {
+            Some(text) => {
+                let policy: MtaStsPolicy = text.parse()?;
+                self.insert(domain, policy.clone());
+                Ok(Some(policy))
+            }
+            None => {
+                self.remove(domain);
+                Ok(None)
+            }
+        }
+    }
+}
+
+//
+//
+//
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MtaStsFailure {
+    MxMismatch,
+    StarttlsNotSupported,
+    /// The TLS negotiation failed, the connection can not continue. In testing mode the
+    /// handshake fails with `MtaStsTlsFailed`.
+    TlsFailed,
+    /// No subjectAltName of the certificate matches the MX host.
+    CertificateMismatch,
+}
+
+impl MtaStsFailure {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Self::MxMismatch => "MX host does not match the MTA-STS policy",
+            Self::StarttlsNotSupported => "STARTTLS is required by the MTA-STS policy",
+            Self::TlsFailed => "TLS negotiation failed with an MTA-STS policy",
+            Self::CertificateMismatch => "The certificate is not valid for the MTA-STS MX host",
+        }
+    }
+}
+
+impl fmt::Display for MtaStsFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// The source of the `Error::Io` returned by the handshake when the TLS negotiation failed under
+/// a testing policy. The stream is gone, but testing mode must not block the delivery: the
+/// caller reconnects with `StartTlsPolicy::Disabled`.
+/// ref https://tools.ietf.org/html/rfc8461#section-5
+#[derive(Debug)]
+pub struct MtaStsTlsFailed(pub(crate) io::Error);
+
+impl MtaStsTlsFailed {
+    pub fn is(err: &Error) -> bool {
+        match err {
+            Error::Io(err) => err.get_ref().is_some_and(|inner| inner.is::<Self>()),
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for MtaStsTlsFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", MtaStsFailure::TlsFailed, self.0)
+    }
+}
+
+impl std::error::Error for MtaStsTlsFailed {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.0)
+    }
+}
+
+pub(crate) struct MtaStsEnforcement {
+    pub(crate) policy: MtaStsPolicy,
+    pub(crate) mx_host: String,
+    pub(crate) failures: Vec<MtaStsFailure>,
+}
+
+impl MtaStsEnforcement {
+    // The chain itself is verified by the upgrader
+    // ref https://tools.ietf.org/html/rfc8461#section-4.2
+    pub(crate) fn certificate_matches(&self, leaf: &[u8]) -> bool {
+        der::dns_names(leaf)
+            .unwrap_or_default()
+            .iter()
+            .any(|name| matches_name(name, &self.mx_host))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(s: &str) -> MtaStsPolicy {
+        s.parse().unwrap()
+    }
+
+    fn client_error(s: &str) -> String {
+        match s.parse::<MtaStsPolicy>() {
+            Err(Error::Client(err)) => err.to_owned(),
+            result => panic!("{:?}", result),
+        }
+    }
+
+    fn enforcement(mx_host: &str) -> MtaStsEnforcement {
+        MtaStsEnforcement {
+            policy: policy("version: STSv1\nmode: enforce\nmx: mx.example.com\nmax_age: 60\n"),
+            mx_host: mx_host.to_owned(),
+            failures: vec![],
+        }
+    }
+
+    #[test]
+    fn parses_policy() {
+        let policy = policy(
+            "version: STSv1\r\nmode: enforce\r\nmx: mx1.example.com\r\nmx: *.example.net\r\nmax_age: 604800\r\n",
+        );
+        assert_eq!(policy.mode, MtaStsMode::Enforce);
+        assert_eq!(policy.mx, vec!["mx1.example.com", "*.example.net"]);
+        assert_eq!(policy.max_age, Duration::from_secs(604_800));
+    }
+
+    #[test]
+    fn parses_policy_leniently() {
+        // Unknown fields are ignored, the first value of a duplicate field is kept
+        let policy = policy(
+            "version: STSv1\nmode: testing\nfoo: bar\nmode: enforce\nmx:mx.example.com\n\nmax_age: 99999999999\n",
+        );
+        assert_eq!(policy.mode, MtaStsMode::Testing);
+        assert_eq!(policy.mx, vec!["mx.example.com"]);
+        assert_eq!(policy.max_age, Duration::from_secs(MAX_AGE_MAX));
+
+        // No mx is needed to remove a policy
+        let policy = self::policy("version: STSv1\nmode: none\nmax_age: 0\n");
+        assert_eq!(policy.mode, MtaStsMode::None);
+        assert!(policy.mx.is_empty());
+    }
+
+    #[test]
+    fn rejects_invalid_policy() {
+        assert_eq!(
+            client_error("version: STSv2\nmode: enforce\nmx: mx.example.com\nmax_age: 60"),
+            "Invalid MTA-STS policy version"
+        );
+        assert_eq!(
+            client_error("mode: enforce\nmx: mx.example.com\nmax_age: 60"),
+            "Invalid MTA-STS policy version"
+        );
+        assert_eq!(
+            client_error("version: STSv1\nmode: strict\nmx: mx.example.com\nmax_age: 60"),
+            "Invalid MTA-STS policy mode"
+        );
+        assert_eq!(
+            client_error("version: STSv1\nmode: enforce\nmx: mx.example.com\nmax_age: -1"),
+            "Invalid MTA-STS policy max_age"
+        );
+        assert_eq!(
+            client_error("version: STSv1\nmode: enforce\nmax_age: 60"),
+            "Missing MTA-STS policy mx"
+        );
+        assert_eq!(
+            client_error("version: STSv1\nmx: mx.example.com\nmax_age: 60"),
+            "Missing MTA-STS policy mode"
+        );
+        assert_eq!(
+            client_error("version: STSv1\nmode: enforce\nmx: mx.example.com"),
+            "Missing MTA-STS policy max_age"
+        );
+        assert_eq!(
+            client_error("version: STSv1\nmode enforce"),
+            "Invalid MTA-STS policy line"
+        );
+    }
+
+    #[test]
+    fn matches_mx() {
+        let policy = policy(
+            "version: STSv1\nmode: enforce\nmx: mx.example.com\nmx: *.example.net\nmax_age: 60",
+        );
+
+        assert!(policy.matches_mx("mx.example.com"));
+        assert!(policy.matches_mx("MX.Example.COM."));
+        assert!(!policy.matches_mx("mx2.example.com"));
+        assert!(!policy.matches_mx("example.com"));
+
+        // The wildcard matches exactly one label
+        assert!(policy.matches_mx("mx1.example.net"));
+        assert!(policy.matches_mx("MX1.EXAMPLE.NET."));
+        assert!(!policy.matches_mx("example.net"));
+        assert!(!policy.matches_mx(".example.net"));
+        assert!(!policy.matches_mx("a.mx1.example.net"));
+        assert!(!policy.matches_mx("mx1.example.net.org"));
+    }
+
+    #[test]
+    fn matches_certificate() {
+        // subjectAltName DNS:mx.example.com, DNS:*.mx.example.net
+        let leaf = include_bytes!("../tests/keys/mx_certificate.der");
+
+        assert!(enforcement("mx.example.com").certificate_matches(leaf));
+        assert!(enforcement("MX.EXAMPLE.COM.").certificate_matches(leaf));
+        assert!(enforcement("a.mx.example.net").certificate_matches(leaf));
+        assert!(!enforcement("mx.example.net").certificate_matches(leaf));
+        assert!(!enforcement("a.b.mx.example.net").certificate_matches(leaf));
+        assert!(!enforcement("mx2.example.com").certificate_matches(leaf));
+
+        // The common name is not used
+        let leaf = include_bytes!("../tests/keys/certificate.der");
+        assert!(!enforcement("mx.example.com").certificate_matches(leaf));
+        assert!(!enforcement("mx.example.com").certificate_matches(b"not a certificate"));
+    }
+}
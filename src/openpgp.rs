@@ -0,0 +1,440 @@
+use std::io;
+use std::time::{Duration, SystemTime};
+
+use lettre::Message;
+use pgp::composed::{
+    ArmorOptions, Deserializable, DetachedSignature, MessageBuilder, SignedPublicKey,
+    SignedPublicSubKey, SignedSecretKey,
+};
+use pgp::crypto::hash::HashAlgorithm;
+use pgp::crypto::sym::SymmetricKeyAlgorithm;
+use pgp::packet::SignatureType;
+use pgp::types::{KeyDetails, Password};
+
+use crate::mime::Entity;
+
+/// Signs the content of a formatted message as PGP/MIME `multipart/signed`, the headers like
+/// `From` and `Subject` stay outside of the signature.
+// ref https://tools.ietf.org/html/rfc3156#section-5
+pub struct PgpSigner {
+    key: SignedSecretKey,
+    password: String,
+}
+
+impl PgpSigner {
+    pub fn new(key: SignedSecretKey) -> Self {
+        Self {
+            key,
+            password: String::new(),
+        }
+    }
+
+    /// An ASCII armored secret key, e.g. from `gpg --export-secret-keys --armor`.
+    pub fn from_armored(key: &str) -> io::Result<Self> {
+        let (key, _) = SignedSecretKey::from_string(key).map_err(pgp_error)?;
+        Ok(Self::new(key))
+    }
+
+    /// Unlocks the secret key.
+    pub fn password(mut self, password: impl Into<String>) -> Self {
+        self.password = password.into();
+        self
+    }
+
+    /// Signs `message.formatted()`.
+    pub fn sign_message(&self, message: &Message) -> io::Result<Vec<u8>> {
+        self.sign(&message.formatted())
+    }
+
+    /// An 8bit body is encoded first, quoted-printable or base64, an 8bit multipart is an
+    /// error.
+    pub fn sign(&self, message: &[u8]) -> io::Result<Vec<u8>> {
+        let entity = Entity::split_seven_bit(message)?;
+
+        let signature = DetachedSignature::sign_binary_data(
+            rand::thread_rng(),
+            &*self.key,
+            &Password::from(self.password.as_str()),
+            HashAlgorithm::Sha256,
+            &entity.entity[..],
+        )
+        .and_then(|signature| signature.to_armored_string(ArmorOptions::default()))
+        .map_err(pgp_error)?;
+
+        let boundary = entity.boundary("pgp");
+        let content_type = format!(
+            "Content-Type: multipart/signed; protocol=\"application/pgp-signature\"; micalg=pgp-sha256;\r\n\tboundary=\"{}\"\r\n",
+            boundary
+        );
+        // The CRLF before a delimiter belongs to it, not to the signed entity
+        // ref https://tools.ietf.org/html/rfc2046#section-5.1.1
+        let mut body = format!(
+            "This is an OpenPGP/MIME signed message (RFC 4880 and 3156)\r\n\r\n--{}\r\n",
+            boundary
+        )
+        .into_bytes();
+        body.extend_from_slice(&entity.entity);
+        body.extend_from_slice(
+            format!(
+                "\r\n--{boundary}\r\nContent-Type: application/pgp-signature; name=\"signature.asc\"\r\nContent-Description: OpenPGP digital signature\r\nContent-Disposition: attachment; filename=\"signature.asc\"\r\n\r\n{signature}\r\n--{boundary}--\r\n",
+                boundary = boundary,
+                signature = armored_lines(&signature),
+            )
+            .as_bytes(),
+        );
+
+        Ok(entity.wrap(&content_type, &body))
+    }
+}
+
+/// Encrypts the content of a formatted message as PGP/MIME `multipart/encrypted`, for every
+/// recipient's key. The headers like `From` and `Subject` stay in the clear.
+// ref https://tools.ietf.org/html/rfc3156#section-4
+#[derive(Default)]
+pub struct PgpEncryptor {
+    recipients: Vec<SignedPublicKey>,
+}
+
+impl PgpEncryptor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Every key in the ASCII armored `keys` is a recipient.
+    pub fn from_armored(keys: &str) -> io::Result<Self> {
+        let (keys, _) = SignedPublicKey::from_string_many(keys).map_err(pgp_error)?;
+        keys.map(|key| key.map_err(pgp_error))
+            .try_fold(Self::new(), |encryptor, key| Ok(encryptor.recipient(key?)))
+    }
+
+    pub fn recipient(mut self, key: SignedPublicKey) -> Self {
+        self.recipients.push(key);
+        self
+    }
+
+    /// Encrypts `message.formatted()`.
+    pub fn encrypt_message(&self, message: &Message) -> io::Result<Vec<u8>> {
+        self.encrypt(&message.formatted())
+    }
+
+    /// `message` may be signed first with `PgpSigner::sign`. Fails when a recipient's
+    /// encryption subkeys are all expired or revoked.
+    pub fn encrypt(&self, message: &[u8]) -> io::Result<Vec<u8>> {
+        if self.recipients.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "no recipient key",
+            ));
+        }
+        let entity = Entity::split(message);
+
+        let mut rng = rand::thread_rng();
+        let mut builder = MessageBuilder::from_bytes("", entity.entity.clone())
+            .seipd_v1(&mut rng, SymmetricKeyAlgorithm::AES256);
+        let now = SystemTime::now();
+        for key in &self.recipients {
+            // The first valid subkey for encryption, else the primary key when there is none
+            let subkeys: Vec<_> = key
+                .public_subkeys
+                .iter()
+                .filter_map(|subkey| encryption_subkey(subkey, now).map(|valid| (subkey, valid)))
+                .collect();
+            match subkeys.iter().find(|(_, valid)| *valid) {
+                Some((subkey, _)) => builder.encrypt_to_key(&mut rng, *subkey),
+                None if subkeys.is_empty() => builder.encrypt_to_key(&mut rng, key),
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "expired or revoked encryption subkey",
+                    ))
+                }
+            }
+            .map_err(pgp_error)?;
+        }
+        let encrypted = builder
+            .to_armored_string(&mut rng, ArmorOptions::default())
+            .map_err(pgp_error)?;
+
+        let boundary = entity.boundary("pgp");
+        let content_type = format!(
+            "Content-Type: multipart/encrypted; protocol=\"application/pgp-encrypted\";\r\n\tboundary=\"{}\"\r\n",
+            boundary
+        );
+        let body = format!(
+            "This is an OpenPGP/MIME encrypted message (RFC 4880 and 3156)\r\n\r\n--{boundary}\r\nContent-Type: application/pgp-encrypted\r\nContent-Description: PGP/MIME version identification\r\n\r\nVersion: 1\r\n\r\n--{boundary}\r\nContent-Type: application/octet-stream; name=\"encrypted.asc\"\r\nContent-Description: OpenPGP encrypted message\r\nContent-Disposition: inline; filename=\"encrypted.asc\"\r\n\r\n{encrypted}\r\n--{boundary}--\r\n",
+            boundary = boundary,
+            encrypted = armored_lines(&encrypted),
+        );
+
+        Ok(entity.wrap(&content_type, body.as_bytes()))
+    }
+}
+
+// None when not for encryption, else whether it is neither expired nor revoked. The latest
+// binding signature has the flags and the expiration time.
+// ref https://tools.ietf.org/html/rfc4880#section-5.2.3.6
+fn encryption_subkey(subkey: &SignedPublicSubKey, now: SystemTime) -> Option<bool> {
+    let binding = subkey
+        .signatures
+        .iter()
+        .filter(|signature| signature.typ() == Some(SignatureType::SubkeyBinding))
+        .max_by_key(|signature| signature.created())?;
+    let flags = binding.key_flags();
+    if !flags.encrypt_comms() && !flags.encrypt_storage() {
+        return None;
+    }
+
+    let revoked = subkey
+        .signatures
+        .iter()
+        .any(|signature| signature.typ() == Some(SignatureType::SubkeyRevocation));
+    // Zero is no expiration
+    let expired = binding
+        .key_expiration_time()
+        .map(|expiration| Duration::from_secs(expiration.as_secs().into()))
+        .filter(|expiration| !expiration.is_zero())
+        .is_some_and(|expiration| SystemTime::from(subkey.key.created_at()) + expiration <= now);
+
+    Some(!revoked && !expired)
+}
+
+// The armor has LF line endings
+fn armored_lines(armored: &str) -> String {
+    armored
+        .lines()
+        .map(|line| format!("{}\r\n", line))
+        .collect()
+}
+
+fn pgp_error(err: pgp::errors::Error) -> io::Error {
+    io::Error::other(err)
+}
+
+#[cfg(test)]
+mod tests {
+    use lettre::message::{header, SinglePart};
+    use pgp::composed::{
+        EncryptionCaps, KeyType, Message as PgpMessage, SecretKeyParamsBuilder, SubkeyParamsBuilder,
+    };
+    use pgp::crypto::ecc_curve::ECCCurve;
+    use pgp::packet::{KeyFlags, SignatureConfig, Subpacket, SubpacketData};
+    use pgp::types::{Duration as PgpDuration, Timestamp};
+
+    use super::*;
+    use crate::mime::tests::parts;
+
+    const DAY: u32 = 24 * 60 * 60;
+
+    // Generated so the tests need no files, one encryption subkey per creation time
+    fn secret_key(subkeys_created_at: &[Timestamp]) -> SignedSecretKey {
+        let subkeys = subkeys_created_at
+            .iter()
+            .map(|created_at| {
+                SubkeyParamsBuilder::default()
+                    .key_type(KeyType::ECDH(ECCCurve::Curve25519Legacy))
+                    .can_encrypt(EncryptionCaps::All)
+                    .created_at(*created_at)
+                    .build()
+                    .unwrap()
+            })
+            .collect();
+        SecretKeyParamsBuilder::default()
+            .key_type(KeyType::Ed25519Legacy)
+            .can_certify(true)
+            .can_sign(true)
+            .primary_user_id("Recipient <recipient@example.com>".into())
+            .subkeys(subkeys)
+            .build()
+            .unwrap()
+            .generate(rand::thread_rng())
+            .unwrap()
+    }
+
+    // Replaces the signatures of a subkey with one of type `typ`
+    fn sign_subkey(
+        key: &mut SignedSecretKey,
+        subkey: usize,
+        typ: SignatureType,
+        subpackets: Vec<SubpacketData>,
+    ) {
+        let mut config =
+            SignatureConfig::v4(typ, key.primary_key.algorithm(), HashAlgorithm::Sha256);
+        config.hashed_subpackets = vec![
+            Subpacket::regular(SubpacketData::SignatureCreationTime(Timestamp::now())).unwrap(),
+            Subpacket::regular(SubpacketData::IssuerFingerprint(
+                key.primary_key.fingerprint(),
+            ))
+            .unwrap(),
+        ];
+        for subpacket in subpackets {
+            config
+                .hashed_subpackets
+                .push(Subpacket::regular(subpacket).unwrap());
+        }
+        let signature = config
+            .sign_subkey_binding(
+                &key.primary_key,
+                key.primary_key.public_key(),
+                &Password::empty(),
+                key.secret_subkeys[subkey].key.public_key(),
+            )
+            .unwrap();
+        match typ {
+            SignatureType::SubkeyRevocation => {
+                key.secret_subkeys[subkey].signatures.push(signature)
+            }
+            _ => key.secret_subkeys[subkey].signatures = vec![signature],
+        }
+    }
+
+    fn expire(key: &mut SignedSecretKey, subkey: usize) {
+        let mut flags = KeyFlags::default();
+        flags.set_encrypt_comms(true);
+        flags.set_encrypt_storage(true);
+        sign_subkey(
+            key,
+            subkey,
+            SignatureType::SubkeyBinding,
+            vec![
+                SubpacketData::KeyFlags(flags),
+                SubpacketData::KeyExpirationTime(PgpDuration::from_secs(DAY)),
+            ],
+        );
+    }
+
+    fn days_ago(days: u32) -> Timestamp {
+        Timestamp::from_secs(Timestamp::now().as_secs() - days * DAY)
+    }
+
+    fn message() -> Message {
+        Message::builder()
+            .from("sender@example.com".parse().unwrap())
+            .to("recipient@example.com".parse().unwrap())
+            .subject("Hi")
+            .singlepart(
+                SinglePart::eight_bit()
+                    .header(header::ContentType(
+                        "text/plain; charset=utf-8".parse().unwrap(),
+                    ))
+                    .body("Grüße \r\n"),
+            )
+            .unwrap()
+    }
+
+    // The body of a part, the armor with LF line endings
+    fn armor(part: &[u8]) -> String {
+        let i = part.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        String::from_utf8(part[i + 4..].to_vec())
+            .unwrap()
+            .replace("\r\n", "\n")
+    }
+
+    fn decrypt(encrypted: &[u8], key: &SignedSecretKey) -> pgp::errors::Result<Vec<u8>> {
+        let parts = parts(encrypted);
+        assert_eq!(parts.len(), 2);
+        assert_eq!(
+            parts[0],
+            b"Content-Type: application/pgp-encrypted\r\nContent-Description: PGP/MIME version identification\r\n\r\nVersion: 1\r\n"
+        );
+        let armor = armor(&parts[1]);
+        let (message, _) = PgpMessage::from_string(&armor)?;
+        let mut message = message.decrypt(&Password::empty(), key)?;
+        if message.is_compressed() {
+            message = message.decompress()?;
+        }
+        Ok(message.as_data_vec()?)
+    }
+
+    #[test]
+    fn signs_seven_bit_entity() {
+        let key = secret_key(&[Timestamp::now()]);
+        let signed = PgpSigner::new(key.clone())
+            .sign_message(&message())
+            .unwrap();
+
+        let text = String::from_utf8(signed.clone()).unwrap();
+        assert!(text.starts_with("From: sender@example.com\r\n"));
+        assert!(text.contains("Content-Type: multipart/signed; protocol=\"application/pgp-signature\"; micalg=pgp-sha256;"));
+        assert!(signed.is_ascii());
+        assert!(!text.contains(" \r\n"));
+
+        let parts = parts(&signed);
+        assert_eq!(parts.len(), 2);
+        assert_eq!(
+            parts[0],
+            b"Content-Type: text/plain; charset=utf-8\r\nContent-Transfer-Encoding: quoted-printable\r\n\r\nGr=C3=BC=C3=9Fe=20\r\n\r\n"
+        );
+        let (signature, _) = DetachedSignature::from_string(&armor(&parts[1])).unwrap();
+        let public = key.to_public_key();
+        signature.verify(&public.primary_key, &parts[0]).unwrap();
+        assert!(signature
+            .verify(
+                &public.primary_key,
+                b"Content-Type: text/plain\r\n\r\nOther\r\n"
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn encrypts_for_every_recipient() {
+        let first = secret_key(&[Timestamp::now()]);
+        let second = secret_key(&[Timestamp::now()]);
+        let message = message();
+        let encrypted = PgpEncryptor::new()
+            .recipient(first.to_public_key())
+            .recipient(second.to_public_key())
+            .encrypt_message(&message)
+            .unwrap();
+
+        let entity = Entity::split(&encrypted);
+        assert_eq!(entity.headers, Entity::split(&message.formatted()).headers);
+        let expected = Entity::split(&message.formatted()).entity;
+        assert_eq!(decrypt(&encrypted, &first).unwrap(), expected);
+        assert_eq!(decrypt(&encrypted, &second).unwrap(), expected);
+        assert!(decrypt(&encrypted, &secret_key(&[Timestamp::now()])).is_err());
+    }
+
+    #[test]
+    fn skips_expired_subkeys() {
+        let mut key = secret_key(&[days_ago(2), Timestamp::now()]);
+        expire(&mut key, 0);
+        let encrypted = PgpEncryptor::new()
+            .recipient(key.to_public_key())
+            .encrypt_message(&message())
+            .unwrap();
+
+        let mut valid = key.clone();
+        valid.secret_subkeys.remove(0);
+        assert!(decrypt(&encrypted, &valid).is_ok());
+        let mut expired = key;
+        expired.secret_subkeys.remove(1);
+        assert!(decrypt(&encrypted, &expired).is_err());
+    }
+
+    #[test]
+    fn keeps_subkeys_not_expired_yet() {
+        let mut key = secret_key(&[Timestamp::now()]);
+        expire(&mut key, 0);
+        let encrypted = PgpEncryptor::new()
+            .recipient(key.to_public_key())
+            .encrypt_message(&message())
+            .unwrap();
+        assert!(decrypt(&encrypted, &key).is_ok());
+    }
+
+    #[test]
+    fn rejects_expired_or_revoked_subkeys() {
+        let mut expired = secret_key(&[days_ago(2)]);
+        expire(&mut expired, 0);
+        let mut revoked = secret_key(&[Timestamp::now()]);
+        sign_subkey(&mut revoked, 0, SignatureType::SubkeyRevocation, vec![]);
+
+        for key in &[expired, revoked] {
+            let err = PgpEncryptor::new()
+                .recipient(key.to_public_key())
+                .encrypt_message(&message())
+                .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        }
+    }
+}
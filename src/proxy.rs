@@ -0,0 +1,500 @@
+use std::io;
+use std::net::{IpAddr, Ipv6Addr};
+
+use futures_util::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+// ref https://tools.ietf.org/html/rfc1928#section-6
+const SOCKS5_REPLY_MESSAGES: [&str; 9] = [
+    "succeeded",
+    "general SOCKS server failure",
+    "connection not allowed by ruleset",
+    "network unreachable",
+    "host unreachable",
+    "connection refused",
+    "TTL expired",
+    "command not supported",
+    "address type not supported",
+];
+
+const HTTP_RESPONSE_HEAD_MAX: usize = 8192;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ProxyKind {
+    Socks5,
+    HttpConnect,
+}
+
+/// Tunnels the SMTP connection, the target host is resolved by the proxy. The TLS upgrader still
+/// has to be for the target host.
+#[derive(Clone, Debug)]
+pub struct Proxy {
+    kind: ProxyKind,
+    pub(crate) host: String,
+    pub(crate) port: u16,
+    credentials: Option<(String, String)>,
+}
+
+impl Proxy {
+    pub fn socks5(host: impl Into<String>, port: u16) -> Self {
+        Self::new(ProxyKind::Socks5, host.into(), port)
+    }
+
+    pub fn http_connect(host: impl Into<String>, port: u16) -> Self {
+        Self::new(ProxyKind::HttpConnect, host.into(), port)
+    }
+
+    fn new(kind: ProxyKind, host: String, port: u16) -> Self {
+        Self {
+            kind,
+            host,
+            port,
+            credentials: None,
+        }
+    }
+
+    /// SOCKS5 username/password or HTTP basic auth.
+    pub fn credentials(mut self, username: impl Into<String>, password: impl Into<String>) -> Self {
+        self.credentials = Some((username.into(), password.into()));
+        self
+    }
+
+    pub fn kind(&self) -> ProxyKind {
+        self.kind
+    }
+
+    /// Asks the proxy, already connected through `stream`, for a tunnel to `host:port`.
+    pub async fn tunnel<S>(&self, stream: &mut S, host: &str, port: u16) -> io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        match self.kind {
+            ProxyKind::Socks5 => self.socks5_tunnel(stream, host, port).await,
+            ProxyKind::HttpConnect => self.http_connect_tunnel(stream, host, port).await,
+        }
+    }
+
+    // ref https://tools.ietf.org/html/rfc1928
+    async fn socks5_tunnel<S>(&self, stream: &mut S, host: &str, port: u16) -> io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        // Method selection
+        let methods: &[u8] = match self.credentials {
+            Some(_) => &[0x00, 0x02],
+            None => &[0x00],
+        };
+        let mut buf = vec![0x05, methods.len() as u8];
+        buf.extend_from_slice(methods);
+        stream.write_all(&buf).await?;
+        stream.flush().await?;
+
+        let mut reply = [0; 2];
+        stream.read_exact(&mut reply).await?;
+        if reply[0] != 0x05 {
+            return Err(proxy_error("invalid SOCKS version"));
+        }
+        match (reply[1], &self.credentials) {
+            (0x00, _) => {}
+            // ref https://tools.ietf.org/html/rfc1929
+            (0x02, Some((username, password))) => {
+                if username.len() > 255 || password.len() > 255 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "SOCKS5 username or password too long",
+                    ));
+                }
+                let mut buf = vec![0x01, username.len() as u8];
+                buf.extend_from_slice(username.as_bytes());
+                buf.push(password.len() as u8);
+                buf.extend_from_slice(password.as_bytes());
+                stream.write_all(&buf).await?;
+                stream.flush().await?;
+
+                let mut reply = [0; 2];
+                stream.read_exact(&mut reply).await?;
+                if reply[0] != 0x01 {
+                    return Err(proxy_error("invalid SOCKS5 authentication version"));
+                }
+                if reply[1] != 0x00 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::PermissionDenied,
+                        "SOCKS5 authentication failed",
+                    ));
+                }
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "no acceptable SOCKS5 authentication method",
+                ))
+            }
+        }
+
+        // Connect
+        let mut buf = vec![0x05, 0x01, 0x00];
+        match host
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse::<IpAddr>()
+        {
+            Ok(IpAddr::V4(ip)) => {
+                buf.push(0x01);
+                buf.extend_from_slice(&ip.octets());
+            }
+            Ok(IpAddr::V6(ip)) => {
+                buf.push(0x04);
+                buf.extend_from_slice(&ip.octets());
+            }
+            Err(_) => {
+                if host.len() > 255 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "SOCKS5 host name too long",
+                    ));
+                }
+                buf.push(0x03);
+                buf.push(host.len() as u8);
+                buf.extend_from_slice(host.as_bytes());
+            }
+        }
+        buf.extend_from_slice(&port.to_be_bytes());
+        stream.write_all(&buf).await?;
+        stream.flush().await?;
+
+        let mut reply = [0; 4];
+        stream.read_exact(&mut reply).await?;
+        if reply[0] != 0x05 {
+            return Err(proxy_error("invalid SOCKS version"));
+        }
+        if reply[1] != 0x00 {
+            let message = SOCKS5_REPLY_MESSAGES
+                .get(reply[1] as usize)
+                .unwrap_or(&"unknown error");
+            return Err(proxy_error(&format!("SOCKS5 connect failed, {}", message)));
+        }
+
+        // Bound address, not used
+        let len = match reply[3] {
+            0x01 => 4,
+            0x04 => 16,
+            0x03 => {
+                let mut len = [0; 1];
+                stream.read_exact(&mut len).await?;
+                len[0] as usize
+            }
+            _ => return Err(proxy_error("invalid SOCKS5 address type")),
+        };
+        let mut bound = vec![0; len + 2];
+        stream.read_exact(&mut bound).await?;
+
+        Ok(())
+    }
+
+    // ref https://tools.ietf.org/html/rfc7231#section-4.3.6
+    async fn http_connect_tunnel<S>(&self, stream: &mut S, host: &str, port: u16) -> io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let authority = if host.parse::<Ipv6Addr>().is_ok() {
+            format!("[{}]:{}", host, port)
+        } else {
+            format!("{}:{}", host, port)
+        };
+
+        let mut request = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n", authority, authority);
+        if let Some((username, password)) = &self.credentials {
+            request.push_str(&format!(
+                "Proxy-Authorization: Basic {}\r\n",
+                base64::encode(format!("{}:{}", username, password))
+            ));
+        }
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes()).await?;
+        stream.flush().await?;
+
+        // Byte by byte, the SMTP greeting may follow right after the response head
+        let mut head = vec![];
+        let mut byte = [0; 1];
+        while !head.ends_with(b"\r\n\r\n") {
+            if head.len() >= HTTP_RESPONSE_HEAD_MAX {
+                return Err(proxy_error("HTTP CONNECT response too large"));
+            }
+            stream.read_exact(&mut byte).await?;
+            head.push(byte[0]);
+        }
+
+        let status_line = head
+            .split(|b| *b == b'\n')
+            .next()
+            .map(|line| String::from_utf8_lossy(line).trim_end().to_owned())
+            .unwrap_or_default();
+        let mut parts = status_line.splitn(3, ' ');
+        match (parts.next(), parts.next()) {
+            (Some(version), Some(code)) if version.starts_with("HTTP/1.") => {
+                if code.starts_with('2') {
+                    Ok(())
+                } else if code == "407" {
+                    Err(io::Error::new(
+                        io::ErrorKind::PermissionDenied,
+                        format!("HTTP CONNECT failed, {}", status_line),
+                    ))
+                } else {
+                    Err(proxy_error(&format!(
+                        "HTTP CONNECT failed, {}",
+                        status_line
+                    )))
+                }
+            }
+            _ => Err(proxy_error("invalid HTTP CONNECT response")),
+        }
+    }
+}
+
+fn proxy_error(message: &str) -> io::Error {
+    io::Error::other(message.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    use futures_util::io::Cursor;
+
+    use super::*;
+
+    // Replies with `input`, keeps what was written and flushed
+    struct ProxyStream {
+        input: Cursor<Vec<u8>>,
+        written: Vec<u8>,
+        flushed: usize,
+    }
+
+    impl ProxyStream {
+        fn new(input: &[&[u8]]) -> Self {
+            Self {
+                input: Cursor::new(input.concat()),
+                written: vec![],
+                flushed: 0,
+            }
+        }
+
+        fn unread(&self) -> &[u8] {
+            &self.input.get_ref()[self.input.position() as usize..]
+        }
+    }
+
+    impl AsyncRead for ProxyStream {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            Pin::new(&mut self.input).poll_read(cx, buf)
+        }
+    }
+
+    impl AsyncWrite for ProxyStream {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            self.written.extend_from_slice(buf);
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            self.flushed = self.written.len();
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    const GREETING: &[u8] = b"220 mx.example.com ESMTP\r\n";
+
+    fn tunnel(proxy: &Proxy, stream: &mut ProxyStream, host: &str) -> io::Result<()> {
+        async_io::block_on(proxy.tunnel(stream, host, 25))
+    }
+
+    #[test]
+    fn socks5_domain() {
+        let proxy = Proxy::socks5("proxy.example.com", 1080);
+        let mut stream = ProxyStream::new(&[
+            &[0x05, 0x00],
+            &[0x05, 0x00, 0x00, 0x01, 192, 0, 2, 1, 0x04, 0x38],
+            GREETING,
+        ]);
+
+        tunnel(&proxy, &mut stream, "mx.example.com").unwrap();
+
+        let mut expected = vec![0x05, 0x01, 0x00, 0x05, 0x01, 0x00, 0x03, 14];
+        expected.extend_from_slice(b"mx.example.com");
+        expected.extend_from_slice(&[0x00, 0x19]);
+        assert_eq!(stream.written, expected);
+        assert_eq!(stream.flushed, stream.written.len());
+        assert_eq!(stream.unread(), GREETING);
+    }
+
+    #[test]
+    fn socks5_ip_addresses() {
+        let proxy = Proxy::socks5("proxy.example.com", 1080);
+        let mut stream = ProxyStream::new(&[
+            &[0x05, 0x00],
+            &[0x05, 0x00, 0x00, 0x03, 8],
+            b"proxy.lo",
+            &[0x04, 0x38],
+            GREETING,
+        ]);
+        tunnel(&proxy, &mut stream, "192.0.2.1").unwrap();
+        assert_eq!(
+            &stream.written[3..],
+            &[0x05, 0x01, 0x00, 0x01, 192, 0, 2, 1, 0x00, 0x19]
+        );
+        assert_eq!(stream.unread(), GREETING);
+
+        let mut stream = ProxyStream::new(&[
+            &[0x05, 0x00],
+            &[0x05, 0x00, 0x00, 0x04],
+            &[0; 16],
+            &[0x04, 0x38],
+            GREETING,
+        ]);
+        tunnel(&proxy, &mut stream, "[2001:db8::1]").unwrap();
+        let mut expected = vec![0x05, 0x01, 0x00, 0x04, 0x20, 0x01, 0x0D, 0xB8];
+        expected.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0x00, 0x19]);
+        assert_eq!(&stream.written[3..], &expected[..]);
+        assert_eq!(stream.unread(), GREETING);
+    }
+
+    #[test]
+    fn socks5_username_password() {
+        let proxy = Proxy::socks5("proxy.example.com", 1080).credentials("user", "secret");
+        let mut stream = ProxyStream::new(&[
+            &[0x05, 0x02],
+            &[0x01, 0x00],
+            &[0x05, 0x00, 0x00, 0x01, 192, 0, 2, 1, 0x04, 0x38],
+        ]);
+
+        tunnel(&proxy, &mut stream, "192.0.2.1").unwrap();
+
+        let mut expected = vec![0x05, 0x02, 0x00, 0x02, 0x01, 4];
+        expected.extend_from_slice(b"user");
+        expected.push(6);
+        expected.extend_from_slice(b"secret");
+        assert_eq!(&stream.written[..expected.len()], &expected[..]);
+        assert_eq!(stream.flushed, stream.written.len());
+
+        // No authentication is still accepted
+        let mut stream = ProxyStream::new(&[
+            &[0x05, 0x00],
+            &[0x05, 0x00, 0x00, 0x01, 192, 0, 2, 1, 0x04, 0x38],
+        ]);
+        tunnel(&proxy, &mut stream, "192.0.2.1").unwrap();
+        assert_eq!(&stream.written[..4], &[0x05, 0x02, 0x00, 0x02]);
+        assert_eq!(stream.written[4], 0x05);
+    }
+
+    #[test]
+    fn socks5_rejected_authentication() {
+        let proxy = Proxy::socks5("proxy.example.com", 1080).credentials("user", "secret");
+
+        let err = tunnel(
+            &proxy,
+            &mut ProxyStream::new(&[&[0x05, 0x02], &[0x01, 0x01]]),
+            "mx",
+        )
+        .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+
+        // A SOCKS version instead of the RFC 1929 one
+        let err = tunnel(
+            &proxy,
+            &mut ProxyStream::new(&[&[0x05, 0x02], &[0x05, 0x00]]),
+            "mx",
+        )
+        .unwrap_err();
+        assert_eq!(err.to_string(), "invalid SOCKS5 authentication version");
+
+        let err = tunnel(&proxy, &mut ProxyStream::new(&[&[0x05, 0xFF]]), "mx").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+
+        // Username/password is only offered with credentials
+        let proxy = Proxy::socks5("proxy.example.com", 1080);
+        let err = tunnel(&proxy, &mut ProxyStream::new(&[&[0x05, 0x02]]), "mx").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+
+        let err = tunnel(&proxy, &mut ProxyStream::new(&[&[0x04, 0x00]]), "mx").unwrap_err();
+        assert_eq!(err.to_string(), "invalid SOCKS version");
+    }
+
+    #[test]
+    fn socks5_reply_errors() {
+        let proxy = Proxy::socks5("proxy.example.com", 1080);
+        for code in 1..=9_u8 {
+            let mut stream =
+                ProxyStream::new(&[&[0x05, 0x00], &[0x05, code, 0x00, 0x01, 0, 0, 0, 0, 0, 0]]);
+            let err = tunnel(&proxy, &mut stream, "mx.example.com").unwrap_err();
+            let message = SOCKS5_REPLY_MESSAGES
+                .get(code as usize)
+                .unwrap_or(&"unknown error");
+            assert_eq!(
+                err.to_string(),
+                format!("SOCKS5 connect failed, {}", message)
+            );
+        }
+
+        let mut stream = ProxyStream::new(&[&[0x05, 0x00], &[0x05, 0x00, 0x00, 0x05]]);
+        let err = tunnel(&proxy, &mut stream, "mx.example.com").unwrap_err();
+        assert_eq!(err.to_string(), "invalid SOCKS5 address type");
+    }
+
+    #[test]
+    fn http_connect() {
+        let proxy = Proxy::http_connect("proxy.example.com", 8080).credentials("user", "secret");
+        let mut stream = ProxyStream::new(&[
+            b"HTTP/1.1 200 Connection established\r\nVia: proxy\r\n\r\n",
+            GREETING,
+        ]);
+
+        tunnel(&proxy, &mut stream, "[2001:db8::1]").unwrap();
+
+        assert_eq!(
+            String::from_utf8(stream.written.clone()).unwrap(),
+            "CONNECT [2001:db8::1]:25 HTTP/1.1\r\nHost: [2001:db8::1]:25\r\n\
+             Proxy-Authorization: Basic dXNlcjpzZWNyZXQ=\r\n\r\n"
+        );
+        assert_eq!(stream.flushed, stream.written.len());
+        assert_eq!(stream.unread(), GREETING);
+    }
+
+    #[test]
+    fn http_connect_failures() {
+        let proxy = Proxy::http_connect("proxy.example.com", 8080);
+
+        let mut stream = ProxyStream::new(&[b"HTTP/1.1 502 Bad Gateway\r\n\r\n"]);
+        let err = tunnel(&proxy, &mut stream, "mx.example.com").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Other);
+        assert_eq!(
+            err.to_string(),
+            "HTTP CONNECT failed, HTTP/1.1 502 Bad Gateway"
+        );
+
+        let mut stream = ProxyStream::new(&[b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n"]);
+        let err = tunnel(&proxy, &mut stream, "mx.example.com").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+
+        let mut stream = ProxyStream::new(&[b"SSH-2.0-OpenSSH\r\n\r\n"]);
+        let err = tunnel(&proxy, &mut stream, "mx.example.com").unwrap_err();
+        assert_eq!(err.to_string(), "invalid HTTP CONNECT response");
+
+        let header = vec![b'a'; HTTP_RESPONSE_HEAD_MAX];
+        let mut stream = ProxyStream::new(&[b"HTTP/1.1 200 OK\r\nX: ", &header, b"\r\n\r\n"]);
+        let err = tunnel(&proxy, &mut stream, "mx.example.com").unwrap_err();
+        assert_eq!(err.to_string(), "HTTP CONNECT response too large");
+    }
+}
@@ -0,0 +1,257 @@
+use std::convert::TryFrom;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+
+// ref https://www.haproxy.org/download/2.0/doc/proxy-protocol.txt
+const V2_SIGNATURE: [u8; 12] = [
+    0x0D, 0x0A, 0x0D, 0x0A, 0x00, 0x0D, 0x0A, 0x51, 0x55, 0x49, 0x54, 0x0A,
+];
+
+// ref https://www.haproxy.org/download/2.0/doc/proxy-protocol.txt section 2.2.1
+pub const PP2_TYPE_ALPN: u8 = 0x01;
+pub const PP2_TYPE_AUTHORITY: u8 = 0x02;
+pub const PP2_TYPE_CRC32C: u8 = 0x03;
+pub const PP2_TYPE_NOOP: u8 = 0x04;
+pub const PP2_TYPE_UNIQUE_ID: u8 = 0x05;
+pub const PP2_TYPE_SSL: u8 = 0x20;
+pub const PP2_TYPE_NETNS: u8 = 0x30;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ProxyProtocolVersion {
+    V1,
+    V2,
+}
+
+/// Written by `handshake` before anything else, even before implicit TLS.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ProxyHeader {
+    version: ProxyProtocolVersion,
+    addresses: Option<(SocketAddr, SocketAddr)>,
+    tlvs: Vec<(u8, Vec<u8>)>,
+}
+
+impl ProxyHeader {
+    pub fn new(version: ProxyProtocolVersion, source: SocketAddr, destination: SocketAddr) -> Self {
+        Self {
+            version,
+            addresses: Some((source, destination)),
+            tlvs: vec![],
+        }
+    }
+
+    /// No addresses, `PROXY UNKNOWN` in v1 and the LOCAL command in v2.
+    pub fn local(version: ProxyProtocolVersion) -> Self {
+        Self {
+            version,
+            addresses: None,
+            tlvs: vec![],
+        }
+    }
+
+    /// v2 only, ignored by v1.
+    pub fn tlv(mut self, kind: u8, value: impl Into<Vec<u8>>) -> Self {
+        self.tlvs.push((kind, value.into()));
+        self
+    }
+
+    pub fn version(&self) -> ProxyProtocolVersion {
+        self.version
+    }
+
+    /// Fails in v2 when a TLV or the whole header is over 65535 bytes.
+    pub fn to_bytes(&self) -> io::Result<Vec<u8>> {
+        match self.version {
+            ProxyProtocolVersion::V1 => Ok(self.to_v1_bytes()),
+            ProxyProtocolVersion::V2 => self.to_v2_bytes(),
+        }
+    }
+
+    // ref https://www.haproxy.org/download/2.0/doc/proxy-protocol.txt section 2.1
+    fn to_v1_bytes(&self) -> Vec<u8> {
+        let line = match self.addresses.map(same_family) {
+            Some((source, destination)) => format!(
+                "PROXY {} {} {} {} {}\r\n",
+                match source.ip() {
+                    IpAddr::V4(_) => "TCP4",
+                    IpAddr::V6(_) => "TCP6",
+                },
+                source.ip(),
+                destination.ip(),
+                source.port(),
+                destination.port()
+            ),
+            None => "PROXY UNKNOWN\r\n".to_owned(),
+        };
+        line.into_bytes()
+    }
+
+    // ref https://www.haproxy.org/download/2.0/doc/proxy-protocol.txt section 2.2
+    fn to_v2_bytes(&self) -> io::Result<Vec<u8>> {
+        let mut body = vec![];
+        let (command, family) = match self.addresses.map(same_family) {
+            Some((source, destination)) => {
+                let family = match (source.ip(), destination.ip()) {
+                    (IpAddr::V4(source_ip), IpAddr::V4(destination_ip)) => {
+                        body.extend_from_slice(&source_ip.octets());
+                        body.extend_from_slice(&destination_ip.octets());
+                        0x11
+                    }
+                    (IpAddr::V6(source_ip), IpAddr::V6(destination_ip)) => {
+                        body.extend_from_slice(&source_ip.octets());
+                        body.extend_from_slice(&destination_ip.octets());
+                        0x21
+                    }
+                    _ => unreachable!(),
+                };
+                body.extend_from_slice(&source.port().to_be_bytes());
+                body.extend_from_slice(&destination.port().to_be_bytes());
+                (0x21, family)
+            }
+            None => (0x20, 0x00),
+        };
+
+        for (kind, value) in &self.tlvs {
+            body.push(*kind);
+            body.extend_from_slice(&v2_length(value.len(), "TLV value too long")?.to_be_bytes());
+            body.extend_from_slice(value);
+        }
+
+        let mut bytes = V2_SIGNATURE.to_vec();
+        bytes.push(command);
+        bytes.push(family);
+        bytes.extend_from_slice(&v2_length(body.len(), "header too long")?.to_be_bytes());
+        bytes.extend_from_slice(&body);
+        Ok(bytes)
+    }
+}
+
+fn v2_length(len: usize, message: &str) -> io::Result<u16> {
+    u16::try_from(len).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, message))
+}
+
+// Mixed families are sent as IPv6, with the IPv4 address mapped
+fn same_family((source, destination): (SocketAddr, SocketAddr)) -> (SocketAddr, SocketAddr) {
+    match (source.ip(), destination.ip()) {
+        (IpAddr::V4(_), IpAddr::V6(_)) | (IpAddr::V6(_), IpAddr::V4(_)) => {
+            (to_ipv6(source), to_ipv6(destination))
+        }
+        _ => (source, destination),
+    }
+}
+
+fn to_ipv6(addr: SocketAddr) -> SocketAddr {
+    match addr.ip() {
+        IpAddr::V4(ip) => SocketAddr::new(IpAddr::V6(ip.to_ipv6_mapped()), addr.port()),
+        IpAddr::V6(_) => addr,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(addr: &str) -> SocketAddr {
+        addr.parse().unwrap()
+    }
+
+    #[test]
+    fn v1() {
+        let header = ProxyHeader::new(
+            ProxyProtocolVersion::V1,
+            addr("192.0.2.1:56324"),
+            addr("198.51.100.2:25"),
+        );
+        assert_eq!(
+            header.to_bytes().unwrap(),
+            b"PROXY TCP4 192.0.2.1 198.51.100.2 56324 25\r\n"
+        );
+
+        let header = ProxyHeader::new(
+            ProxyProtocolVersion::V1,
+            addr("[2001:db8::1]:56324"),
+            addr("[2001:db8::2]:25"),
+        );
+        assert_eq!(
+            header.to_bytes().unwrap(),
+            b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 25\r\n"
+        );
+
+        let header = ProxyHeader::new(
+            ProxyProtocolVersion::V1,
+            addr("192.0.2.1:56324"),
+            addr("[2001:db8::2]:25"),
+        );
+        assert_eq!(
+            header.to_bytes().unwrap(),
+            b"PROXY TCP6 ::ffff:192.0.2.1 2001:db8::2 56324 25\r\n"
+        );
+
+        let header = ProxyHeader::local(ProxyProtocolVersion::V1).tlv(PP2_TYPE_NOOP, "ignored");
+        assert_eq!(header.to_bytes().unwrap(), b"PROXY UNKNOWN\r\n");
+    }
+
+    #[test]
+    fn v2() {
+        let header = ProxyHeader::new(
+            ProxyProtocolVersion::V2,
+            addr("192.0.2.1:56324"),
+            addr("198.51.100.2:25"),
+        )
+        .tlv(PP2_TYPE_AUTHORITY, "mx.example.com");
+
+        let mut expected = V2_SIGNATURE.to_vec();
+        expected.extend_from_slice(&[0x21, 0x11, 0x00, 0x1D]);
+        expected.extend_from_slice(&[192, 0, 2, 1, 198, 51, 100, 2]);
+        expected.extend_from_slice(&[0xDC, 0x04, 0x00, 0x19]);
+        expected.extend_from_slice(&[PP2_TYPE_AUTHORITY, 0x00, 0x0E]);
+        expected.extend_from_slice(b"mx.example.com");
+        assert_eq!(header.to_bytes().unwrap(), expected);
+    }
+
+    #[test]
+    fn v2_ipv6() {
+        let header = ProxyHeader::new(
+            ProxyProtocolVersion::V2,
+            addr("192.0.2.1:56324"),
+            addr("[2001:db8::2]:25"),
+        );
+
+        let mut expected = V2_SIGNATURE.to_vec();
+        expected.extend_from_slice(&[0x21, 0x21, 0x00, 0x24]);
+        expected.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xFF, 0xFF, 192, 0, 2, 1]);
+        expected.extend_from_slice(&[0x20, 0x01, 0x0D, 0xB8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2]);
+        expected.extend_from_slice(&[0xDC, 0x04, 0x00, 0x19]);
+        assert_eq!(header.to_bytes().unwrap(), expected);
+    }
+
+    #[test]
+    fn v2_local() {
+        let mut expected = V2_SIGNATURE.to_vec();
+        expected.extend_from_slice(&[0x20, 0x00, 0x00, 0x00]);
+        assert_eq!(
+            ProxyHeader::local(ProxyProtocolVersion::V2)
+                .to_bytes()
+                .unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn v2_rejects_oversized_lengths() {
+        let header = ProxyHeader::local(ProxyProtocolVersion::V2)
+            .tlv(PP2_TYPE_UNIQUE_ID, vec![0; u16::MAX as usize + 1]);
+        let err = header.to_bytes().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        // Each TLV fits, the header doesn't
+        let header = ProxyHeader::local(ProxyProtocolVersion::V2)
+            .tlv(PP2_TYPE_NOOP, vec![0; 40000])
+            .tlv(PP2_TYPE_NOOP, vec![0; 40000]);
+        let err = header.to_bytes().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        let header = ProxyHeader::local(ProxyProtocolVersion::V2)
+            .tlv(PP2_TYPE_NOOP, vec![0; u16::MAX as usize - 3]);
+        assert_eq!(header.to_bytes().unwrap().len(), 16 + u16::MAX as usize);
+    }
+}
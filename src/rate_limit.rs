@@ -0,0 +1,507 @@
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+
+use crate::connector::Runtime;
+
+const CONNECTION_SLOT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+#[derive(Default, Clone, Debug)]
+pub struct RateLimits {
+    messages_per_second: Option<f64>,
+    recipients_per_minute: Option<f64>,
+    max_messages_per_connection: Option<usize>,
+    max_connections_per_host: Option<usize>,
+}
+
+impl RateLimits {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn messages_per_second(mut self, messages: f64) -> Self {
+        self.messages_per_second = Some(messages);
+        self
+    }
+
+    pub fn recipients_per_minute(mut self, recipients: f64) -> Self {
+        self.recipients_per_minute = Some(recipients);
+        self
+    }
+
+    /// A connection then refuses to send, `BatchSender` reconnects instead.
+    pub fn max_messages_per_connection(mut self, messages: usize) -> Self {
+        self.max_messages_per_connection = Some(messages.max(1));
+        self
+    }
+
+    pub fn max_connections_per_host(mut self, connections: usize) -> Self {
+        self.max_connections_per_host = Some(connections.max(1));
+        self
+    }
+}
+
+// A reservation may take the tokens below zero, the caller then waits for the refill.
+#[derive(Debug)]
+struct TokenBucket {
+    capacity: f64,
+    tokens: f64,
+    per_second: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(per_second: f64, capacity: f64, now: Instant) -> Self {
+        let capacity = capacity.max(1.0);
+        Self {
+            capacity,
+            tokens: capacity,
+            per_second,
+            updated: now,
+        }
+    }
+
+    fn reserve(&mut self, tokens: f64, now: Instant) -> Duration {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.per_second).min(self.capacity);
+        self.updated = now;
+
+        self.tokens -= tokens;
+        if self.tokens >= 0.0 || self.per_second <= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.per_second)
+        }
+    }
+}
+
+#[derive(Debug)]
+struct HostState {
+    messages: Option<TokenBucket>,
+    recipients: Option<TokenBucket>,
+    connections: usize,
+    backoff: Duration,
+    backoff_until: Option<Instant>,
+}
+
+type Hosts = Arc<Mutex<HashMap<String, HostState>>>;
+
+/// Per host, shared by the connections through `ConnectOptions::rate_limiter`. Sending is
+/// delayed rather than failed, and slowed down further while the host replies 421 or 454.
+pub struct RateLimiter<R> {
+    limits: RateLimits,
+    hosts: Hosts,
+    runtime: PhantomData<fn() -> R>,
+}
+
+impl<R> RateLimiter<R>
+where
+    R: Runtime,
+{
+    pub fn new(limits: RateLimits) -> Self {
+        Self {
+            limits,
+            hosts: Arc::new(Mutex::new(HashMap::new())),
+            runtime: PhantomData,
+        }
+    }
+
+    pub fn limits(&self) -> &RateLimits {
+        &self.limits
+    }
+
+    /// The slot is released when the permit is dropped.
+    pub async fn acquire_connection(&self, host: &str) -> ConnectionPermit {
+        let host = host.to_ascii_lowercase();
+        loop {
+            {
+                let mut hosts = self.hosts.lock().expect("never");
+                let state = self.host_state(&mut hosts, &host);
+                if self
+                    .limits
+                    .max_connections_per_host
+                    .is_none_or(|max| state.connections < max)
+                {
+                    state.connections += 1;
+                    return ConnectionPermit {
+                        hosts: self.hosts.clone(),
+                        host,
+                    };
+                }
+            }
+            R::sleep(CONNECTION_SLOT_POLL_INTERVAL).await;
+        }
+    }
+
+    /// Waits until a message with that many recipients may be sent.
+    pub async fn acquire_message(&self, host: &str, recipients: usize) {
+        let wait = {
+            let now = Instant::now();
+            let mut hosts = self.hosts.lock().expect("never");
+            let state = self.host_state(&mut hosts, &host.to_ascii_lowercase());
+
+            let mut wait = state
+                .backoff_until
+                .map(|until| until.saturating_duration_since(now))
+                .unwrap_or_default();
+            if let Some(bucket) = &mut state.messages {
+                wait = wait.max(bucket.reserve(1.0, now));
+            }
+            if let Some(bucket) = &mut state.recipients {
+                wait = wait.max(bucket.reserve(recipients as f64, now));
+            }
+            wait
+        };
+
+        if wait > Duration::ZERO {
+            smtp_event!(host = %host, wait_ms = wait.as_millis() as u64, "rate limited");
+            R::sleep(wait).await;
+        }
+    }
+
+    /// Doubles the backoff on a throttling reply to `command`, halves it once a message is
+    /// accepted, i.e. a positive reply to the end of the DATA (`"."`).
+    pub fn record(&self, host: &str, command: &str, code: u16) {
+        let mut hosts = self.hosts.lock().expect("never");
+        let state = self.host_state(&mut hosts, &host.to_ascii_lowercase());
+
+        if is_throttling(command, code) {
+            state.backoff = (state.backoff * 2).clamp(MIN_BACKOFF, MAX_BACKOFF);
+            state.backoff_until = Some(Instant::now() + state.backoff);
+        } else if command == "." && code / 100 == 2 {
+            state.backoff /= 2;
+            if state.backoff < MIN_BACKOFF {
+                state.backoff = Duration::ZERO;
+            }
+        }
+    }
+
+    fn host_state<'a>(
+        &self,
+        hosts: &'a mut HashMap<String, HostState>,
+        host: &str,
+    ) -> &'a mut HostState {
+        let now = Instant::now();
+        hosts.entry(host.to_owned()).or_insert_with(|| HostState {
+            messages: self
+                .limits
+                .messages_per_second
+                .map(|per_second| TokenBucket::new(per_second, per_second, now)),
+            recipients: self
+                .limits
+                .recipients_per_minute
+                .map(|per_minute| TokenBucket::new(per_minute / 60.0, per_minute, now)),
+            connections: 0,
+            backoff: Duration::ZERO,
+            backoff_until: None,
+        })
+    }
+}
+
+// 421 Service not available, 454 Temporary authentication failure, both used for throttling,
+// but 454 to STARTTLS is TLS not available
+fn is_throttling(command: &str, code: u16) -> bool {
+    match code {
+        421 => true,
+        454 => !command.eq_ignore_ascii_case("STARTTLS"),
+        _ => false,
+    }
+}
+
+pub struct ConnectionPermit {
+    hosts: Hosts,
+    host: String,
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        if let Some(state) = self.hosts.lock().expect("never").get_mut(&self.host) {
+            state.connections = state.connections.saturating_sub(1);
+        }
+    }
+}
+
+// Lets the connection, which is not generic over the runtime, use the limiter
+#[async_trait]
+pub(crate) trait Throttle: Send + Sync {
+    async fn acquire_message(&self, host: &str, recipients: usize);
+
+    fn record(&self, host: &str, command: &str, code: u16);
+
+    fn max_messages_per_connection(&self) -> Option<usize>;
+}
+
+#[async_trait]
+impl<R> Throttle for RateLimiter<R>
+where
+    R: Runtime,
+{
+    async fn acquire_message(&self, host: &str, recipients: usize) {
+        RateLimiter::acquire_message(self, host, recipients).await
+    }
+
+    fn record(&self, host: &str, command: &str, code: u16) {
+        RateLimiter::record(self, host, command, code)
+    }
+
+    fn max_messages_per_connection(&self) -> Option<usize> {
+        self.limits.max_messages_per_connection
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::future::{self, Either};
+
+    use super::*;
+    use crate::connector::tests::{sleeps, TestRuntime};
+
+    fn backoff(limiter: &RateLimiter<TestRuntime>, host: &str) -> Duration {
+        limiter.hosts.lock().unwrap()[host].backoff
+    }
+
+    fn record(limiter: &RateLimiter<TestRuntime>, replies: &[(&str, u16)]) -> Duration {
+        for (command, code) in replies {
+            limiter.record("mx.example.com", command, *code);
+        }
+        backoff(limiter, "mx.example.com")
+    }
+
+    #[test]
+    fn doubles_on_throttling() {
+        let limiter = RateLimiter::new(RateLimits::new());
+        assert_eq!(record(&limiter, &[("RCPT", 421)]), Duration::from_secs(1));
+        assert_eq!(record(&limiter, &[("MAIL", 421)]), Duration::from_secs(2));
+        assert_eq!(record(&limiter, &[("AUTH", 454)]), Duration::from_secs(4));
+        assert_eq!(record(&limiter, &[("RCPT", 421); 20]), MAX_BACKOFF);
+    }
+
+    #[test]
+    fn keeps_backoff_across_the_handshake() {
+        let limiter = RateLimiter::new(RateLimits::new());
+        record(&limiter, &[("RCPT", 421), ("CONNECT", 421)]);
+        assert_eq!(
+            record(
+                &limiter,
+                &[
+                    ("CONNECT", 220),
+                    ("EHLO", 250),
+                    ("MAIL", 250),
+                    ("RCPT", 250),
+                    ("DATA", 354)
+                ]
+            ),
+            Duration::from_secs(2)
+        );
+        assert_eq!(record(&limiter, &[("RCPT", 421)]), Duration::from_secs(4));
+    }
+
+    #[test]
+    fn halves_on_accepted_message() {
+        let limiter = RateLimiter::new(RateLimits::new());
+        record(&limiter, &[("RCPT", 421); 3]);
+        assert_eq!(record(&limiter, &[(".", 250)]), Duration::from_secs(2));
+        assert_eq!(record(&limiter, &[(".", 250)]), Duration::from_secs(1));
+        assert_eq!(record(&limiter, &[(".", 250)]), Duration::ZERO);
+        assert_eq!(record(&limiter, &[(".", 550)]), Duration::ZERO);
+    }
+
+    #[test]
+    fn ignores_starttls_not_available() {
+        let limiter = RateLimiter::new(RateLimits::new());
+        assert_eq!(record(&limiter, &[("STARTTLS", 454)]), Duration::ZERO);
+        assert_eq!(record(&limiter, &[("RCPT", 451)]), Duration::ZERO);
+    }
+
+    #[test]
+    fn refills_the_bucket() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(2.0, 2.0, now);
+        assert_eq!(bucket.reserve(1.0, now), Duration::ZERO);
+        assert_eq!(bucket.reserve(1.0, now), Duration::ZERO);
+        assert_eq!(bucket.reserve(1.0, now), Duration::from_millis(500));
+        // The reservation is owed
+        assert_eq!(
+            bucket.reserve(1.0, now + Duration::from_millis(500)),
+            Duration::from_millis(500)
+        );
+        // Up to the capacity after a while
+        let later = now + Duration::from_secs(3600);
+        assert_eq!(bucket.reserve(3.0, later), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn limits_the_messages() {
+        let limiter = RateLimiter::<TestRuntime>::new(RateLimits::new().messages_per_second(20.0));
+        for _ in 0..20 {
+            async_io::block_on(limiter.acquire_message("mx.example.com", 1));
+        }
+        assert!(sleeps().is_empty());
+
+        // Other hosts have their own bucket
+        async_io::block_on(limiter.acquire_message("mx.example.net", 1));
+        assert!(sleeps().is_empty());
+
+        async_io::block_on(limiter.acquire_message("MX.example.com", 1));
+        let sleeps = sleeps();
+        assert_eq!(sleeps.len(), 1);
+        assert!(sleeps[0] > Duration::ZERO && sleeps[0] <= Duration::from_millis(50));
+    }
+
+    #[test]
+    fn limits_the_recipients() {
+        let limiter =
+            RateLimiter::<TestRuntime>::new(RateLimits::new().recipients_per_minute(6000.0));
+        async_io::block_on(limiter.acquire_message("mx.example.com", 6000));
+        assert!(sleeps().is_empty());
+
+        async_io::block_on(limiter.acquire_message("mx.example.com", 1));
+        let sleeps = sleeps();
+        assert_eq!(sleeps.len(), 1);
+        assert!(sleeps[0] > Duration::ZERO && sleeps[0] <= Duration::from_millis(10));
+    }
+
+    #[test]
+    fn limits_the_connections() {
+        let limiter =
+            RateLimiter::<TestRuntime>::new(RateLimits::new().max_connections_per_host(2));
+        let first = async_io::block_on(limiter.acquire_connection("mx.example.com"));
+        let _second = async_io::block_on(limiter.acquire_connection("MX.example.com"));
+        let _other = async_io::block_on(limiter.acquire_connection("mx.example.net"));
+
+        let third = async_io::block_on(future::select(
+            Box::pin(limiter.acquire_connection("mx.example.com")),
+            async_io::Timer::after(Duration::from_millis(200)),
+        ));
+        assert!(matches!(third, Either::Right(_)));
+        assert!(sleeps().contains(&CONNECTION_SLOT_POLL_INTERVAL));
+
+        drop(first);
+        let _third = async_io::block_on(limiter.acquire_connection("mx.example.com"));
+        assert_eq!(
+            limiter.hosts.lock().unwrap()["mx.example.com"].connections,
+            2
+        );
+    }
+
+    #[test]
+    fn releases_the_connection_on_drop() {
+        let limiter = RateLimiter::<TestRuntime>::new(RateLimits::new());
+        let permits: Vec<_> = (0..3)
+            .map(|_| async_io::block_on(limiter.acquire_connection("mx.example.com")))
+            .collect();
+        assert_eq!(
+            limiter.hosts.lock().unwrap()["mx.example.com"].connections,
+            3
+        );
+
+        drop(permits);
+        assert_eq!(
+            limiter.hosts.lock().unwrap()["mx.example.com"].connections,
+            0
+        );
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn limits_the_messages_per_connection() {
+        use std::sync::Arc;
+
+        use lettre::transport::smtp::error::Error;
+        use lettre::transport::smtp::extension::ClientId;
+        use lettre::Envelope;
+
+        use crate::testing::MockServer;
+
+        let server = MockServer::new();
+        let mut connection = server.connection();
+        let limiter = Arc::new(RateLimiter::<TestRuntime>::new(
+            RateLimits::new().max_messages_per_connection(2),
+        ));
+        connection.set_rate_limiter(limiter, "mx.example.com");
+        let envelope = Envelope::new(
+            Some("sender@example.com".parse().unwrap()),
+            vec!["a@example.com".parse().unwrap()],
+        )
+        .unwrap();
+        let email = b"Subject: Hi\r\n\r\nHello";
+
+        async_io::block_on(async {
+            connection
+                .handshake(false, ClientId::Domain("client.example.com".to_owned()))
+                .await
+                .unwrap();
+            connection.send(&envelope, email).await.unwrap();
+            connection
+                .send_with_receipt(&envelope, email)
+                .await
+                .unwrap();
+
+            assert!(connection.message_limit_reached());
+            assert!(matches!(
+                connection.send(&envelope, email).await,
+                Err(Error::Client(_))
+            ));
+            assert!(matches!(
+                connection.send_with_receipt(&envelope, email).await,
+                Err(Error::Client(_))
+            ));
+            assert!(connection.send_lmtp(&envelope, email).await.is_err());
+        });
+
+        assert_eq!(server.messages().len(), 2);
+        assert!(!connection.has_broken());
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn receipt_excludes_the_wait() {
+        use std::sync::Arc;
+
+        use lettre::transport::smtp::extension::ClientId;
+        use lettre::Envelope;
+
+        use crate::testing::MockServer;
+
+        let server = MockServer::new().delay(".", Duration::from_millis(20));
+        let mut connection = server.connection();
+        let limiter = Arc::new(RateLimiter::<TestRuntime>::new(
+            RateLimits::new().messages_per_second(5.0),
+        ));
+        connection.set_rate_limiter(limiter, "mx.example.com");
+        let envelope = Envelope::new(
+            Some("sender@example.com".parse().unwrap()),
+            vec!["a@example.com".parse().unwrap()],
+        )
+        .unwrap();
+
+        let elapsed = async_io::block_on(async {
+            connection
+                .handshake(false, ClientId::Domain("client.example.com".to_owned()))
+                .await
+                .unwrap();
+            let mut elapsed = vec![];
+            for _ in 0..6 {
+                let started = Instant::now();
+                let receipt = connection
+                    .send_with_receipt(&envelope, b"Subject: Hi\r\n\r\nHello")
+                    .await
+                    .unwrap();
+                assert!(receipt.elapsed >= Duration::from_millis(20));
+                elapsed.push((receipt.elapsed, started.elapsed()));
+            }
+            elapsed
+        });
+
+        // The last message waited for the bucket, before the MAIL command
+        let sleeps = sleeps();
+        assert_eq!(sleeps.len(), 1);
+        let (receipt, total) = elapsed[5];
+        assert!(total >= receipt + sleeps[0]);
+    }
+}
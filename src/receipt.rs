@@ -0,0 +1,201 @@
+use std::time::Duration;
+
+use lettre::transport::smtp::response::Response;
+
+use crate::connection::RecipientResult;
+
+#[derive(Debug)]
+pub struct SendReceipt {
+    /// The final response to the DATA, the last accepted one with LMTP.
+    pub response: Response,
+    pub queue_id: Option<String>,
+    /// Of the message as passed, before the dot-stuffing.
+    pub size: usize,
+    /// From the (first, with VERP) MAIL command to the final response, the DKIM signing and
+    /// the rate limit wait before it are not counted.
+    pub elapsed: Duration,
+    /// The RCPT responses, the rejected recipients too, the per recipient DATA responses
+    /// with LMTP or VERP.
+    pub recipients: Vec<RecipientResult>,
+}
+
+impl SendReceipt {
+    pub(crate) fn new(
+        response: Response,
+        size: usize,
+        elapsed: Duration,
+        recipients: Vec<RecipientResult>,
+    ) -> Self {
+        Self {
+            queue_id: parse_queue_id(&response),
+            response,
+            size,
+            elapsed,
+            recipients,
+        }
+    }
+}
+
+/// Best effort, the formats are not standardized.
+pub fn parse_queue_id(response: &Response) -> Option<String> {
+    response
+        .message
+        .iter()
+        .rev()
+        .find_map(|line| parse_queue_id_line(line))
+}
+
+fn parse_queue_id_line(line: &str) -> Option<String> {
+    let mut words: Vec<&str> = line.split_whitespace().collect();
+    if enhanced_status_code(line).is_some() {
+        words.remove(0);
+    }
+
+    let id = if let Some(i) = words
+        .windows(2)
+        .position(|pair| pair[0].eq_ignore_ascii_case("queued") && pair[1] == "as")
+    {
+        // Postfix, `Ok: queued as 4F2A1B`
+        words.get(i + 2).copied()
+    } else if let Some(word) = words.iter().find(|word| word.starts_with("[InternalId=")) {
+        // Exchange, `<id@host> [InternalId=1234, Hostname=host] Queued mail for delivery`
+        Some(&word["[InternalId=".len()..])
+    } else if let Some(word) = words.iter().find(|word| word.starts_with("id=")) {
+        // Exim, `OK id=1kT5Xy-0004Zq-3A`
+        Some(&word["id=".len()..])
+    } else if words.get(1..4) == Some(&["Message", "accepted", "for"]) {
+        // Sendmail, `2.0.0 09GAbCde012345 Message accepted for delivery`
+        Some(words[0])
+    } else if words.len() >= 3 && words[words.len() - 2..] == ["-", "gsmtp"] {
+        // Gmail, `OK  1603017600 a1si123456qkb.12 - gsmtp`
+        Some(words[words.len() - 3])
+    } else if words.len() == 2 && words[0].eq_ignore_ascii_case("ok") && is_ses_message_id(words[1])
+    {
+        // Amazon SES, `Ok 0100017551a1b2c3-1a2b3c4d-...-000000`
+        Some(words[1])
+    } else {
+        None
+    }?;
+
+    let id = id.trim_matches(|c: char| matches!(c, ',' | '.' | ';' | '[' | ']' | '(' | ')'));
+    if id.is_empty() {
+        None
+    } else {
+        Some(id.to_owned())
+    }
+}
+
+// 16, 8, 4, 4, 4 and 12 hex digits, then 6 digits, e.g.
+// `010001755f2b3a1c-8d1e6c2a-3b4f-4a5e-9c7d-1e2f3a4b5c6d-000000`
+fn is_ses_message_id(word: &str) -> bool {
+    let groups: Vec<&str> = word.split('-').collect();
+    groups.len() == 7
+        && groups
+            .iter()
+            .zip(&[16, 8, 4, 4, 4, 12, 6])
+            .all(|(group, len)| group.len() == *len && group.bytes().all(|b| b.is_ascii_hexdigit()))
+}
+
+// `5.1.1` of `5.1.1 User unknown`
+// ref https://tools.ietf.org/html/rfc3463#section-2
+pub(crate) fn enhanced_status_code(line: &str) -> Option<&str> {
+    let word = line.split_whitespace().next()?;
+    let parts: Vec<&str> = word.split('.').collect();
+    if parts.len() == 3
+        && parts
+            .iter()
+            .all(|part| !part.is_empty() && part.bytes().all(|b| b.is_ascii_digit()))
+    {
+        Some(word)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queue_id(reply: &str) -> Option<String> {
+        let response: Response = format!("{}\r\n", reply).parse().unwrap();
+        parse_queue_id(&response)
+    }
+
+    #[test]
+    fn postfix() {
+        assert_eq!(
+            queue_id("250 2.0.0 Ok: queued as 4F2A1B3C4D").as_deref(),
+            Some("4F2A1B3C4D")
+        );
+        assert_eq!(
+            queue_id("250 Ok: queued as 4bHQx52Fq7z9rwP").as_deref(),
+            Some("4bHQx52Fq7z9rwP")
+        );
+    }
+
+    #[test]
+    fn exim() {
+        assert_eq!(
+            queue_id("250 OK id=1kT5Xy-0004Zq-3A").as_deref(),
+            Some("1kT5Xy-0004Zq-3A")
+        );
+    }
+
+    #[test]
+    fn sendmail() {
+        assert_eq!(
+            queue_id("250 2.0.0 09GAbCde012345 Message accepted for delivery").as_deref(),
+            Some("09GAbCde012345")
+        );
+    }
+
+    #[test]
+    fn exchange() {
+        assert_eq!(
+            queue_id("250 2.6.0 <a1b2@mail.example.com> [InternalId=1234567890, Hostname=EX01.example.com] Queued mail for delivery").as_deref(),
+            Some("1234567890")
+        );
+    }
+
+    #[test]
+    fn gmail() {
+        assert_eq!(
+            queue_id("250 2.0.0 OK  1603017600 a1si123456qkb.12 - gsmtp").as_deref(),
+            Some("a1si123456qkb.12")
+        );
+    }
+
+    #[test]
+    fn amazon_ses() {
+        assert_eq!(
+            queue_id("250 Ok 010001755f2b3a1c-8d1e6c2a-3b4f-4a5e-9c7d-1e2f3a4b5c6d-000000")
+                .as_deref(),
+            Some("010001755f2b3a1c-8d1e6c2a-3b4f-4a5e-9c7d-1e2f3a4b5c6d-000000")
+        );
+    }
+
+    #[test]
+    fn multiline_uses_the_last_id() {
+        assert_eq!(
+            queue_id("250-mx.example.com\r\n250 2.0.0 Ok: queued as ABC123").as_deref(),
+            Some("ABC123")
+        );
+    }
+
+    #[test]
+    fn no_queue_id() {
+        for reply in &[
+            "250 OK Accepted",
+            "250 Ok Thanks",
+            "250 2.0.0 Ok",
+            "250 OK",
+            "250 ok 12345",
+            "250 Ok 010001755f2b3a1c-8d1e6c2a-3b4f-4a5e-9c7d-1e2f3a4b5c6d",
+            "250 Ok: queued as",
+            "250 2.0.0 Message accepted for delivery",
+            "250 2.0.0 Ok: queued as .",
+        ] {
+            assert_eq!(queue_id(reply), None, "{}", reply);
+        }
+    }
+}
@@ -0,0 +1,223 @@
+use std::io;
+use std::sync::Arc;
+
+use async_stream_packed::{TlsClientUpgrader, Upgrader};
+use async_trait::async_trait;
+use futures_rustls::{client::TlsStream, TlsConnector};
+use futures_util::io::{AsyncRead, AsyncWrite};
+use rustls::{
+    Certificate, ClientConfig, RootCertStore, ServerCertVerified, ServerCertVerifier, Session,
+    TLSError, WebPKIVerifier,
+};
+use webpki::DNSNameRef;
+
+use crate::verification::{CertificateChain, PeerCertificates};
+
+pub struct RustlsClientTlsUpgrader {
+    connector: TlsConnector,
+    server_name: String,
+}
+
+impl RustlsClientTlsUpgrader {
+    pub fn new(config: Arc<ClientConfig>, server_name: String) -> Self {
+        Self {
+            connector: config.into(),
+            server_name,
+        }
+    }
+
+    pub fn builder() -> RustlsClientTlsUpgraderBuilder {
+        RustlsClientTlsUpgraderBuilder::new()
+    }
+}
+
+#[async_trait]
+impl<S> Upgrader<S> for RustlsClientTlsUpgrader
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    type Output = TlsStream<S>;
+
+    async fn upgrade(&mut self, stream: S) -> io::Result<Self::Output> {
+        let domain = DNSNameRef::try_from_ascii_str(&self.server_name)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        self.connector.connect(domain, stream).await
+    }
+}
+
+impl<S> TlsClientUpgrader<S> for RustlsClientTlsUpgrader where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static
+{
+}
+
+impl<S> PeerCertificates for TlsStream<S> {
+    fn peer_certificates(&self) -> Option<CertificateChain> {
+        let (_, session) = self.get_ref();
+        session
+            .get_peer_certificates()
+            .map(|certificates| certificates.into_iter().map(|c| c.0).collect())
+    }
+}
+
+//
+//
+//
+
+/// `server_name` of build is sent as SNI and verified against the certificate, connecting by IP
+/// address only needs the TCP stream to point there.
+pub struct RustlsClientTlsUpgraderBuilder {
+    root_certificates: Vec<Vec<u8>>,
+    webpki_roots: bool,
+    native_roots: bool,
+    sni: bool,
+    verify_name: Option<String>,
+    accept_invalid_certs: bool,
+}
+
+impl Default for RustlsClientTlsUpgraderBuilder {
+    fn default() -> Self {
+        Self {
+            root_certificates: vec![],
+            webpki_roots: true,
+            native_roots: false,
+            sni: true,
+            verify_name: None,
+            accept_invalid_certs: false,
+        }
+    }
+}
+
+impl RustlsClientTlsUpgraderBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// DER encoded, e.g. a private CA.
+    pub fn add_root_certificate(mut self, certificate: Vec<u8>) -> Self {
+        self.root_certificates.push(certificate);
+        self
+    }
+
+    pub fn add_root_certificates_pem(mut self, mut pem: &[u8]) -> io::Result<Self> {
+        let certificates = rustls::internal::pemfile::certs(&mut pem)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid PEM certificates"))?;
+        self.root_certificates
+            .extend(certificates.into_iter().map(|c| c.0));
+        Ok(self)
+    }
+
+    /// Mozilla's root certificates, enabled by default.
+    pub fn webpki_roots(mut self, enabled: bool) -> Self {
+        self.webpki_roots = enabled;
+        self
+    }
+
+    /// The platform's certificate store.
+    pub fn native_roots(mut self, enabled: bool) -> Self {
+        self.native_roots = enabled;
+        self
+    }
+
+    pub fn sni(mut self, enabled: bool) -> Self {
+        self.sni = enabled;
+        self
+    }
+
+    /// Verify the certificate against `name` instead of the server name.
+    pub fn verify_name(mut self, name: impl Into<String>) -> Self {
+        self.verify_name = Some(name.into());
+        self
+    }
+
+    /// DANGEROUS: any certificate is accepted, for local testing or when a
+    /// `PeerCertificateVerifier` such as `Dane` verifies the certificates instead.
+    pub fn dangerous_accept_invalid_certs(mut self, enabled: bool) -> Self {
+        self.accept_invalid_certs = enabled;
+        self
+    }
+
+    pub fn build_config(self) -> io::Result<ClientConfig> {
+        let mut config = ClientConfig::new();
+
+        if self.native_roots {
+            config.root_store = match rustls_native_certs::load_native_certs() {
+                Ok(store) => store,
+                // Unparsable certificates are skipped
+                Err((Some(store), _)) => store,
+                Err((None, err)) => return Err(err),
+            };
+        }
+        if self.webpki_roots {
+            config
+                .root_store
+                .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+        }
+        for certificate in self.root_certificates {
+            config
+                .root_store
+                .add(&Certificate(certificate))
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        }
+
+        config.enable_sni = self.sni;
+
+        if self.accept_invalid_certs {
+            config
+                .dangerous()
+                .set_certificate_verifier(Arc::new(AcceptInvalidCerts));
+        } else if let Some(name) = self.verify_name {
+            DNSNameRef::try_from_ascii_str(&name)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+            config
+                .dangerous()
+                .set_certificate_verifier(Arc::new(VerifyName {
+                    inner: WebPKIVerifier::new(),
+                    name,
+                }));
+        }
+
+        Ok(config)
+    }
+
+    pub fn build(self, server_name: impl Into<String>) -> io::Result<RustlsClientTlsUpgrader> {
+        let config = self.build_config()?;
+        Ok(RustlsClientTlsUpgrader::new(
+            Arc::new(config),
+            server_name.into(),
+        ))
+    }
+}
+
+struct VerifyName {
+    inner: WebPKIVerifier,
+    name: String,
+}
+
+impl ServerCertVerifier for VerifyName {
+    fn verify_server_cert(
+        &self,
+        roots: &RootCertStore,
+        presented_certs: &[Certificate],
+        _dns_name: DNSNameRef,
+        ocsp_response: &[u8],
+    ) -> Result<ServerCertVerified, TLSError> {
+        let name = DNSNameRef::try_from_ascii_str(&self.name)
+            .map_err(|_| TLSError::General("invalid verify name".to_owned()))?;
+        self.inner
+            .verify_server_cert(roots, presented_certs, name, ocsp_response)
+    }
+}
+
+struct AcceptInvalidCerts;
+
+impl ServerCertVerifier for AcceptInvalidCerts {
+    fn verify_server_cert(
+        &self,
+        _roots: &RootCertStore,
+        _presented_certs: &[Certificate],
+        _dns_name: DNSNameRef,
+        _ocsp_response: &[u8],
+    ) -> Result<ServerCertVerified, TLSError> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
@@ -7,6 +7,7 @@ use lettre::transport::smtp::error::Error;
 use lettre::Envelope;
 
 use crate::connection::AsyncConnection;
+use crate::receipt::SendReceipt;
 
 pub struct AsyncSession<'a, S, STU>
 where
@@ -26,7 +27,7 @@ where
     }
 
     // ref https://github.com/lettre/lettre/blob/v0.10.0-alpha.1/src/lib.rs#L145-L149
-    pub async fn send(&mut self, message: &Message) -> result::Result<(), Error> {
+    pub async fn send(&mut self, message: &Message) -> result::Result<SendReceipt, Error> {
         let raw = message.formatted();
         self.send_raw(message.envelope(), &raw).await
     }
@@ -36,11 +37,11 @@ where
         &mut self,
         envelope: &Envelope,
         email: &[u8],
-    ) -> result::Result<(), Error> {
-        let _ = self.connection.send(envelope, email).await?;
+    ) -> result::Result<SendReceipt, Error> {
+        let receipt = self.connection.send_with_receipt(envelope, email).await?;
 
         self.connection.quit().await?;
 
-        Ok(())
+        Ok(receipt)
     }
 }
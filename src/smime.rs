@@ -0,0 +1,346 @@
+use std::io;
+
+use openssl::error::ErrorStack;
+use openssl::pkcs7::{Pkcs7, Pkcs7Flags};
+use openssl::pkey::{PKey, Private};
+use openssl::stack::Stack;
+use openssl::symm::Cipher;
+use openssl::x509::X509;
+
+use lettre::Message;
+
+use crate::mime::{base64_lines, Entity};
+
+/// Signs the content of a formatted message as `multipart/signed`, the headers like `From`
+/// and `Subject` stay outside of the signature.
+// ref https://tools.ietf.org/html/rfc8551#section-3.5.3
+pub struct SmimeSigner {
+    certificate: X509,
+    key: PKey<Private>,
+    chain: Vec<X509>,
+}
+
+impl SmimeSigner {
+    pub fn new(certificate: X509, key: PKey<Private>) -> Self {
+        Self {
+            certificate,
+            key,
+            chain: vec![],
+        }
+    }
+
+    /// The signer's certificate, then the intermediates if any, and its private key.
+    pub fn from_pem(certificates: &[u8], key: &[u8]) -> io::Result<Self> {
+        let mut certificates = X509::stack_from_pem(certificates)
+            .map_err(openssl_error)?
+            .into_iter();
+        let certificate = certificates
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no certificate"))?;
+        let key = PKey::private_key_from_pem(key).map_err(openssl_error)?;
+
+        Ok(certificates.fold(Self::new(certificate, key), Self::intermediate))
+    }
+
+    /// Included in the signature, so the recipient can build the path to its trust anchor.
+    pub fn intermediate(mut self, certificate: X509) -> Self {
+        self.chain.push(certificate);
+        self
+    }
+
+    /// Signs `message.formatted()`.
+    pub fn sign_message(&self, message: &Message) -> io::Result<Vec<u8>> {
+        self.sign(&message.formatted())
+    }
+
+    /// An 8bit body is encoded first, quoted-printable or base64, an 8bit multipart is an
+    /// error.
+    pub fn sign(&self, message: &[u8]) -> io::Result<Vec<u8>> {
+        let entity = Entity::split_seven_bit(message)?;
+
+        let mut chain = Stack::new().map_err(openssl_error)?;
+        for certificate in &self.chain {
+            chain.push(certificate.clone()).map_err(openssl_error)?;
+        }
+        // Already canonical, BINARY keeps openssl from converting the line endings again
+        let signature = Pkcs7::sign(
+            &self.certificate,
+            &self.key,
+            &chain,
+            &entity.entity,
+            Pkcs7Flags::DETACHED | Pkcs7Flags::BINARY,
+        )
+        .and_then(|pkcs7| pkcs7.to_der())
+        .map_err(openssl_error)?;
+
+        let boundary = entity.boundary("smime");
+        let content_type = format!(
+            "Content-Type: multipart/signed; protocol=\"application/pkcs7-signature\"; micalg=sha-256;\r\n\tboundary=\"{}\"\r\n",
+            boundary
+        );
+        // The CRLF before a delimiter belongs to it, not to the signed entity
+        // ref https://tools.ietf.org/html/rfc2046#section-5.1.1
+        let mut body = format!(
+            "This is a cryptographically signed message in MIME format.\r\n\r\n--{}\r\n",
+            boundary
+        )
+        .into_bytes();
+        body.extend_from_slice(&entity.entity);
+        body.extend_from_slice(
+            format!(
+                "\r\n--{boundary}\r\nContent-Type: application/pkcs7-signature; name=\"smime.p7s\"\r\nContent-Transfer-Encoding: base64\r\nContent-Disposition: attachment; filename=\"smime.p7s\"\r\n\r\n{signature}\r\n--{boundary}--\r\n",
+                boundary = boundary,
+                signature = base64_lines(&signature),
+            )
+            .as_bytes(),
+        );
+
+        Ok(entity.wrap(&content_type, &body))
+    }
+}
+
+/// Encrypts the content of a formatted message as `application/pkcs7-mime`, for every
+/// recipient's certificate. The headers like `From` and `Subject` stay in the clear.
+// ref https://tools.ietf.org/html/rfc8551#section-3.3
+pub struct SmimeEncryptor {
+    recipients: Vec<X509>,
+    cipher: Cipher,
+}
+
+impl Default for SmimeEncryptor {
+    fn default() -> Self {
+        Self {
+            recipients: vec![],
+            cipher: Cipher::aes_256_cbc(),
+        }
+    }
+}
+
+impl SmimeEncryptor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Every certificate in `certificates` is a recipient.
+    pub fn from_pem(certificates: &[u8]) -> io::Result<Self> {
+        Ok(X509::stack_from_pem(certificates)
+            .map_err(openssl_error)?
+            .into_iter()
+            .fold(Self::new(), Self::recipient))
+    }
+
+    pub fn recipient(mut self, certificate: X509) -> Self {
+        self.recipients.push(certificate);
+        self
+    }
+
+    /// Defaults to AES-256-CBC.
+    pub fn cipher(mut self, cipher: Cipher) -> Self {
+        self.cipher = cipher;
+        self
+    }
+
+    /// Encrypts `message.formatted()`.
+    pub fn encrypt_message(&self, message: &Message) -> io::Result<Vec<u8>> {
+        self.encrypt(&message.formatted())
+    }
+
+    /// `message` may be signed first with `SmimeSigner::sign`.
+    pub fn encrypt(&self, message: &[u8]) -> io::Result<Vec<u8>> {
+        if self.recipients.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "no recipient certificate",
+            ));
+        }
+        let entity = Entity::split(message);
+
+        let mut recipients = Stack::new().map_err(openssl_error)?;
+        for certificate in &self.recipients {
+            recipients
+                .push(certificate.clone())
+                .map_err(openssl_error)?;
+        }
+        let encrypted =
+            Pkcs7::encrypt(&recipients, &entity.entity, self.cipher, Pkcs7Flags::BINARY)
+                .and_then(|pkcs7| pkcs7.to_der())
+                .map_err(openssl_error)?;
+
+        let content_type = "Content-Type: application/pkcs7-mime; smime-type=enveloped-data; name=\"smime.p7m\"\r\nContent-Transfer-Encoding: base64\r\nContent-Disposition: attachment; filename=\"smime.p7m\"\r\n";
+
+        Ok(entity.wrap(content_type, base64_lines(&encrypted).as_bytes()))
+    }
+}
+
+fn openssl_error(err: ErrorStack) -> io::Error {
+    io::Error::other(err)
+}
+
+#[cfg(test)]
+mod tests {
+    use lettre::message::{header, SinglePart};
+    use openssl::asn1::Asn1Time;
+    use openssl::bn::BigNum;
+    use openssl::hash::MessageDigest;
+    use openssl::rsa::Rsa;
+    use openssl::x509::store::X509StoreBuilder;
+    use openssl::x509::{X509Builder, X509NameBuilder};
+
+    use super::*;
+    use crate::mime::tests::parts;
+
+    // A self-signed certificate, generated so the tests need no files
+    fn certificate(email: &str) -> (X509, PKey<Private>) {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", email).unwrap();
+        let name = name.build();
+
+        let mut builder = X509Builder::new().unwrap();
+        builder.set_version(2).unwrap();
+        builder
+            .set_serial_number(&BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap())
+            .unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+
+        (builder.build(), key)
+    }
+
+    fn message() -> Message {
+        Message::builder()
+            .from("sender@example.com".parse().unwrap())
+            .to("recipient@example.com".parse().unwrap())
+            .subject("Hi")
+            .singlepart(
+                SinglePart::eight_bit()
+                    .header(header::ContentType(
+                        "text/plain; charset=utf-8".parse().unwrap(),
+                    ))
+                    .body("Grüße \r\n"),
+            )
+            .unwrap()
+    }
+
+    // The base64 body of a part, or of a message
+    fn decode_body(entity: &[u8]) -> Vec<u8> {
+        let i = entity.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        let body: Vec<u8> = entity[i + 4..]
+            .iter()
+            .filter(|byte| !byte.is_ascii_whitespace())
+            .copied()
+            .collect();
+        base64::decode(body).unwrap()
+    }
+
+    fn verify(signed: &[u8], certificate: &X509) -> Result<Vec<u8>, ErrorStack> {
+        let parts = parts(signed);
+        assert_eq!(parts.len(), 2);
+        let signature = Pkcs7::from_der(&decode_body(&parts[1]))?;
+
+        let mut store = X509StoreBuilder::new()?;
+        store.add_cert(certificate.clone())?;
+        let certificates: Stack<X509> = Stack::new()?;
+        signature.verify(
+            &certificates,
+            &store.build(),
+            Some(&parts[0]),
+            None,
+            Pkcs7Flags::BINARY,
+        )?;
+        Ok(parts[0].clone())
+    }
+
+    #[test]
+    fn signs_seven_bit_entity() {
+        let (certificate, key) = certificate("sender@example.com");
+        let signed = SmimeSigner::new(certificate.clone(), key)
+            .sign_message(&message())
+            .unwrap();
+
+        let text = String::from_utf8(signed.clone()).unwrap();
+        assert!(text.starts_with("From: sender@example.com\r\n"));
+        assert!(text.contains("Subject: Hi\r\n"));
+        assert!(text.contains(
+            "Content-Type: multipart/signed; protocol=\"application/pkcs7-signature\"; micalg=sha-256;"
+        ));
+        assert!(signed.is_ascii());
+        assert!(!text.contains(" \r\n"));
+
+        assert_eq!(
+            verify(&signed, &certificate).unwrap(),
+            b"Content-Type: text/plain; charset=utf-8\r\nContent-Transfer-Encoding: quoted-printable\r\n\r\nGr=C3=BC=C3=9Fe=20\r\n\r\n"
+        );
+
+        let tampered = text.replace("Gr=C3=BC", "Gr=C3=BD");
+        assert!(verify(tampered.as_bytes(), &certificate).is_err());
+        let (other, _) = self::certificate("other@example.com");
+        assert!(verify(&signed, &other).is_err());
+    }
+
+    #[test]
+    fn rejects_eight_bit_multipart() {
+        let (certificate, key) = certificate("sender@example.com");
+        let err = SmimeSigner::new(certificate, key)
+            .sign(
+                "Subject: Hi\r\nContent-Type: multipart/mixed; boundary=\"b\"\r\n\r\n--b\r\n\r\nGrüße\r\n--b--\r\n"
+                    .as_bytes(),
+            )
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn encrypts_for_every_recipient() {
+        let (first, first_key) = certificate("first@example.com");
+        let (second, second_key) = certificate("second@example.com");
+        let message = message();
+        let encrypted = SmimeEncryptor::new()
+            .recipient(first.clone())
+            .recipient(second.clone())
+            .encrypt_message(&message)
+            .unwrap();
+
+        let entity = Entity::split(&encrypted);
+        assert_eq!(entity.headers, Entity::split(&message.formatted()).headers);
+        assert!(entity
+            .entity
+            .starts_with(b"Content-Type: application/pkcs7-mime; smime-type=enveloped-data;"));
+        let pkcs7 = Pkcs7::from_der(&decode_body(&entity.entity)).unwrap();
+
+        let expected = Entity::split(&message.formatted()).entity;
+        assert_eq!(
+            pkcs7
+                .decrypt(&first_key, &first, Pkcs7Flags::BINARY)
+                .unwrap(),
+            expected
+        );
+        assert_eq!(
+            pkcs7
+                .decrypt(&second_key, &second, Pkcs7Flags::BINARY)
+                .unwrap(),
+            expected
+        );
+
+        let (other, other_key) = certificate("other@example.com");
+        assert!(pkcs7
+            .decrypt(&other_key, &other, Pkcs7Flags::BINARY)
+            .is_err());
+    }
+
+    #[test]
+    fn encrypt_needs_a_recipient() {
+        let err = SmimeEncryptor::new()
+            .encrypt_message(&message())
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,162 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SourceAddressStrategy {
+    /// One rotation shared by all destinations.
+    RoundRobin,
+    /// A separate rotation for each destination host.
+    PerDestination,
+    /// The same address for a destination host, as long as the pool is unchanged.
+    Sticky,
+}
+
+/// Local addresses outbound connections are bound to, only the addresses of the destination's
+/// family are candidates.
+#[derive(Debug)]
+pub struct SourceAddressPool {
+    addrs: Vec<IpAddr>,
+    strategy: SourceAddressStrategy,
+    next: AtomicUsize,
+    next_per_destination: Mutex<HashMap<String, usize>>,
+}
+
+impl SourceAddressPool {
+    pub fn new(addrs: Vec<IpAddr>, strategy: SourceAddressStrategy) -> Self {
+        Self {
+            addrs,
+            strategy,
+            next: AtomicUsize::new(0),
+            next_per_destination: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn single(addr: IpAddr) -> Self {
+        Self::new(vec![addr], SourceAddressStrategy::RoundRobin)
+    }
+
+    pub fn addrs(&self) -> &[IpAddr] {
+        &self.addrs
+    }
+
+    pub fn strategy(&self) -> SourceAddressStrategy {
+        self.strategy
+    }
+
+    /// `destination` is the host connected to, the MX host for `DirectDelivery`, not the
+    /// recipient domain.
+    pub fn select(&self, destination: &str, addr: &SocketAddr) -> io::Result<IpAddr> {
+        let candidates: Vec<_> = self
+            .addrs
+            .iter()
+            .filter(|ip| ip.is_ipv4() == addr.is_ipv4())
+            .collect();
+        if candidates.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                format!("no source address of the family of {}", addr),
+            ));
+        }
+
+        let index = match self.strategy {
+            SourceAddressStrategy::RoundRobin => self.next.fetch_add(1, Ordering::Relaxed),
+            SourceAddressStrategy::PerDestination => {
+                let mut next_per_destination = self.next_per_destination.lock().expect("never");
+                let next = next_per_destination
+                    .entry(destination.to_ascii_lowercase())
+                    .or_insert(0);
+                let index = *next;
+                *next = next.wrapping_add(1);
+                index
+            }
+            SourceAddressStrategy::Sticky => {
+                let mut hasher = DefaultHasher::new();
+                destination.to_ascii_lowercase().hash(&mut hasher);
+                hasher.finish() as usize
+            }
+        };
+
+        Ok(*candidates[index % candidates.len()])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const V4_A: &str = "192.0.2.1";
+    const V4_B: &str = "192.0.2.2";
+    const V6: &str = "2001:db8::1";
+
+    fn pool(strategy: SourceAddressStrategy) -> SourceAddressPool {
+        SourceAddressPool::new(
+            vec![
+                V4_A.parse().unwrap(),
+                V6.parse().unwrap(),
+                V4_B.parse().unwrap(),
+            ],
+            strategy,
+        )
+    }
+
+    fn select(pool: &SourceAddressPool, destination: &str) -> String {
+        let addr = "198.51.100.1:25".parse().unwrap();
+        pool.select(destination, &addr).unwrap().to_string()
+    }
+
+    #[test]
+    fn round_robin() {
+        let pool = pool(SourceAddressStrategy::RoundRobin);
+        assert_eq!(select(&pool, "mx1.example.com"), V4_A);
+        assert_eq!(select(&pool, "mx2.example.com"), V4_B);
+        assert_eq!(select(&pool, "mx1.example.com"), V4_A);
+    }
+
+    #[test]
+    fn per_destination() {
+        let pool = pool(SourceAddressStrategy::PerDestination);
+        assert_eq!(select(&pool, "mx1.example.com"), V4_A);
+        assert_eq!(select(&pool, "mx2.example.com"), V4_A);
+        assert_eq!(select(&pool, "MX1.example.com"), V4_B);
+        assert_eq!(select(&pool, "mx2.example.com"), V4_B);
+        assert_eq!(select(&pool, "mx1.example.com"), V4_A);
+    }
+
+    #[test]
+    fn sticky() {
+        let pool = pool(SourceAddressStrategy::Sticky);
+        let selected = select(&pool, "mx1.example.com");
+        for _ in 0..5 {
+            assert_eq!(select(&pool, "mx1.example.com"), selected);
+            assert_eq!(select(&pool, "MX1.example.com"), selected);
+        }
+
+        // Spread over the destinations
+        let selected: Vec<_> = (0..20)
+            .map(|i| select(&pool, &format!("mx{}.example.com", i)))
+            .collect();
+        assert!(selected.iter().any(|addr| addr == V4_A));
+        assert!(selected.iter().any(|addr| addr == V4_B));
+    }
+
+    #[test]
+    fn filters_the_family() {
+        let pool = pool(SourceAddressStrategy::RoundRobin);
+        let addr = "[2001:db8::25]:25".parse().unwrap();
+        for _ in 0..3 {
+            assert_eq!(
+                pool.select("mx.example.com", &addr).unwrap().to_string(),
+                V6
+            );
+        }
+
+        let pool = SourceAddressPool::single(V4_A.parse().unwrap());
+        let err = pool.select("mx.example.com", &addr).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AddrNotAvailable);
+    }
+}
@@ -0,0 +1,538 @@
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::process;
+use std::result;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use async_stream_packed::TlsClientUpgrader;
+use async_trait::async_trait;
+use futures_util::io::{AsyncRead, AsyncWrite};
+use lettre::message::Message;
+use lettre::transport::smtp::{
+    authentication::{Credentials, Mechanism},
+    error::Error,
+    response::Response,
+};
+use lettre::{Address, Envelope};
+
+use crate::client::AsyncClient;
+use crate::connection::RecipientResult;
+use crate::connector::{ConnectOptions, Runtime};
+use crate::dsn::{Dsn, FailedRecipient};
+use crate::receipt::SendReceipt;
+
+// ref https://tools.ietf.org/html/rfc5321#section-4.5.4.1
+const RETRY_INTERVAL: Duration = Duration::from_secs(30 * 60);
+const GIVE_UP_AFTER: Duration = Duration::from_secs(5 * 24 * 60 * 60);
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Clone, Debug)]
+pub struct SpoolEntry {
+    pub id: String,
+    /// `None` for the null reverse-path, e.g. bounces.
+    pub from: Option<Address>,
+    pub to: Vec<Address>,
+    pub message: Vec<u8>,
+    pub created: SystemTime,
+    pub attempts: u32,
+    pub next_attempt: SystemTime,
+    pub last_error: Option<String>,
+}
+
+impl SpoolEntry {
+    pub fn new(envelope: &Envelope, message: Vec<u8>) -> Self {
+        let now = SystemTime::now();
+        Self {
+            id: new_id(now),
+            from: envelope.from().cloned(),
+            to: envelope.to().to_vec(),
+            message,
+            created: now,
+            attempts: 0,
+            next_attempt: now,
+            last_error: None,
+        }
+    }
+
+    pub fn from_message(message: &Message) -> Self {
+        Self::new(message.envelope(), message.formatted())
+    }
+
+    pub fn envelope(&self) -> result::Result<Envelope, lettre::error::Error> {
+        Envelope::new(self.from.clone(), self.to.clone())
+    }
+}
+
+// Unique in the process, and across restarts as long as the clock does not go back
+fn new_id(now: SystemTime) -> String {
+    let since_epoch = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+    format!(
+        "{:x}{:05x}-{:x}-{:x}",
+        since_epoch.as_secs(),
+        since_epoch.subsec_micros(),
+        process::id(),
+        NEXT_ID.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+#[async_trait]
+pub trait SpoolStorage: Send + Sync {
+    /// Inserts, or replaces the entry with the same id.
+    async fn store(&self, entry: &SpoolEntry) -> io::Result<()>;
+
+    async fn list(&self) -> io::Result<Vec<SpoolEntry>>;
+
+    async fn remove(&self, id: &str) -> io::Result<()>;
+}
+
+/// Two files per entry in `dir`, `<id>.eml` with the message and `<id>.env` with the envelope
+/// and the delivery state. The files are small, they are read and written blocking. `list`
+/// skips the entries without a message.
+#[derive(Clone, Debug)]
+pub struct FileSpool {
+    dir: PathBuf,
+}
+
+impl FileSpool {
+    pub fn new(dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+
+    fn path(&self, id: &str, extension: &str) -> PathBuf {
+        self.dir.join(format!("{}.{}", id, extension))
+    }
+}
+
+#[async_trait]
+impl SpoolStorage for FileSpool {
+    async fn store(&self, entry: &SpoolEntry) -> io::Result<()> {
+        if entry.id.is_empty() || entry.id.contains(['/', '\\', '.']) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid id"));
+        }
+
+        // The envelope file is written last, an entry without it is incomplete
+        let message_path = self.path(&entry.id, "eml");
+        if !message_path.exists() {
+            let tmp = self.path(&entry.id, "eml.tmp");
+            fs::write(&tmp, &entry.message)?;
+            fs::rename(&tmp, &message_path)?;
+        }
+
+        let tmp = self.path(&entry.id, "env.tmp");
+        fs::write(&tmp, format_envelope(entry))?;
+        fs::rename(&tmp, self.path(&entry.id, "env"))
+    }
+
+    async fn list(&self) -> io::Result<Vec<SpoolEntry>> {
+        let mut entries = vec![];
+        for dir_entry in fs::read_dir(&self.dir)? {
+            let path = dir_entry?.path();
+            if path.extension().is_none_or(|extension| extension != "env") {
+                continue;
+            }
+            let id = match path.file_stem().and_then(|stem| stem.to_str()) {
+                Some(id) => id.to_owned(),
+                None => continue,
+            };
+
+            let mut entry = parse_envelope(&id, &fs::read_to_string(&path)?)?;
+            // Removed in the meantime, a broken entry must not hold back the others
+            entry.message = match fs::read(self.path(&id, "eml")) {
+                Ok(message) => message,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err),
+            };
+            entries.push(entry);
+        }
+        entries.sort_by(|a, b| (a.created, &a.id).cmp(&(b.created, &b.id)));
+        Ok(entries)
+    }
+
+    async fn remove(&self, id: &str) -> io::Result<()> {
+        for extension in &["env", "eml"] {
+            match fs::remove_file(self.path(id, extension)) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+// One `name value` per line
+fn format_envelope(entry: &SpoolEntry) -> String {
+    let mut out = String::new();
+    out.push_str(&format!(
+        "from {}\n",
+        entry
+            .from
+            .as_ref()
+            .map(ToString::to_string)
+            .unwrap_or_default()
+    ));
+    for to in &entry.to {
+        out.push_str(&format!("to {}\n", to));
+    }
+    out.push_str(&format!("created {}\n", unix_secs(entry.created)));
+    out.push_str(&format!("attempts {}\n", entry.attempts));
+    out.push_str(&format!("next-attempt {}\n", unix_secs(entry.next_attempt)));
+    if let Some(last_error) = &entry.last_error {
+        out.push_str(&format!("last-error {}\n", last_error.replace('\n', " ")));
+    }
+    out
+}
+
+fn parse_envelope(id: &str, text: &str) -> io::Result<SpoolEntry> {
+    let invalid = |what: &str| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid spool entry {}: {}", id, what),
+        )
+    };
+
+    let mut entry = SpoolEntry {
+        id: id.to_owned(),
+        from: None,
+        to: vec![],
+        message: vec![],
+        created: UNIX_EPOCH,
+        attempts: 0,
+        next_attempt: UNIX_EPOCH,
+        last_error: None,
+    };
+    for line in text.lines() {
+        let (name, value) = line.split_once(' ').unwrap_or((line, ""));
+        match name {
+            "from" if !value.is_empty() => {
+                entry.from = Some(Address::from_str(value).map_err(|_| invalid("from"))?)
+            }
+            "from" => {}
+            "to" => entry
+                .to
+                .push(Address::from_str(value).map_err(|_| invalid("to"))?),
+            "created" => entry.created = from_unix_secs(value).ok_or_else(|| invalid(name))?,
+            "attempts" => entry.attempts = value.parse().map_err(|_| invalid(name))?,
+            "next-attempt" => {
+                entry.next_attempt = from_unix_secs(value).ok_or_else(|| invalid(name))?
+            }
+            "last-error" => entry.last_error = Some(value.to_owned()),
+            _ => {}
+        }
+    }
+    Ok(entry)
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn from_unix_secs(value: &str) -> Option<SystemTime> {
+    value
+        .parse()
+        .ok()
+        .map(|secs| UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+//
+//
+//
+#[derive(Clone, Debug)]
+pub struct RetrySchedule {
+    intervals: Vec<Duration>,
+    expiry: Duration,
+}
+
+impl Default for RetrySchedule {
+    fn default() -> Self {
+        Self::new(vec![RETRY_INTERVAL], GIVE_UP_AFTER)
+    }
+}
+
+impl RetrySchedule {
+    /// The last interval is repeated until `expiry`, counted from the enqueueing.
+    pub fn new(intervals: Vec<Duration>, expiry: Duration) -> Self {
+        Self { intervals, expiry }
+    }
+
+    pub fn expiry(&self) -> Duration {
+        self.expiry
+    }
+
+    /// After `attempts` failed attempts.
+    pub fn delay(&self, attempts: u32) -> Duration {
+        let index = (attempts.max(1) - 1) as usize;
+        self.intervals
+            .get(index)
+            .or_else(|| self.intervals.last())
+            .cloned()
+            .unwrap_or(RETRY_INTERVAL)
+    }
+}
+
+/// Per entry and recipient group, an entry partly delivered also has `Failed` or `Deferred`
+/// outcomes for the other recipients.
+#[derive(Debug)]
+pub enum SpoolOutcome {
+    Delivered {
+        id: String,
+        recipients: Vec<Address>,
+        response: Response,
+    },
+    /// The entry is kept for these recipients only.
+    Deferred {
+        id: String,
+        recipients: Vec<Address>,
+        error: Error,
+        next_attempt: SystemTime,
+    },
+    /// Rejected permanently or expired, `bounce_id` is the enqueued DSN if any.
+    Failed {
+        id: String,
+        recipients: Vec<Address>,
+        error: Error,
+        bounce_id: Option<String>,
+    },
+}
+
+/// Delivers the due entries through a relay, one connection per entry.
+pub struct SpoolWorker<R, F> {
+    storage: Arc<dyn SpoolStorage>,
+    host: String,
+    port: u16,
+    options: ConnectOptions<R>,
+    upgrader: F,
+    credentials: Option<(Vec<Mechanism>, Credentials)>,
+    schedule: RetrySchedule,
+    reporting_mta: String,
+}
+
+impl<R, F, STU> SpoolWorker<R, F>
+where
+    R: Runtime + 'static,
+    F: Fn(&str) -> STU,
+    STU: TlsClientUpgrader<R::TcpStream> + Unpin,
+    STU::Output: AsyncRead + AsyncWrite + Unpin,
+{
+    /// `upgrader` is called with `host`.
+    pub fn new(
+        storage: Arc<dyn SpoolStorage>,
+        host: &str,
+        port: u16,
+        options: ConnectOptions<R>,
+        upgrader: F,
+    ) -> Self {
+        let reporting_mta = options.hello_name.to_string();
+        Self {
+            storage,
+            host: host.to_owned(),
+            port,
+            options,
+            upgrader,
+            credentials: None,
+            schedule: RetrySchedule::default(),
+            reporting_mta,
+        }
+    }
+
+    pub fn credentials(mut self, mechanisms: &[Mechanism], credentials: Credentials) -> Self {
+        self.credentials = Some((mechanisms.to_vec(), credentials));
+        self
+    }
+
+    pub fn schedule(mut self, schedule: RetrySchedule) -> Self {
+        self.schedule = schedule;
+        self
+    }
+
+    /// The name in the DSNs, the hello name by default. The DSNs are sent from
+    /// `MAILER-DAEMON@<reporting_mta>`.
+    pub fn reporting_mta(mut self, reporting_mta: impl Into<String>) -> Self {
+        self.reporting_mta = reporting_mta.into();
+        self
+    }
+
+    pub async fn enqueue(&self, message: &Message) -> io::Result<String> {
+        let entry = SpoolEntry::from_message(message);
+        self.storage.store(&entry).await?;
+        Ok(entry.id)
+    }
+
+    /// Never returns but on a storage error.
+    pub async fn run(&self, poll_interval: Duration) -> io::Result<()> {
+        loop {
+            self.run_once().await?;
+            R::sleep(poll_interval).await;
+        }
+    }
+
+    /// Attempts the due entries once.
+    pub async fn run_once(&self) -> io::Result<Vec<SpoolOutcome>> {
+        let now = SystemTime::now();
+        let mut outcomes = vec![];
+
+        for mut entry in self.storage.list().await? {
+            if entry.next_attempt > now {
+                continue;
+            }
+
+            let result = self.deliver(&entry).await;
+            entry.attempts += 1;
+
+            let mut entry_outcomes = vec![];
+            let mut rejected = vec![];
+            let mut deferred = vec![];
+            match result {
+                Ok(receipt) => {
+                    let mut delivered = vec![];
+                    for RecipientResult { recipient, result } in receipt.recipients {
+                        match result {
+                            Ok(_) => delivered.push(recipient),
+                            Err(error @ Error::Permanent(_)) => {
+                                rejected.push((vec![recipient], error))
+                            }
+                            Err(error) => deferred.push((vec![recipient], error)),
+                        }
+                    }
+                    entry_outcomes.push(SpoolOutcome::Delivered {
+                        id: entry.id.clone(),
+                        recipients: delivered,
+                        response: receipt.response,
+                    });
+                }
+                // The transaction failed for all the recipients
+                Err(error @ Error::Permanent(_)) => rejected.push((entry.to.clone(), error)),
+                Err(error) => deferred.push((entry.to.clone(), error)),
+            }
+            entry_outcomes.extend(self.settle(entry, rejected, deferred).await?);
+
+            for outcome in entry_outcomes {
+                smtp_event!(outcome = ?outcome, "spool");
+                outcomes.push(outcome);
+            }
+        }
+
+        Ok(outcomes)
+    }
+
+    async fn deliver(&self, entry: &SpoolEntry) -> result::Result<SendReceipt, Error> {
+        let envelope = entry
+            .envelope()
+            .map_err(|_| Error::Client("Invalid envelope"))?;
+
+        let upgrader = (self.upgrader)(&self.host);
+        let client = AsyncClient::connect(&self.host, self.port, upgrader, &self.options).await?;
+        let mut connection = client.into_connection();
+        if let Some((mechanisms, credentials)) = &self.credentials {
+            connection.auth(mechanisms, credentials).await?;
+        }
+
+        let receipt = connection
+            .send_with_receipt(&envelope, &entry.message)
+            .await?;
+        let _ = connection.quit().await;
+
+        Ok(receipt)
+    }
+
+    // Enqueues one DSN for the rejected recipients, and the deferred ones once expired, unless
+    // the entry is a bounce itself. Then keeps the entry for the other deferred recipients.
+    async fn settle(
+        &self,
+        mut entry: SpoolEntry,
+        rejected: Vec<(Vec<Address>, Error)>,
+        mut deferred: Vec<(Vec<Address>, Error)>,
+    ) -> io::Result<Vec<SpoolOutcome>> {
+        let mut failed: Vec<_> = rejected
+            .into_iter()
+            .map(|(recipients, error)| (recipients, error, "5.0.0"))
+            .collect();
+        if SystemTime::now() >= entry.created + self.schedule.expiry() {
+            // ref https://tools.ietf.org/html/rfc3463#section-3.5
+            failed.extend(
+                deferred
+                    .drain(..)
+                    .map(|(recipients, error)| (recipients, error, "4.4.7")),
+            );
+        }
+
+        let bounce_id = match &entry.from {
+            Some(from) if !failed.is_empty() => {
+                let bounce = self.bounce(&entry, from, &failed)?;
+                self.storage.store(&bounce).await?;
+                Some(bounce.id)
+            }
+            _ => None,
+        };
+
+        let mut outcomes = vec![];
+        for (recipients, error, _) in failed {
+            outcomes.push(SpoolOutcome::Failed {
+                id: entry.id.clone(),
+                recipients,
+                error,
+                bounce_id: bounce_id.clone(),
+            });
+        }
+
+        if deferred.is_empty() {
+            self.storage.remove(&entry.id).await?;
+            return Ok(outcomes);
+        }
+
+        entry.to = deferred
+            .iter()
+            .flat_map(|(recipients, _)| recipients.iter().cloned())
+            .collect();
+        entry.next_attempt = SystemTime::now() + self.schedule.delay(entry.attempts);
+        entry.last_error = deferred.first().map(|(_, error)| error.to_string());
+        self.storage.store(&entry).await?;
+
+        for (recipients, error) in deferred {
+            outcomes.push(SpoolOutcome::Deferred {
+                id: entry.id.clone(),
+                recipients,
+                error,
+                next_attempt: entry.next_attempt,
+            });
+        }
+        Ok(outcomes)
+    }
+
+    fn bounce(
+        &self,
+        entry: &SpoolEntry,
+        to: &Address,
+        failed: &[(Vec<Address>, Error, &str)],
+    ) -> io::Result<SpoolEntry> {
+        let from = Address::new("MAILER-DAEMON", &self.reporting_mta)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+
+        let mut dsn = Dsn::new(&self.reporting_mta, from, to.clone(), &entry.message)
+            .arrival_date(entry.created);
+        for (recipients, error, default_status) in failed {
+            for recipient in recipients {
+                dsn = dsn.recipient(FailedRecipient::from_error(
+                    recipient.clone(),
+                    error,
+                    default_status,
+                ));
+            }
+        }
+
+        let envelope = dsn
+            .envelope()
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        let mut bounce = SpoolEntry::new(&envelope, vec![]);
+        bounce.message = dsn.formatted(&format!("{}@{}", bounce.id, self.reporting_mta));
+        Ok(bounce)
+    }
+}
@@ -0,0 +1,165 @@
+use std::io;
+use std::mem;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use async_stream_packed::{TlsClientUpgrader, Upgrader};
+use async_trait::async_trait;
+use futures_util::io::{AsyncRead, AsyncWrite};
+
+// ref https://github.com/bk-rs/async-stream-packed/blob/master/src/upgradable.rs
+// Same as UpgradableAsyncStream, but the upgraded stream stays reachable (e.g. for the peer
+// certificates).
+pub struct AsyncStream<S, STU>
+where
+    STU: Upgrader<S>,
+{
+    inner: Inner<S, STU>,
+}
+
+enum Inner<S, STU>
+where
+    STU: Upgrader<S>,
+{
+    Pending(S, STU),
+    Upgraded(STU::Output, STU),
+    None,
+}
+
+impl<S, STU> AsyncStream<S, STU>
+where
+    STU: Upgrader<S>,
+{
+    pub fn new(stream: S, upgrader: STU) -> Self {
+        Self {
+            inner: Inner::Pending(stream, upgrader),
+        }
+    }
+
+    pub fn with_upgraded_stream_and_upgrader(stream: STU::Output, upgrader: STU) -> Self {
+        Self {
+            inner: Inner::Upgraded(stream, upgrader),
+        }
+    }
+
+    pub fn is_upgraded(&self) -> bool {
+        matches!(self.inner, Inner::Upgraded(_, _))
+    }
+
+    pub fn get_upgraded_ref(&self) -> Option<&STU::Output> {
+        match &self.inner {
+            Inner::Upgraded(s, _) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn upgrade_required(&self) -> bool {
+        match &self.inner {
+            Inner::Pending(_, upgrader) => upgrader.upgrade_required(),
+            Inner::Upgraded(_, _) => false,
+            Inner::None => panic!("never"),
+        }
+    }
+
+    pub async fn upgrade(&mut self) -> io::Result<()> {
+        match mem::replace(&mut self.inner, Inner::None) {
+            Inner::Pending(stream, mut upgrader) => {
+                if !upgrader.upgrade_required() {
+                    self.inner = Inner::Pending(stream, upgrader);
+                    return Err(io::Error::other("upgrade not required"));
+                }
+                let stream = upgrader.upgrade(stream).await?;
+                self.inner = Inner::Upgraded(stream, upgrader);
+                Ok(())
+            }
+            inner @ Inner::Upgraded(_, _) => {
+                self.inner = inner;
+                Err(io::Error::other("already upgraded"))
+            }
+            Inner::None => panic!("never"),
+        }
+    }
+}
+
+impl<S, STU> AsyncWrite for AsyncStream<S, STU>
+where
+    STU: Upgrader<S> + Unpin,
+    S: AsyncWrite + Unpin,
+    STU::Output: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &[u8],
+    ) -> Poll<Result<usize, io::Error>> {
+        match &mut self.get_mut().inner {
+            Inner::Pending(s, _) => Pin::new(s).poll_write(cx, buf),
+            Inner::Upgraded(s, _) => Pin::new(s).poll_write(cx, buf),
+            Inner::None => panic!("never"),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), io::Error>> {
+        match &mut self.get_mut().inner {
+            Inner::Pending(s, _) => Pin::new(s).poll_flush(cx),
+            Inner::Upgraded(s, _) => Pin::new(s).poll_flush(cx),
+            Inner::None => panic!("never"),
+        }
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), io::Error>> {
+        match &mut self.get_mut().inner {
+            Inner::Pending(s, _) => Pin::new(s).poll_close(cx),
+            Inner::Upgraded(s, _) => Pin::new(s).poll_close(cx),
+            Inner::None => panic!("never"),
+        }
+    }
+}
+
+impl<S, STU> AsyncRead for AsyncStream<S, STU>
+where
+    STU: Upgrader<S> + Unpin,
+    S: AsyncRead + Unpin,
+    STU::Output: AsyncRead + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<Result<usize, io::Error>> {
+        match &mut self.get_mut().inner {
+            Inner::Pending(s, _) => Pin::new(s).poll_read(cx, buf),
+            Inner::Upgraded(s, _) => Pin::new(s).poll_read(cx, buf),
+            Inner::None => panic!("never"),
+        }
+    }
+}
+
+//
+//
+//
+
+/// For plaintext only streams (e.g. Unix sockets), STARTTLS is not attempted.
+#[derive(Default, Clone, Copy, Debug)]
+pub struct NoTls;
+
+#[async_trait]
+impl<S> Upgrader<S> for NoTls
+where
+    S: Send + 'static,
+{
+    type Output = S;
+
+    async fn upgrade(&mut self, _: S) -> io::Result<Self::Output> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "TLS is disabled for this connection",
+        ))
+    }
+
+    fn upgrade_required(&self) -> bool {
+        false
+    }
+}
+
+impl<S> TlsClientUpgrader<S> for NoTls where S: Send + 'static {}
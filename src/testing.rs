@@ -0,0 +1,535 @@
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use futures_util::io::{AsyncRead, AsyncWrite};
+
+use crate::connection::AsyncConnection;
+use crate::stream::NoTls;
+use crate::verification::{CertificateChain, PeerCertificates};
+
+const AUTH_LOGIN_USERNAME: &str = "334 VXNlcm5hbWU6";
+const AUTH_LOGIN_PASSWORD: &str = "334 UGFzc3dvcmQ6";
+
+#[derive(Clone, Debug)]
+pub enum MockAction {
+    Reply(String),
+    /// Closes the connection instead of replying.
+    Disconnect,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MockDirection {
+    Client,
+    Server,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct MockLine {
+    pub direction: MockDirection,
+    pub line: String,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct MockMessage {
+    pub from: String,
+    pub to: Vec<String>,
+    /// Dot-unstuffed, without the final `.` line.
+    pub data: Vec<u8>,
+}
+
+#[derive(Default, Debug)]
+struct Recorded {
+    transcript: Vec<MockLine>,
+    messages: Vec<MockMessage>,
+    connections: usize,
+}
+
+#[derive(Debug)]
+struct Script {
+    greeting: MockAction,
+    greeting_delay: Duration,
+    hostname: String,
+    extensions: Vec<String>,
+    auth_reply: String,
+    replies: Mutex<HashMap<String, VecDeque<MockAction>>>,
+    delays: HashMap<String, Duration>,
+    certificates: Option<CertificateChain>,
+}
+
+/// Scripted SMTP server, each `stream` is a new in-memory connection to it. The per-command
+/// replies are shared by all the connections and used once, in order, before the defaults.
+///
+/// Commands are matched by their verb, e.g. `"RCPT"`, and `"."` for the end of the DATA.
+pub struct MockServer {
+    script: Arc<Script>,
+    recorded: Arc<Mutex<Recorded>>,
+}
+
+impl Default for MockServer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MockServer {
+    pub fn new() -> Self {
+        Self {
+            script: Arc::new(Script {
+                greeting: MockAction::Reply("220 mock.localhost ESMTP".to_owned()),
+                greeting_delay: Duration::from_secs(0),
+                hostname: "mock.localhost".to_owned(),
+                extensions: vec![
+                    "8BITMIME".to_owned(),
+                    "AUTH PLAIN LOGIN".to_owned(),
+                    "PIPELINING".to_owned(),
+                ],
+                auth_reply: "235 2.7.0 Authentication successful".to_owned(),
+                replies: Mutex::new(HashMap::new()),
+                delays: HashMap::new(),
+                certificates: None,
+            }),
+            recorded: Arc::new(Mutex::new(Recorded::default())),
+        }
+    }
+
+    fn script_mut(&mut self) -> &mut Script {
+        Arc::get_mut(&mut self.script).expect("configure the mock server before connecting")
+    }
+
+    pub fn greeting(mut self, greeting: impl Into<String>) -> Self {
+        self.script_mut().greeting = MockAction::Reply(greeting.into());
+        self
+    }
+
+    /// Closes the connections right away, without a greeting.
+    pub fn disconnect_on_connect(mut self) -> Self {
+        self.script_mut().greeting = MockAction::Disconnect;
+        self
+    }
+
+    pub fn greeting_delay(mut self, delay: Duration) -> Self {
+        self.script_mut().greeting_delay = delay;
+        self
+    }
+
+    pub fn hostname(mut self, hostname: impl Into<String>) -> Self {
+        self.script_mut().hostname = hostname.into();
+        self
+    }
+
+    /// Replaces the EHLO keywords, e.g. `["SIZE 1000", "STARTTLS"]`.
+    pub fn extensions<I, E>(mut self, extensions: I) -> Self
+    where
+        I: IntoIterator<Item = E>,
+        E: Into<String>,
+    {
+        self.script_mut().extensions = extensions.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// The final AUTH reply, after the LOGIN challenges.
+    pub fn auth_reply(mut self, reply: impl Into<String>) -> Self {
+        self.script_mut().auth_reply = reply.into();
+        self
+    }
+
+    pub fn reply(self, verb: &str, reply: impl Into<String>) -> Self {
+        self.action(verb, MockAction::Reply(reply.into()))
+    }
+
+    pub fn disconnect_on(self, verb: &str) -> Self {
+        self.action(verb, MockAction::Disconnect)
+    }
+
+    pub fn action(mut self, verb: &str, action: MockAction) -> Self {
+        self.script_mut()
+            .replies
+            .get_mut()
+            .expect("never")
+            .entry(verb.to_ascii_uppercase())
+            .or_default()
+            .push_back(action);
+        self
+    }
+
+    /// Every reply to `verb` is delayed.
+    pub fn delay(mut self, verb: &str, delay: Duration) -> Self {
+        self.script_mut()
+            .delays
+            .insert(verb.to_ascii_uppercase(), delay);
+        self
+    }
+
+    /// Returned by `PeerCertificates` of the streams, the leaf first.
+    pub fn peer_certificates(mut self, chain: CertificateChain) -> Self {
+        self.script_mut().certificates = Some(chain);
+        self
+    }
+
+    pub fn stream(&self) -> MockStream {
+        self.recorded.lock().expect("never").connections += 1;
+        MockStream::new(self.script.clone(), self.recorded.clone())
+    }
+
+    pub fn connection(&self) -> AsyncConnection<MockStream, NoTls> {
+        AsyncConnection::plain(self.stream())
+    }
+
+    pub fn transcript(&self) -> Vec<MockLine> {
+        self.recorded.lock().expect("never").transcript.clone()
+    }
+
+    /// The client lines only.
+    pub fn commands(&self) -> Vec<String> {
+        self.transcript()
+            .into_iter()
+            .filter(|line| line.direction == MockDirection::Client)
+            .map(|line| line.line)
+            .collect()
+    }
+
+    pub fn messages(&self) -> Vec<MockMessage> {
+        self.recorded.lock().expect("never").messages.clone()
+    }
+
+    pub fn connections(&self) -> usize {
+        self.recorded.lock().expect("never").connections
+    }
+}
+
+//
+//
+//
+enum Out {
+    Data(Vec<u8>),
+    Close,
+}
+
+enum State {
+    Command,
+    Data(Vec<u8>),
+    AuthLogin(u8),
+    Closed,
+}
+
+pub struct MockStream {
+    script: Arc<Script>,
+    recorded: Arc<Mutex<Recorded>>,
+    state: State,
+    input: Vec<u8>,
+    output: VecDeque<(Instant, Out)>,
+    ready_at: Instant,
+    read_waker: Option<Waker>,
+    timer: Option<Timer>,
+    closed: bool,
+    from: String,
+    to: Vec<String>,
+}
+
+impl MockStream {
+    fn new(script: Arc<Script>, recorded: Arc<Mutex<Recorded>>) -> Self {
+        let mut stream = Self {
+            script,
+            recorded,
+            state: State::Command,
+            input: vec![],
+            output: VecDeque::new(),
+            ready_at: Instant::now(),
+            read_waker: None,
+            timer: None,
+            closed: false,
+            from: String::new(),
+            to: vec![],
+        };
+
+        let greeting = stream.script.greeting.clone();
+        stream.respond(stream.script.greeting_delay, greeting);
+        stream
+    }
+
+    fn record(&self, direction: MockDirection, line: &str) {
+        self.recorded
+            .lock()
+            .expect("never")
+            .transcript
+            .push(MockLine {
+                direction,
+                line: line.to_owned(),
+            });
+    }
+
+    fn respond(&mut self, delay: Duration, action: MockAction) {
+        self.ready_at = self.ready_at.max(Instant::now()) + delay;
+        match action {
+            MockAction::Reply(reply) => {
+                let reply = reply.trim_end_matches("\r\n");
+                for line in reply.split("\r\n") {
+                    self.record(MockDirection::Server, line);
+                }
+                self.output
+                    .push_back((self.ready_at, Out::Data(format!("{}\r\n", reply).into())));
+            }
+            MockAction::Disconnect => {
+                self.output.push_back((self.ready_at, Out::Close));
+                self.state = State::Closed;
+            }
+        }
+    }
+
+    // Whether the reply is positive
+    fn reply_to(&mut self, verb: &str, default: String) -> bool {
+        let action = self
+            .script
+            .replies
+            .lock()
+            .expect("never")
+            .get_mut(verb)
+            .and_then(VecDeque::pop_front)
+            .unwrap_or(MockAction::Reply(default));
+        let delay = self.script.delays.get(verb).cloned().unwrap_or_default();
+
+        let positive = matches!(&action, MockAction::Reply(reply) if reply.starts_with(['2', '3']));
+        self.respond(delay, action);
+
+        if positive {
+            match verb {
+                "DATA" => self.state = State::Data(vec![]),
+                "QUIT" => self.respond(Duration::from_secs(0), MockAction::Disconnect),
+                _ => {}
+            }
+        }
+        positive
+    }
+
+    fn process(&mut self) {
+        while let Some(i) = self.input.windows(2).position(|w| w == b"\r\n") {
+            let line: Vec<u8> = self.input.drain(..i + 2).collect();
+            let line = &line[..line.len() - 2];
+
+            match &mut self.state {
+                State::Closed => return,
+                State::Data(data) => {
+                    if line == b"." {
+                        let message = MockMessage {
+                            from: self.from.clone(),
+                            to: self.to.drain(..).collect(),
+                            data: std::mem::take(data),
+                        };
+                        self.state = State::Command;
+                        self.record(MockDirection::Client, ".");
+
+                        let mut recorded = self.recorded.lock().expect("never");
+                        recorded.messages.push(message);
+                        let id = recorded.messages.len();
+                        drop(recorded);
+
+                        let _ = self.reply_to(".", format!("250 2.0.0 Ok: queued as MOCK{}", id));
+                    } else {
+                        // ref https://tools.ietf.org/html/rfc5321#section-4.5.2
+                        let line = line.strip_prefix(b".").unwrap_or(line);
+                        data.extend_from_slice(line);
+                        data.extend_from_slice(b"\r\n");
+                    }
+                }
+                State::AuthLogin(step) => {
+                    let step = *step;
+                    self.record(MockDirection::Client, &String::from_utf8_lossy(line));
+                    if step == 0 {
+                        self.state = State::AuthLogin(1);
+                        self.respond(
+                            Duration::from_secs(0),
+                            MockAction::Reply(AUTH_LOGIN_PASSWORD.to_owned()),
+                        );
+                    } else {
+                        self.state = State::Command;
+                        let reply = self.script.auth_reply.clone();
+                        let _ = self.reply_to("AUTH", reply);
+                    }
+                }
+                State::Command => {
+                    let line = String::from_utf8_lossy(line).into_owned();
+                    self.record(MockDirection::Client, &line);
+                    self.command(&line);
+                }
+            }
+        }
+    }
+
+    fn command(&mut self, line: &str) {
+        let mut parts = line.splitn(2, ' ');
+        let verb = parts.next().unwrap_or_default().to_ascii_uppercase();
+        let argument = parts.next().unwrap_or_default().trim();
+
+        let default = match verb.as_str() {
+            "EHLO" | "LHLO" => {
+                let mut lines = vec![self.script.hostname.clone()];
+                lines.extend(self.script.extensions.iter().cloned());
+                let last = lines.len() - 1;
+                lines
+                    .iter()
+                    .enumerate()
+                    .map(|(i, line)| format!("250{}{}", if i == last { ' ' } else { '-' }, line))
+                    .collect::<Vec<_>>()
+                    .join("\r\n")
+            }
+            "HELO" => format!("250 {}", self.script.hostname),
+            "MAIL" => {
+                self.from = path(argument);
+                self.to.clear();
+                "250 2.1.0 Ok".to_owned()
+            }
+            "RCPT" => "250 2.1.5 Ok".to_owned(),
+            "DATA" => "354 End data with <CR><LF>.<CR><LF>".to_owned(),
+            "RSET" => {
+                self.from.clear();
+                self.to.clear();
+                "250 2.0.0 Ok".to_owned()
+            }
+            "NOOP" => "250 2.0.0 Ok".to_owned(),
+            "QUIT" => "221 2.0.0 Bye".to_owned(),
+            "STARTTLS" => "454 4.7.0 TLS not available".to_owned(),
+            "AUTH" => {
+                let mechanism = argument.split(' ').next().unwrap_or_default();
+                if mechanism.eq_ignore_ascii_case("LOGIN") && !argument.contains(' ') {
+                    self.state = State::AuthLogin(0);
+                    self.respond(
+                        Duration::from_secs(0),
+                        MockAction::Reply(AUTH_LOGIN_USERNAME.to_owned()),
+                    );
+                    return;
+                }
+                self.script.auth_reply.clone()
+            }
+            _ => "502 5.5.2 Command not recognized".to_owned(),
+        };
+
+        if self.reply_to(&verb, default) && verb == "RCPT" {
+            self.to.push(path(argument));
+        }
+    }
+}
+
+// One thread per delayed reply, the polls in between only replace the waker
+struct Timer {
+    deadline: Instant,
+    waker: Arc<Mutex<Option<Waker>>>,
+}
+
+impl Timer {
+    fn start(deadline: Instant, waker: &Waker) -> Self {
+        let timer = Self {
+            deadline,
+            waker: Arc::new(Mutex::new(Some(waker.clone()))),
+        };
+        let shared = timer.waker.clone();
+        thread::spawn(move || {
+            thread::sleep(deadline.saturating_duration_since(Instant::now()));
+            if let Some(waker) = shared.lock().expect("never").take() {
+                waker.wake();
+            }
+        });
+        timer
+    }
+
+    fn set_waker(&self, waker: &Waker) {
+        *self.waker.lock().expect("never") = Some(waker.clone());
+    }
+}
+
+// `FROM:<a@example.com> SIZE=1` to `a@example.com`
+fn path(argument: &str) -> String {
+    let argument = argument.split_once(':').map(|(_, p)| p).unwrap_or(argument);
+    let argument = argument.trim_start();
+    match (argument.find('<'), argument.find('>')) {
+        (Some(start), Some(end)) if start < end => argument[start + 1..end].to_owned(),
+        _ => argument.split(' ').next().unwrap_or_default().to_owned(),
+    }
+}
+
+impl PeerCertificates for MockStream {
+    fn peer_certificates(&self) -> Option<CertificateChain> {
+        self.script.certificates.clone()
+    }
+}
+
+impl AsyncRead for MockStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<Result<usize, io::Error>> {
+        let this = self.get_mut();
+
+        let (ready_at, out) = match this.output.front_mut() {
+            Some(front) => front,
+            None if this.closed => return Poll::Ready(Ok(0)),
+            None => {
+                this.read_waker = Some(cx.waker().clone());
+                return Poll::Pending;
+            }
+        };
+
+        if *ready_at > Instant::now() {
+            match &this.timer {
+                Some(timer) if timer.deadline == *ready_at => timer.set_waker(cx.waker()),
+                _ => this.timer = Some(Timer::start(*ready_at, cx.waker())),
+            }
+            return Poll::Pending;
+        }
+
+        match out {
+            Out::Data(data) => {
+                let n = buf.len().min(data.len());
+                buf[..n].copy_from_slice(&data[..n]);
+                data.drain(..n);
+                if data.is_empty() {
+                    this.output.pop_front();
+                }
+                Poll::Ready(Ok(n))
+            }
+            Out::Close => {
+                this.output.clear();
+                this.closed = true;
+                Poll::Ready(Ok(0))
+            }
+        }
+    }
+}
+
+impl AsyncWrite for MockStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context,
+        buf: &[u8],
+    ) -> Poll<Result<usize, io::Error>> {
+        let this = self.get_mut();
+        if this.closed || matches!(this.state, State::Closed) {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+
+        this.input.extend_from_slice(buf);
+        this.process();
+        if !this.output.is_empty() {
+            if let Some(waker) = this.read_waker.take() {
+                waker.wake();
+            }
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<Result<(), io::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<Result<(), io::Error>> {
+        let this = self.get_mut();
+        this.closed = true;
+        this.state = State::Closed;
+        Poll::Ready(Ok(()))
+    }
+}
@@ -0,0 +1,205 @@
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures_util::io::{AsyncRead, AsyncWrite};
+use futures_util::ready;
+use tokio::io::{AsyncRead as TokioAsyncRead, AsyncWrite as TokioAsyncWrite, ReadBuf};
+
+#[cfg(any(feature = "tokio_rustls", feature = "tokio_native_tls"))]
+use crate::verification::{CertificateChain, PeerCertificates};
+
+/// Tokio stream as a futures-io stream, what `AsyncConnection` and the upgraders work with.
+pub struct TokioStream<T> {
+    inner: T,
+}
+
+impl<T> TokioStream<T> {
+    pub fn new(inner: T) -> Self {
+        Self { inner }
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T> AsyncRead for TokioStream<T>
+where
+    T: TokioAsyncRead + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<Result<usize, io::Error>> {
+        let mut read_buf = ReadBuf::new(buf);
+        ready!(Pin::new(&mut self.get_mut().inner).poll_read(cx, &mut read_buf))?;
+        Poll::Ready(Ok(read_buf.filled().len()))
+    }
+}
+
+impl<T> AsyncWrite for TokioStream<T>
+where
+    T: TokioAsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &[u8],
+    ) -> Poll<Result<usize, io::Error>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), io::Error>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), io::Error>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(any(feature = "tokio_rustls", feature = "tokio_native_tls"))]
+impl<T> PeerCertificates for TokioStream<T>
+where
+    T: PeerCertificates,
+{
+    fn peer_certificates(&self) -> Option<CertificateChain> {
+        self.inner.peer_certificates()
+    }
+}
+
+//
+//
+//
+#[cfg(feature = "tokio_rustls")]
+pub use self::tokio_rustls_client::TokioRustlsClientTlsUpgrader;
+
+#[cfg(feature = "tokio_rustls")]
+mod tokio_rustls_client {
+    use std::io;
+    use std::sync::Arc;
+
+    use async_stream_packed::{TlsClientUpgrader, Upgrader};
+    use async_trait::async_trait;
+    use rustls::{ClientConfig, Session};
+    use tokio::io::{AsyncRead, AsyncWrite};
+    use tokio_rustls::{client::TlsStream, TlsConnector};
+    use webpki::DNSNameRef;
+
+    use super::TokioStream;
+    use crate::verification::{CertificateChain, PeerCertificates};
+
+    /// The config can come from `RustlsClientTlsUpgraderBuilder::build_config`.
+    pub struct TokioRustlsClientTlsUpgrader {
+        connector: TlsConnector,
+        server_name: String,
+    }
+
+    impl TokioRustlsClientTlsUpgrader {
+        pub fn new(config: Arc<ClientConfig>, server_name: String) -> Self {
+            Self {
+                connector: config.into(),
+                server_name,
+            }
+        }
+    }
+
+    #[async_trait]
+    impl<T> Upgrader<TokioStream<T>> for TokioRustlsClientTlsUpgrader
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        type Output = TokioStream<TlsStream<T>>;
+
+        async fn upgrade(&mut self, stream: TokioStream<T>) -> io::Result<Self::Output> {
+            let domain = DNSNameRef::try_from_ascii_str(&self.server_name)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+            let stream = self.connector.connect(domain, stream.into_inner()).await?;
+            Ok(TokioStream::new(stream))
+        }
+    }
+
+    impl<T> TlsClientUpgrader<TokioStream<T>> for TokioRustlsClientTlsUpgrader where
+        T: AsyncRead + AsyncWrite + Unpin + Send + 'static
+    {
+    }
+
+    impl<T> PeerCertificates for TlsStream<T> {
+        fn peer_certificates(&self) -> Option<CertificateChain> {
+            let (_, session) = self.get_ref();
+            session
+                .get_peer_certificates()
+                .map(|certificates| certificates.into_iter().map(|c| c.0).collect())
+        }
+    }
+}
+
+#[cfg(feature = "tokio_native_tls")]
+pub use self::tokio_native_tls_client::TokioNativeTlsClientTlsUpgrader;
+
+#[cfg(feature = "tokio_native_tls")]
+mod tokio_native_tls_client {
+    use std::io;
+
+    use async_stream_packed::{TlsClientUpgrader, Upgrader};
+    use async_trait::async_trait;
+    use tokio::io::{AsyncRead, AsyncWrite};
+    use tokio_native_tls::{TlsConnector, TlsStream};
+
+    use super::TokioStream;
+    use crate::verification::{CertificateChain, PeerCertificates};
+
+    pub struct TokioNativeTlsClientTlsUpgrader {
+        connector: TlsConnector,
+        domain: String,
+    }
+
+    impl TokioNativeTlsClientTlsUpgrader {
+        pub fn new(connector: TlsConnector, domain: String) -> Self {
+            Self { connector, domain }
+        }
+    }
+
+    #[async_trait]
+    impl<T> Upgrader<TokioStream<T>> for TokioNativeTlsClientTlsUpgrader
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        type Output = TokioStream<TlsStream<T>>;
+
+        async fn upgrade(&mut self, stream: TokioStream<T>) -> io::Result<Self::Output> {
+            let stream = self
+                .connector
+                .connect(&self.domain, stream.into_inner())
+                .await
+                .map_err(io::Error::other)?;
+            Ok(TokioStream::new(stream))
+        }
+    }
+
+    impl<T> TlsClientUpgrader<TokioStream<T>> for TokioNativeTlsClientTlsUpgrader where
+        T: AsyncRead + AsyncWrite + Unpin + Send + 'static
+    {
+    }
+
+    // native-tls only exposes the leaf certificate
+    impl<T> PeerCertificates for TlsStream<T>
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+    {
+        fn peer_certificates(&self) -> Option<CertificateChain> {
+            let certificate = self.get_ref().peer_certificate().ok()??;
+            certificate.to_der().ok().map(|der| vec![der])
+        }
+    }
+}
@@ -0,0 +1,12 @@
+// Secrets (credentials, AUTH payloads) and message contents must never be in the fields
+#[cfg(feature = "tracing")]
+macro_rules! smtp_event {
+    ($($arg:tt)+) => {
+        tracing::debug!($($arg)+)
+    };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! smtp_event {
+    ($($arg:tt)+) => {};
+}
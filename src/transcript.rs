@@ -0,0 +1,97 @@
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub(crate) const REDACTED: &str = "<redacted>";
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TranscriptDirection {
+    Sent,
+    Received,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct TranscriptEntry {
+    pub timestamp: SystemTime,
+    pub direction: TranscriptDirection,
+    /// Without the line ending.
+    pub line: String,
+}
+
+// `1603017600.123 C: EHLO example.com`
+impl fmt::Display for TranscriptEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let since_epoch = self
+            .timestamp
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        write!(
+            f,
+            "{}.{:03} {}: {}",
+            since_epoch.as_secs(),
+            since_epoch.subsec_millis(),
+            match self.direction {
+                TranscriptDirection::Sent => "C",
+                TranscriptDirection::Received => "S",
+            },
+            self.line
+        )
+    }
+}
+
+pub trait TranscriptHook: Send + Sync {
+    fn record(&self, entry: &TranscriptEntry);
+}
+
+impl<F> TranscriptHook for F
+where
+    F: Fn(&TranscriptEntry) + Send + Sync,
+{
+    fn record(&self, entry: &TranscriptEntry) {
+        self(entry)
+    }
+}
+
+/// Keeps the entries in memory, clones share them.
+#[derive(Clone, Default, Debug)]
+pub struct Transcript {
+    entries: Arc<Mutex<Vec<TranscriptEntry>>>,
+}
+
+impl Transcript {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn entries(&self) -> Vec<TranscriptEntry> {
+        self.entries.lock().expect("never").clone()
+    }
+
+    pub fn clear(&self) {
+        self.entries.lock().expect("never").clear();
+    }
+}
+
+impl TranscriptHook for Transcript {
+    fn record(&self, entry: &TranscriptEntry) {
+        self.entries.lock().expect("never").push(entry.clone());
+    }
+}
+
+impl fmt::Display for Transcript {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for entry in self.entries.lock().expect("never").iter() {
+            writeln!(f, "{}", entry)?;
+        }
+        Ok(())
+    }
+}
+
+// `AUTH PLAIN AGZvbwBiYXI=` to `AUTH PLAIN <redacted>`
+pub(crate) fn redact_auth(line: &str) -> String {
+    let mut parts = line.splitn(3, ' ');
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(verb), Some(mechanism), Some(_)) => format!("{} {} {}", verb, mechanism, REDACTED),
+        _ => line.to_owned(),
+    }
+}
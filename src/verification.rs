@@ -0,0 +1,559 @@
+use std::result;
+use std::str::FromStr;
+
+use lettre::transport::smtp::error::Error;
+use sha2::{Digest, Sha256, Sha512};
+
+/// DER encoded certificates presented by the server, leaf first.
+pub type CertificateChain = Vec<Vec<u8>>;
+
+pub trait PeerCertificates {
+    fn peer_certificates(&self) -> Option<CertificateChain>;
+}
+
+#[cfg(feature = "async_native_tls")]
+impl<S> PeerCertificates for async_native_tls::TlsStream<S>
+where
+    S: futures_util::io::AsyncRead + futures_util::io::AsyncWrite + Unpin,
+{
+    // native-tls only exposes the leaf certificate
+    fn peer_certificates(&self) -> Option<CertificateChain> {
+        let certificate = self.peer_certificate().ok()??;
+        certificate.to_der().ok().map(|der| vec![der])
+    }
+}
+
+/// Invoked after the TLS upgrade, an `Err` rejects the connection.
+///
+/// It runs after the upgrader's own PKIX verification, which it can only restrict. A DANE-EE
+/// record or a pin on a self-signed certificate matches only when the upgrader accepts any
+/// certificate, e.g. `RustlsClientTlsUpgraderBuilder::dangerous_accept_invalid_certs` or
+/// native-tls's `danger_accept_invalid_certs`.
+pub trait PeerCertificateVerifier: Send + Sync {
+    fn verify(&self, chain: &[Vec<u8>]) -> result::Result<(), Error>;
+}
+
+impl<F> PeerCertificateVerifier for F
+where
+    F: Fn(&[Vec<u8>]) -> result::Result<(), Error> + Send + Sync,
+{
+    fn verify(&self, chain: &[Vec<u8>]) -> result::Result<(), Error> {
+        self(chain)
+    }
+}
+
+//
+//
+//
+
+/// SHA-256 pins of the SubjectPublicKeyInfo, matched against the leaf certificate only. The
+/// other certificates sent by the server are not verified to be on its path, appending the
+/// pinned one must not pass.
+#[derive(Default, Clone, Debug)]
+pub struct PinnedPublicKeys {
+    pins: Vec<[u8; 32]>,
+}
+
+impl PinnedPublicKeys {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn pin_sha256(mut self, digest: [u8; 32]) -> Self {
+        self.pins.push(digest);
+        self
+    }
+
+    pub fn pin_certificate(self, certificate: &[u8]) -> result::Result<Self, Error> {
+        let spki = der::subject_public_key_info(certificate)
+            .ok_or(Error::Client("Invalid certificate"))?;
+        let mut digest = [0; 32];
+        digest.copy_from_slice(&Sha256::digest(spki));
+        Ok(self.pin_sha256(digest))
+    }
+}
+
+impl PeerCertificateVerifier for PinnedPublicKeys {
+    fn verify(&self, chain: &[Vec<u8>]) -> result::Result<(), Error> {
+        let matched = chain
+            .first()
+            .and_then(|leaf| der::subject_public_key_info(leaf))
+            .is_some_and(|spki| {
+                let digest = Sha256::digest(spki);
+                self.pins.iter().any(|pin| pin[..] == digest[..])
+            });
+
+        if matched {
+            Ok(())
+        } else {
+            Err(Error::Client(
+                "The peer certificate does not match a pinned public key",
+            ))
+        }
+    }
+}
+
+//
+//
+//
+
+// ref https://tools.ietf.org/html/rfc6698#section-2.1
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TlsaUsage {
+    PkixTa,
+    PkixEe,
+    DaneTa,
+    DaneEe,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TlsaSelector {
+    FullCertificate,
+    SubjectPublicKeyInfo,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TlsaMatchingType {
+    Full,
+    Sha256,
+    Sha512,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct TlsaRecord {
+    pub usage: TlsaUsage,
+    pub selector: TlsaSelector,
+    pub matching_type: TlsaMatchingType,
+    pub data: Vec<u8>,
+}
+
+impl TlsaRecord {
+    pub fn matches(&self, certificate: &[u8]) -> bool {
+        let selected = match self.selector {
+            TlsaSelector::FullCertificate => certificate,
+            TlsaSelector::SubjectPublicKeyInfo => match der::subject_public_key_info(certificate) {
+                Some(spki) => spki,
+                None => return false,
+            },
+        };
+
+        match self.matching_type {
+            TlsaMatchingType::Full => selected == &self.data[..],
+            TlsaMatchingType::Sha256 => Sha256::digest(selected)[..] == self.data[..],
+            TlsaMatchingType::Sha512 => Sha512::digest(selected)[..] == self.data[..],
+        }
+    }
+}
+
+// Presentation format, e.g. `3 1 1 0123...cdef`
+// ref https://tools.ietf.org/html/rfc6698#section-2.2
+impl FromStr for TlsaRecord {
+    type Err = Error;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        let mut fields = s.split_whitespace();
+
+        let usage = match fields.next() {
+            Some("0") => TlsaUsage::PkixTa,
+            Some("1") => TlsaUsage::PkixEe,
+            Some("2") => TlsaUsage::DaneTa,
+            Some("3") => TlsaUsage::DaneEe,
+            _ => return Err(Error::Client("Invalid TLSA certificate usage")),
+        };
+        let selector = match fields.next() {
+            Some("0") => TlsaSelector::FullCertificate,
+            Some("1") => TlsaSelector::SubjectPublicKeyInfo,
+            _ => return Err(Error::Client("Invalid TLSA selector")),
+        };
+        let matching_type = match fields.next() {
+            Some("0") => TlsaMatchingType::Full,
+            Some("1") => TlsaMatchingType::Sha256,
+            Some("2") => TlsaMatchingType::Sha512,
+            _ => return Err(Error::Client("Invalid TLSA matching type")),
+        };
+
+        let hex: String = fields.collect();
+        if hex.is_empty() || hex.len() % 2 != 0 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(Error::Client("Invalid TLSA certificate association data"));
+        }
+        let data = hex
+            .as_bytes()
+            .chunks(2)
+            .map(|pair| (hex_digit(pair[0]) << 4) | hex_digit(pair[1]))
+            .collect();
+
+        Ok(Self {
+            usage,
+            selector,
+            matching_type,
+            data,
+        })
+    }
+}
+
+fn hex_digit(digit: u8) -> u8 {
+    match digit {
+        b'0'..=b'9' => digit - b'0',
+        b'a'..=b'f' => digit - b'a' + 10,
+        _ => digit - b'A' + 10,
+    }
+}
+
+/// DANE-EE(3) and DANE-TA(2) matching, PKIX-TA(0) and PKIX-EE(1) records are ignored. The
+/// upgrader must skip its PKIX verification, see `PeerCertificateVerifier`.
+/// ref https://tools.ietf.org/html/rfc7672#section-3.1
+#[cfg(feature = "dane")]
+#[derive(Clone, Debug)]
+pub struct Dane {
+    server_name: String,
+    records: Vec<TlsaRecord>,
+}
+
+#[cfg(feature = "dane")]
+impl Dane {
+    /// `server_name` is the MX host name, it is checked against the certificate for DANE-TA only.
+    pub fn new(server_name: impl Into<String>, records: Vec<TlsaRecord>) -> Self {
+        Self {
+            server_name: server_name.into(),
+            records,
+        }
+    }
+
+    fn verify_dane_ta(&self, chain: &[Vec<u8>]) -> bool {
+        let leaf = match chain.first() {
+            Some(leaf) => leaf,
+            None => return false,
+        };
+
+        chain.iter().enumerate().skip(1).any(|(i, certificate)| {
+            let matched = self
+                .records
+                .iter()
+                .filter(|record| record.usage == TlsaUsage::DaneTa)
+                .any(|record| record.matches(certificate));
+
+            matched && self.verify_issued_by(leaf, &chain[1..i], certificate)
+        })
+    }
+
+    fn verify_issued_by(&self, leaf: &[u8], intermediates: &[Vec<u8>], anchor: &[u8]) -> bool {
+        use std::time::SystemTime;
+
+        let anchor = match webpki::trust_anchor_util::cert_der_as_trust_anchor(anchor) {
+            Ok(anchor) => anchor,
+            Err(_) => return false,
+        };
+        let end_entity = match webpki::EndEntityCert::from(leaf) {
+            Ok(end_entity) => end_entity,
+            Err(_) => return false,
+        };
+        let time = match webpki::Time::try_from(SystemTime::now()) {
+            Ok(time) => time,
+            Err(_) => return false,
+        };
+        let name = match webpki::DNSNameRef::try_from_ascii_str(&self.server_name) {
+            Ok(name) => name,
+            Err(_) => return false,
+        };
+        let intermediates: Vec<&[u8]> = intermediates.iter().map(|c| &c[..]).collect();
+
+        end_entity
+            .verify_is_valid_tls_server_cert(
+                SUPPORTED_SIG_ALGS,
+                &webpki::TLSServerTrustAnchors(&[anchor]),
+                &intermediates,
+                time,
+            )
+            .is_ok()
+            && end_entity.verify_is_valid_for_dns_name(name).is_ok()
+    }
+}
+
+#[cfg(feature = "dane")]
+impl PeerCertificateVerifier for Dane {
+    fn verify(&self, chain: &[Vec<u8>]) -> result::Result<(), Error> {
+        // DANE-EE ignores names and expiry, only the leaf key matters
+        // ref https://tools.ietf.org/html/rfc7672#section-3.1.1
+        let dane_ee = chain.first().is_some_and(|leaf| {
+            self.records
+                .iter()
+                .filter(|record| record.usage == TlsaUsage::DaneEe)
+                .any(|record| record.matches(leaf))
+        });
+
+        if dane_ee || self.verify_dane_ta(chain) {
+            Ok(())
+        } else {
+            Err(Error::Client(
+                "No TLSA record matches the peer certificates",
+            ))
+        }
+    }
+}
+
+#[cfg(feature = "dane")]
+static SUPPORTED_SIG_ALGS: &[&webpki::SignatureAlgorithm] = &[
+    &webpki::ECDSA_P256_SHA256,
+    &webpki::ECDSA_P256_SHA384,
+    &webpki::ECDSA_P384_SHA256,
+    &webpki::ECDSA_P384_SHA384,
+    &webpki::ED25519,
+    &webpki::RSA_PSS_2048_8192_SHA256_LEGACY_KEY,
+    &webpki::RSA_PSS_2048_8192_SHA384_LEGACY_KEY,
+    &webpki::RSA_PSS_2048_8192_SHA512_LEGACY_KEY,
+    &webpki::RSA_PKCS1_2048_8192_SHA256,
+    &webpki::RSA_PKCS1_2048_8192_SHA384,
+    &webpki::RSA_PKCS1_2048_8192_SHA512,
+    &webpki::RSA_PKCS1_3072_8192_SHA384,
+];
+
+//
+//
+//
+
+pub(crate) mod der {
+    // ref https://tools.ietf.org/html/rfc5280#section-4.2.1.6
+    const SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1d, 0x11];
+
+    // ref https://tools.ietf.org/html/rfc5280#section-4.1
+    pub fn subject_public_key_info(certificate: &[u8]) -> Option<&[u8]> {
+        subject_public_key_info_and_rest(certificate).map(|(spki, _)| spki)
+    }
+
+    /// The dNSName entries of the subjectAltName extension.
+    pub fn dns_names(certificate: &[u8]) -> Option<Vec<&str>> {
+        // issuerUniqueID [1], subjectUniqueID [2], extensions [3] EXPLICIT
+        let (_, mut rest) = subject_public_key_info_and_rest(certificate)?;
+        let mut extensions: &[u8] = &[];
+        while !rest.is_empty() {
+            let (tag, value, next) = read_any(rest)?;
+            if tag == 0xa3 {
+                extensions = read_tlv(value, 0x30)?.1;
+            }
+            rest = next;
+        }
+
+        while !extensions.is_empty() {
+            let (_, extension, next) = read_tlv(extensions, 0x30)?;
+            extensions = next;
+
+            let (_, oid, mut value) = read_tlv(extension, 0x06)?;
+            if oid != SUBJECT_ALT_NAME {
+                continue;
+            }
+            // critical, optional
+            if value.first() == Some(&0x01) {
+                value = read_tlv(value, 0x01)?.2;
+            }
+            let (_, octets, _) = read_tlv(value, 0x04)?;
+            let (_, mut general_names, _) = read_tlv(octets, 0x30)?;
+
+            let mut names = vec![];
+            while !general_names.is_empty() {
+                let (tag, name, next) = read_any(general_names)?;
+                // dNSName [2] IMPLICIT IA5String
+                if tag == 0x82 {
+                    names.push(std::str::from_utf8(name).ok()?);
+                }
+                general_names = next;
+            }
+            return Some(names);
+        }
+        Some(vec![])
+    }
+
+    fn subject_public_key_info_and_rest(certificate: &[u8]) -> Option<(&[u8], &[u8])> {
+        let (_, certificate, _) = read_tlv(certificate, 0x30)?;
+        let (_, mut tbs_certificate, _) = read_tlv(certificate, 0x30)?;
+
+        // version [0] EXPLICIT, optional
+        if tbs_certificate.first() == Some(&0xa0) {
+            tbs_certificate = read_tlv(tbs_certificate, 0xa0)?.2;
+        }
+        // serialNumber
+        tbs_certificate = read_tlv(tbs_certificate, 0x02)?.2;
+        // signature, issuer, validity, subject
+        for _ in 0..4 {
+            tbs_certificate = read_tlv(tbs_certificate, 0x30)?.2;
+        }
+
+        let (spki, _, rest) = read_tlv(tbs_certificate, 0x30)?;
+        Some((spki, rest))
+    }
+
+    // Returns (whole tlv, value, rest)
+    fn read_tlv(input: &[u8], tag: u8) -> Option<(&[u8], &[u8], &[u8])> {
+        if *input.first()? != tag {
+            return None;
+        }
+
+        let (header_len, len) = read_length(input)?;
+        let end = header_len.checked_add(len)?;
+        if input.len() < end {
+            return None;
+        }
+
+        Some((&input[..end], &input[header_len..end], &input[end..]))
+    }
+
+    // Returns (tag, value, rest)
+    fn read_any(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+        let tag = *input.first()?;
+        let (_, value, rest) = read_tlv(input, tag)?;
+        Some((tag, value, rest))
+    }
+
+    // Returns (header length, value length)
+    fn read_length(input: &[u8]) -> Option<(usize, usize)> {
+        let first = *input.get(1)? as usize;
+        if first < 0x80 {
+            return Some((2, first));
+        }
+
+        let n = first & 0x7f;
+        if n == 0 || n > 4 {
+            return None;
+        }
+        let len = input
+            .get(2..2 + n)?
+            .iter()
+            .fold(0_usize, |acc, b| (acc << 8) | *b as usize);
+        Some((2 + n, len))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A self-signed P-256 certificate for mx.example.com
+    const CERTIFICATE: &[u8] = include_bytes!("../tests/keys/certificate.der");
+    const SPKI: &str = "3059301306072a8648ce3d020106082a8648ce3d0301070342000497391de7679e45ac9f3bcc37db0d828dc2904f6214b45c57c507ef32bcbb114e424b22746b484ecf54bb6f9b18a7a3c5c01f7b1e6cc4e9e60c545bc9c08c5839";
+    const SPKI_SHA256: &str = "0adb19f7b8229893bc8d27cb5f81b7cdb58bd3830321ede9014ba67e69d7f369";
+    const SPKI_SHA512: &str = "14df9d29c0aba1b7ec3df51d73661d43e35c2bbe960f0d1fcb2d333218394a651eace40eb0cc97bd0852a8c7dbfe0074997aee6d68ab92ee9a6b0a01f552a06a";
+    const CERTIFICATE_SHA256: &str =
+        "28ca036ac8ffd6f7c8d2ddf7359bee5f67ac041867d1ec9b600bd3ab09c6e883";
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    fn tlsa(record: &str) -> TlsaRecord {
+        record.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_subject_public_key_info() {
+        let spki = der::subject_public_key_info(CERTIFICATE).unwrap();
+        assert_eq!(hex(spki), SPKI);
+    }
+
+    #[test]
+    fn rejects_invalid_der() {
+        assert!(der::subject_public_key_info(b"").is_none());
+        assert!(der::subject_public_key_info(&CERTIFICATE[..CERTIFICATE.len() - 1]).is_none());
+        assert!(der::subject_public_key_info(&CERTIFICATE[4..]).is_none());
+        // Length of length over 4 octets
+        assert!(der::subject_public_key_info(&[0x30, 0x85, 0, 0, 0, 0, 1]).is_none());
+    }
+
+    #[test]
+    fn parses_dns_names() {
+        let certificate = include_bytes!("../tests/keys/mx_certificate.der");
+        assert_eq!(
+            der::dns_names(certificate).unwrap(),
+            vec!["mx.example.com", "*.mx.example.net"]
+        );
+        // No subjectAltName
+        assert_eq!(der::dns_names(CERTIFICATE).unwrap(), Vec::<&str>::new());
+        assert!(der::dns_names(&certificate[..certificate.len() - 1]).is_none());
+    }
+
+    #[test]
+    fn parses_tlsa_records() {
+        let record = tlsa(&format!("3 1 1 {}", SPKI_SHA256.to_uppercase()));
+        assert_eq!(record.usage, TlsaUsage::DaneEe);
+        assert_eq!(record.selector, TlsaSelector::SubjectPublicKeyInfo);
+        assert_eq!(record.matching_type, TlsaMatchingType::Sha256);
+        assert_eq!(hex(&record.data), SPKI_SHA256);
+
+        // The data may be split by whitespace
+        let record = tlsa("2 0 0 0a0b 0c");
+        assert_eq!(record.usage, TlsaUsage::DaneTa);
+        assert_eq!(record.selector, TlsaSelector::FullCertificate);
+        assert_eq!(record.matching_type, TlsaMatchingType::Full);
+        assert_eq!(record.data, vec![0x0a, 0x0b, 0x0c]);
+    }
+
+    #[test]
+    fn rejects_invalid_tlsa_records() {
+        for record in &[
+            "",
+            "4 1 1 00",
+            "3 2 1 00",
+            "3 1 3 00",
+            "3 1 1",
+            "3 1 1 0",
+            "3 1 1 zz",
+            "3 1 1 aéb",
+            "3 1 1 é",
+            "3 1 1 +0",
+        ] {
+            assert!(record.parse::<TlsaRecord>().is_err(), "{}", record);
+        }
+    }
+
+    #[test]
+    fn matches_tlsa_records() {
+        assert!(tlsa(&format!("3 1 1 {}", SPKI_SHA256)).matches(CERTIFICATE));
+        assert!(tlsa(&format!("3 1 2 {}", SPKI_SHA512)).matches(CERTIFICATE));
+        assert!(tlsa(&format!("3 1 0 {}", SPKI)).matches(CERTIFICATE));
+        assert!(tlsa(&format!("3 0 1 {}", CERTIFICATE_SHA256)).matches(CERTIFICATE));
+        assert!(tlsa(&format!("3 0 0 {}", hex(CERTIFICATE))).matches(CERTIFICATE));
+
+        assert!(!tlsa(&format!("3 0 1 {}", SPKI_SHA256)).matches(CERTIFICATE));
+        assert!(!tlsa(&format!("3 1 1 {}", CERTIFICATE_SHA256)).matches(CERTIFICATE));
+        assert!(!tlsa(&format!("3 1 1 {}", SPKI_SHA256)).matches(b"not a certificate"));
+    }
+
+    #[test]
+    fn matches_pinned_public_keys() {
+        let pins = PinnedPublicKeys::new()
+            .pin_certificate(CERTIFICATE)
+            .unwrap();
+        assert!(pins.verify(&[CERTIFICATE.to_vec()]).is_ok());
+        assert!(pins
+            .verify(&[CERTIFICATE.to_vec(), b"intermediate".to_vec()])
+            .is_ok());
+        assert!(pins.verify(&[]).is_err());
+
+        // The pinned certificate appended after another leaf
+        assert!(pins
+            .verify(&[b"intermediate".to_vec(), CERTIFICATE.to_vec()])
+            .is_err());
+        let other = include_bytes!("../tests/keys/mx_certificate.der");
+        assert!(pins
+            .verify(&[other.to_vec(), CERTIFICATE.to_vec()])
+            .is_err());
+        assert!(PinnedPublicKeys::new()
+            .pin_sha256([0; 32])
+            .verify(&[CERTIFICATE.to_vec()])
+            .is_err());
+    }
+
+    #[cfg(feature = "dane")]
+    #[test]
+    fn dane_ee_ignores_the_name() {
+        let dane = Dane::new(
+            "other.example.com",
+            vec![tlsa("2 1 1 00"), tlsa(&format!("3 1 1 {}", SPKI_SHA256))],
+        );
+        assert!(dane.verify(&[CERTIFICATE.to_vec()]).is_ok());
+
+        let dane = Dane::new(
+            "mx.example.com",
+            vec![tlsa(&format!("1 1 1 {}", SPKI_SHA256))],
+        );
+        assert!(dane.verify(&[CERTIFICATE.to_vec()]).is_err());
+    }
+}
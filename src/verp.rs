@@ -0,0 +1,191 @@
+use std::result;
+
+use lettre::address::AddressError;
+use lettre::Address;
+
+/// Variable envelope return paths, the recipient encoded in the reverse-path, e.g.
+/// `bounces+alice=example.com@our.domain` for `bounces@our.domain` and `alice@example.com`,
+/// so a bounce tells which recipient failed.
+// ref https://cr.yp.to/proto/verp.txt
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Verp {
+    delimiter: char,
+    separator: char,
+}
+
+impl Default for Verp {
+    fn default() -> Self {
+        Self {
+            delimiter: '+',
+            separator: '=',
+        }
+    }
+}
+
+impl Verp {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Between the return path's local part and the recipient, defaults to `+`.
+    pub fn delimiter(mut self, delimiter: char) -> Self {
+        self.delimiter = delimiter;
+        self
+    }
+
+    /// Replaces the recipient's `@`, defaults to `=`.
+    pub fn separator(mut self, separator: char) -> Self {
+        self.separator = separator;
+        self
+    }
+
+    /// Fails when the local part would be invalid, e.g. over 64 octets.
+    pub fn encode(
+        &self,
+        return_path: &Address,
+        recipient: &Address,
+    ) -> result::Result<Address, AddressError> {
+        let user = format!(
+            "{}{}{}{}{}",
+            return_path.user, self.delimiter, recipient.user, self.separator, recipient.domain
+        );
+        // ref https://tools.ietf.org/html/rfc5321#section-4.5.3.1.1
+        if user.len() > 64 {
+            return Err(AddressError::InvalidUser);
+        }
+        Address::new(user, return_path.domain.clone())
+    }
+
+    /// The recipient of a bounce to `address`, `None` when it is not one for `return_path`.
+    /// The return path's local part may contain the delimiter.
+    pub fn decode(&self, return_path: &Address, address: &Address) -> Option<Address> {
+        if !address.domain.eq_ignore_ascii_case(&return_path.domain) {
+            return None;
+        }
+        let encoded = address
+            .user
+            .strip_prefix(return_path.user.as_str())?
+            .strip_prefix(self.delimiter)?;
+        // The domain can not have the separator, the recipient's local part can
+        let (recipient_user, recipient_domain) = encoded.rsplit_once(self.separator)?;
+
+        Address::new(recipient_user, recipient_domain).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(address: &str) -> Address {
+        address.parse().unwrap()
+    }
+
+    #[test]
+    fn encodes() {
+        let verp = Verp::new();
+        let return_path = address("bounces@our.example");
+
+        assert_eq!(
+            verp.encode(&return_path, &address("alice@example.com"))
+                .unwrap(),
+            address("bounces+alice=example.com@our.example")
+        );
+        // The separator in the recipient's local part is kept
+        assert_eq!(
+            verp.encode(&return_path, &address("a=b@example.com"))
+                .unwrap(),
+            address("bounces+a=b=example.com@our.example")
+        );
+        assert_eq!(
+            Verp::new()
+                .delimiter('-')
+                .separator('#')
+                .encode(&return_path, &address("alice@example.com"))
+                .unwrap(),
+            address("bounces-alice#example.com@our.example")
+        );
+    }
+
+    #[test]
+    fn encodes_up_to_64_octets() {
+        let verp = Verp::new();
+        let return_path = address("bounces@our.example");
+
+        // bounces + 44 + = example.com
+        let recipient = address(&format!("{}@example.com", "a".repeat(44)));
+        assert_eq!(
+            verp.encode(&return_path, &recipient).unwrap().user.len(),
+            64
+        );
+
+        let recipient = address(&format!("{}@example.com", "a".repeat(45)));
+        assert_eq!(
+            verp.encode(&return_path, &recipient),
+            Err(AddressError::InvalidUser)
+        );
+    }
+
+    #[test]
+    fn decodes() {
+        let verp = Verp::new();
+        let return_path = address("bounces@our.example");
+
+        for recipient in &["alice@example.com", "a=b@example.com", "a+b@example.com"] {
+            let recipient = address(recipient);
+            let encoded = verp.encode(&return_path, &recipient).unwrap();
+            assert_eq!(verp.decode(&return_path, &encoded), Some(recipient));
+        }
+        assert_eq!(
+            verp.decode(
+                &return_path,
+                &address("bounces+alice=example.com@OUR.EXAMPLE")
+            ),
+            Some(address("alice@example.com"))
+        );
+    }
+
+    #[test]
+    fn decodes_return_path_with_delimiter() {
+        let verp = Verp::new();
+        let return_path = address("bounces+list@our.example");
+
+        let encoded = verp
+            .encode(&return_path, &address("alice@example.com"))
+            .unwrap();
+        assert_eq!(
+            encoded,
+            address("bounces+list+alice=example.com@our.example")
+        );
+        assert_eq!(
+            verp.decode(&return_path, &encoded),
+            Some(address("alice@example.com"))
+        );
+    }
+
+    #[test]
+    fn decodes_only_bounces() {
+        let verp = Verp::new();
+        let return_path = address("bounces@our.example");
+
+        for not_a_bounce in &[
+            // A subaddress of another user
+            "user+tag=example.com@our.example",
+            "user+tag@our.example",
+            // Another domain
+            "bounces+alice=example.com@other.example",
+            // The return path itself, or a longer local part
+            "bounces@our.example",
+            "bouncesx+alice=example.com@our.example",
+            // No recipient domain
+            "bounces+alice@our.example",
+        ] {
+            assert_eq!(
+                verp.decode(&return_path, &address(not_a_bounce)),
+                None,
+                "{}",
+                not_a_bounce
+            );
+        }
+    }
+}
@@ -0,0 +1,141 @@
+#![cfg(feature = "testing")]
+
+use std::time::Duration;
+
+use async_io::block_on;
+use async_smtp_lite::lettre::{ClientId, Message};
+use async_smtp_lite::testing::MockServer;
+use async_smtp_lite::{BatchOutcome, BatchSender};
+use futures_util::stream::{self, StreamExt};
+use lettre::transport::smtp::error::Error;
+
+fn message(i: usize) -> Message {
+    Message::builder()
+        .from("sender@example.com".parse().unwrap())
+        .to(format!("rcpt{}@example.com", i).parse().unwrap())
+        .subject(format!("Message {}", i))
+        .body(format!("Hello {}", i))
+        .unwrap()
+}
+
+fn recipient(outcome: &BatchOutcome) -> String {
+    outcome.envelope.to()[0].to_string()
+}
+
+fn send(
+    server: &MockServer,
+    connections: usize,
+    messages_per_connection: usize,
+    messages: usize,
+) -> Vec<BatchOutcome> {
+    let sender = BatchSender::new(|| {
+        let mut connection = server.connection();
+        async move {
+            connection
+                .handshake(false, ClientId::Domain("client.example.com".to_owned()))
+                .await?;
+            Ok::<_, Error>(connection)
+        }
+    })
+    .connections(connections)
+    .messages_per_connection(messages_per_connection);
+
+    block_on(
+        sender
+            .send(stream::iter((0..messages).map(message)))
+            .collect(),
+    )
+}
+
+fn quits(server: &MockServer) -> usize {
+    server
+        .commands()
+        .iter()
+        .filter(|command| *command == "QUIT")
+        .count()
+}
+
+#[test]
+fn spreads_the_messages_over_the_connections() {
+    let server = MockServer::new().delay(".", Duration::from_millis(20));
+
+    let outcomes = send(&server, 3, 100, 6);
+
+    assert_eq!(outcomes.len(), 6);
+    assert!(outcomes.iter().all(|outcome| outcome.result.is_ok()));
+    assert_eq!(server.connections(), 3);
+    assert_eq!(server.messages().len(), 6);
+    assert_eq!(quits(&server), 3);
+}
+
+#[test]
+fn reconnects_after_messages_per_connection() {
+    let server = MockServer::new();
+
+    let outcomes = send(&server, 1, 2, 5);
+
+    assert!(outcomes.iter().all(|outcome| outcome.result.is_ok()));
+    assert_eq!(server.connections(), 3);
+    assert_eq!(server.messages().len(), 5);
+    assert_eq!(quits(&server), 3);
+}
+
+#[test]
+fn continues_after_a_failed_message() {
+    let server = MockServer::new().reply(".", "550 5.7.1 Rejected");
+
+    let outcomes = send(&server, 1, 100, 3);
+
+    assert_eq!(outcomes.len(), 3);
+    assert!(matches!(outcomes[0].result, Err(Error::Permanent(_))));
+    assert!(outcomes[1].result.is_ok());
+    assert!(outcomes[2].result.is_ok());
+    // Replaced after the failure
+    assert_eq!(server.connections(), 2);
+    assert_eq!(server.messages().len(), 3);
+}
+
+#[test]
+fn replaces_a_broken_connection() {
+    let server = MockServer::new().disconnect_on("MAIL");
+
+    let outcomes = send(&server, 1, 100, 3);
+
+    assert_eq!(outcomes.len(), 3);
+    assert!(outcomes[0].result.is_err());
+    assert!(outcomes[1].result.is_ok());
+    assert!(outcomes[2].result.is_ok());
+    assert_eq!(server.connections(), 2);
+    assert_eq!(server.messages().len(), 2);
+}
+
+#[test]
+fn outcomes_match_the_input() {
+    let server = MockServer::new();
+    let outcomes = send(&server, 1, 100, 4);
+    for (i, outcome) in outcomes.iter().enumerate() {
+        assert_eq!(outcome.index, i);
+        assert_eq!(recipient(outcome), format!("rcpt{}@example.com", i));
+    }
+
+    // Several connections, the outcomes come in completion order
+    let server = MockServer::new().delay("MAIL", Duration::from_millis(10));
+    let mut outcomes = send(&server, 4, 100, 8);
+    assert_eq!(outcomes.len(), 8);
+    outcomes.sort_by_key(|outcome| outcome.index);
+    for (i, outcome) in outcomes.iter().enumerate() {
+        assert_eq!(outcome.index, i);
+        assert_eq!(recipient(outcome), format!("rcpt{}@example.com", i));
+        assert!(outcome.result.is_ok());
+    }
+    let messages = server.messages();
+    for i in 0..8 {
+        let to = format!("rcpt{}@example.com", i);
+        let message = messages
+            .iter()
+            .find(|message| message.to == vec![to.clone()])
+            .unwrap();
+        let subject = format!("Subject: Message {}", i);
+        assert!(String::from_utf8_lossy(&message.data).contains(&subject));
+    }
+}
@@ -0,0 +1,642 @@
+#![cfg(feature = "testing")]
+
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_io::block_on;
+use async_smtp_lite::lettre::{Address, ClientId, Credentials, Envelope, Mechanism};
+use async_smtp_lite::mta_sts::{MtaStsFailure, MtaStsTlsFailed};
+use async_smtp_lite::testing::{MockServer, MockStream};
+use async_smtp_lite::{
+    AsyncConnection, Metrics, MetricsPhase, MtaStsPolicy, StartTlsPolicy, Transcript,
+    TranscriptDirection, Verp,
+};
+use async_stream_packed::{TlsClientUpgrader, Upgrader};
+use async_trait::async_trait;
+use lettre::transport::smtp::error::Error;
+
+const EMAIL: &[u8] = b"Subject: Hi\r\n\r\nHello\r\n.dot";
+// subjectAltName DNS:mx.example.com, DNS:*.mx.example.net
+const MX_CERTIFICATE: &[u8] = include_bytes!("keys/mx_certificate.der");
+
+// Upgrades without encrypting, the mock server keeps talking in plaintext
+struct FakeTls;
+
+#[async_trait]
+impl Upgrader<MockStream> for FakeTls {
+    type Output = MockStream;
+
+    async fn upgrade(&mut self, stream: MockStream) -> io::Result<Self::Output> {
+        Ok(stream)
+    }
+}
+
+impl TlsClientUpgrader<MockStream> for FakeTls {}
+
+// Fails the negotiation like a handshake error
+struct FailingTls;
+
+#[async_trait]
+impl Upgrader<MockStream> for FailingTls {
+    type Output = MockStream;
+
+    async fn upgrade(&mut self, _stream: MockStream) -> io::Result<Self::Output> {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "handshake failed",
+        ))
+    }
+}
+
+impl TlsClientUpgrader<MockStream> for FailingTls {}
+
+fn address(address: &str) -> Address {
+    address.parse().unwrap()
+}
+
+fn envelope(to: &[&str]) -> Envelope {
+    Envelope::new(
+        Some(address("sender@example.com")),
+        to.iter().map(|to| address(to)).collect(),
+    )
+    .unwrap()
+}
+
+fn hello_name() -> ClientId {
+    ClientId::Domain("client.example.com".to_owned())
+}
+
+#[test]
+fn handshake() {
+    let server = MockServer::new()
+        .greeting("220 mx.example.com ESMTP ready")
+        .extensions(vec!["SIZE 1000", "8BITMIME"]);
+    let mut connection = server.connection();
+
+    block_on(connection.handshake(false, hello_name())).unwrap();
+
+    assert_eq!(
+        connection.greeting().unwrap().message,
+        vec!["mx.example.com ESMTP ready"]
+    );
+    assert_eq!(connection.capabilities().size(), Some(1000));
+    assert!(connection.capabilities().eight_bit_mime());
+    assert_eq!(server.commands(), vec!["EHLO client.example.com"]);
+}
+
+#[test]
+fn handshake_captures_the_greeting_and_capabilities() {
+    let server = MockServer::new()
+        .greeting("220-mx.example.com ESMTP\r\n220 No UCE")
+        .hostname("mx.example.com")
+        .extensions(vec!["AUTH=LOGIN", "AUTH LOGIN PLAIN", "XCLIENT NAME ADDR"]);
+    let mut connection = server.connection();
+
+    block_on(connection.handshake(false, hello_name())).unwrap();
+
+    assert_eq!(
+        connection.greeting().unwrap().message,
+        vec!["mx.example.com ESMTP", "No UCE"]
+    );
+    let capabilities = connection.capabilities();
+    assert_eq!(capabilities.name(), "mx.example.com");
+    assert_eq!(capabilities.auth_mechanisms(), ["LOGIN", "PLAIN"]);
+    assert_eq!(capabilities.params("XClient").unwrap(), ["NAME", "ADDR"]);
+}
+
+#[test]
+fn handshake_fails_on_rejected_greeting() {
+    let server = MockServer::new().greeting("554 5.3.2 Go away");
+    let mut connection = server.connection();
+
+    let err = block_on(connection.handshake(false, hello_name())).unwrap_err();
+
+    assert!(matches!(err, Error::Permanent(_)));
+    assert!(server.commands().is_empty());
+}
+
+#[test]
+fn delayed_greeting() {
+    let server = MockServer::new().greeting_delay(Duration::from_millis(50));
+    let mut connection = server.connection();
+
+    block_on(connection.handshake(false, hello_name())).unwrap();
+
+    assert_eq!(server.commands(), vec!["EHLO client.example.com"]);
+}
+
+#[test]
+fn starttls_when_advertised() {
+    let server = MockServer::new()
+        .extensions(vec!["STARTTLS"])
+        .reply("STARTTLS", "220 2.0.0 Ready to start TLS");
+    let mut connection = AsyncConnection::new(server.stream(), FakeTls);
+
+    block_on(connection.handshake(false, hello_name())).unwrap();
+
+    assert!(connection.is_encrypted());
+    assert_eq!(
+        server.commands(),
+        vec![
+            "EHLO client.example.com",
+            "STARTTLS",
+            "EHLO client.example.com"
+        ]
+    );
+}
+
+#[test]
+fn starttls_disabled() {
+    let server = MockServer::new().extensions(vec!["STARTTLS"]);
+    let mut connection = AsyncConnection::new(server.stream(), FakeTls);
+    connection.set_starttls_policy(StartTlsPolicy::Disabled);
+
+    block_on(connection.handshake(false, hello_name())).unwrap();
+
+    assert!(!connection.is_encrypted());
+    assert_eq!(server.commands(), vec!["EHLO client.example.com"]);
+}
+
+#[test]
+fn starttls_required_but_not_advertised() {
+    let server = MockServer::new();
+    let mut connection = AsyncConnection::new(server.stream(), FakeTls);
+    connection.set_starttls_policy(StartTlsPolicy::Required);
+
+    let err = block_on(connection.handshake(false, hello_name())).unwrap_err();
+
+    assert!(matches!(err, Error::Client(_)));
+    assert!(connection.has_broken());
+}
+
+#[test]
+fn starttls_opportunistic_without_advertisement() {
+    let server = MockServer::new();
+    let mut connection = AsyncConnection::new(server.stream(), FakeTls);
+
+    block_on(connection.handshake(false, hello_name())).unwrap();
+
+    assert!(!connection.is_encrypted());
+}
+
+fn mta_sts_policy(mode: &str) -> MtaStsPolicy {
+    format!(
+        "version: STSv1\nmode: {}\nmx: mx.example.com\nmax_age: 86400\n",
+        mode
+    )
+    .parse()
+    .unwrap()
+}
+
+fn starttls_server() -> MockServer {
+    MockServer::new()
+        .extensions(vec!["STARTTLS"])
+        .reply("STARTTLS", "220 2.0.0 Ready to start TLS")
+}
+
+#[test]
+fn mta_sts_valid_certificate() {
+    let server = starttls_server().peer_certificates(vec![MX_CERTIFICATE.to_vec()]);
+    let mut connection = AsyncConnection::new(server.stream(), FakeTls);
+    connection.set_mta_sts_policy(mta_sts_policy("enforce"), "mx.example.com");
+
+    block_on(connection.handshake(false, hello_name())).unwrap();
+
+    assert!(connection.is_encrypted());
+    assert!(connection.mta_sts_failures().is_empty());
+}
+
+#[test]
+fn mta_sts_enforce_rejects_certificate_for_another_host() {
+    let server = starttls_server().peer_certificates(vec![MX_CERTIFICATE.to_vec()]);
+    let mut connection = AsyncConnection::new(server.stream(), FakeTls);
+    let policy = "version: STSv1\nmode: enforce\nmx: *.example.org\nmax_age: 86400\n"
+        .parse()
+        .unwrap();
+    connection.set_mta_sts_policy(policy, "mx.example.org");
+
+    let err = block_on(connection.handshake(false, hello_name())).unwrap_err();
+
+    assert!(matches!(err, Error::Client(_)));
+    assert!(connection.has_broken());
+}
+
+#[test]
+fn mta_sts_testing_continues_without_certificate() {
+    let server = starttls_server();
+    let mut connection = AsyncConnection::new(server.stream(), FakeTls);
+    connection.set_mta_sts_policy(mta_sts_policy("testing"), "mx.example.com");
+
+    block_on(connection.handshake(false, hello_name())).unwrap();
+
+    assert!(connection.is_encrypted());
+    assert_eq!(
+        connection.mta_sts_failures(),
+        &[MtaStsFailure::CertificateMismatch]
+    );
+    assert_eq!(
+        server.commands(),
+        vec![
+            "EHLO client.example.com",
+            "STARTTLS",
+            "EHLO client.example.com"
+        ]
+    );
+}
+
+#[test]
+fn mta_sts_testing_continues_on_refused_starttls() {
+    let server = MockServer::new().extensions(vec!["STARTTLS"]);
+    let mut connection = AsyncConnection::new(server.stream(), FakeTls);
+    connection.set_mta_sts_policy(mta_sts_policy("testing"), "mx.example.com");
+
+    block_on(connection.handshake(false, hello_name())).unwrap();
+
+    assert!(!connection.is_encrypted());
+    assert_eq!(
+        connection.mta_sts_failures(),
+        &[MtaStsFailure::StarttlsNotSupported]
+    );
+    block_on(connection.send(&envelope(&["a@example.com"]), EMAIL)).unwrap();
+}
+
+#[test]
+fn mta_sts_enforce_fails_on_refused_starttls() {
+    let server = MockServer::new().extensions(vec!["STARTTLS"]);
+    let mut connection = AsyncConnection::new(server.stream(), FakeTls);
+    connection.set_mta_sts_policy(mta_sts_policy("enforce"), "mx.example.com");
+
+    let err = block_on(connection.handshake(false, hello_name())).unwrap_err();
+
+    assert!(matches!(err, Error::Client(_)));
+    assert!(connection.has_broken());
+}
+
+#[test]
+fn mta_sts_testing_reconnects_after_failed_negotiation() {
+    let server = starttls_server();
+    let mut connection = AsyncConnection::new(server.stream(), FailingTls);
+    connection.set_mta_sts_policy(mta_sts_policy("testing"), "mx.example.com");
+
+    let err = block_on(connection.handshake(false, hello_name())).unwrap_err();
+
+    assert!(MtaStsTlsFailed::is(&err));
+    assert!(connection.has_broken());
+    assert_eq!(connection.mta_sts_failures(), &[MtaStsFailure::TlsFailed]);
+
+    // Testing mode must not block the delivery
+    let mut connection = AsyncConnection::new(server.stream(), FailingTls);
+    connection.set_mta_sts_policy(mta_sts_policy("testing"), "mx.example.com");
+    connection.set_starttls_policy(StartTlsPolicy::Disabled);
+
+    block_on(connection.handshake(false, hello_name())).unwrap();
+    block_on(connection.send(&envelope(&["a@example.com"]), EMAIL)).unwrap();
+
+    assert_eq!(
+        connection.mta_sts_failures(),
+        &[MtaStsFailure::StarttlsNotSupported]
+    );
+    assert_eq!(server.messages().len(), 1);
+}
+
+#[test]
+fn mta_sts_enforce_fails_on_failed_negotiation() {
+    let server = starttls_server();
+    let mut connection = AsyncConnection::new(server.stream(), FailingTls);
+    connection.set_mta_sts_policy(mta_sts_policy("enforce"), "mx.example.com");
+
+    let err = block_on(connection.handshake(false, hello_name())).unwrap_err();
+
+    assert!(matches!(err, Error::Io(_)));
+    assert!(!MtaStsTlsFailed::is(&err));
+}
+
+#[test]
+fn auth_plain() {
+    let server = MockServer::new();
+    let mut connection = server.connection();
+    block_on(connection.handshake(false, hello_name())).unwrap();
+
+    let response = block_on(connection.auth(
+        &[Mechanism::Plain],
+        &Credentials::new("user".to_owned(), "secret".to_owned()),
+    ))
+    .unwrap();
+
+    assert_eq!(response.code.to_string(), "235");
+    // "\0user\0secret"
+    assert_eq!(server.commands()[1], "AUTH PLAIN AHVzZXIAc2VjcmV0");
+}
+
+#[test]
+fn auth_login() {
+    let server = MockServer::new();
+    let mut connection = server.connection();
+    block_on(connection.handshake(false, hello_name())).unwrap();
+
+    block_on(connection.auth(
+        &[Mechanism::Login],
+        &Credentials::new("user".to_owned(), "secret".to_owned()),
+    ))
+    .unwrap();
+
+    assert_eq!(
+        server.commands()[1..],
+        ["AUTH LOGIN", "dXNlcg==", "c2VjcmV0"]
+    );
+}
+
+#[test]
+fn auth_rejected() {
+    let server = MockServer::new().auth_reply("535 5.7.8 Authentication credentials invalid");
+    let mut connection = server.connection();
+    block_on(connection.handshake(false, hello_name())).unwrap();
+
+    let err = block_on(connection.auth(
+        &[Mechanism::Plain],
+        &Credentials::new("user".to_owned(), "wrong".to_owned()),
+    ))
+    .unwrap_err();
+
+    assert!(matches!(err, Error::Permanent(_)));
+}
+
+#[test]
+fn send() {
+    let server = MockServer::new();
+    let mut connection = server.connection();
+    block_on(connection.handshake(false, hello_name())).unwrap();
+
+    let response =
+        block_on(connection.send(&envelope(&["a@example.com", "b@example.com"]), EMAIL)).unwrap();
+
+    assert_eq!(response.message, vec!["2.0.0 Ok: queued as MOCK1"]);
+    let messages = server.messages();
+    assert_eq!(messages.len(), 1);
+    assert_eq!(messages[0].from, "sender@example.com");
+    assert_eq!(messages[0].to, vec!["a@example.com", "b@example.com"]);
+    // Unstuffed, with the CRLF before the final dot
+    assert_eq!(messages[0].data, [EMAIL, b"\r\n"].concat());
+}
+
+#[test]
+fn send_fails_on_first_rejected_recipient() {
+    let server = MockServer::new().reply("RCPT", "550 5.1.1 No such user");
+    let mut connection = server.connection();
+    block_on(connection.handshake(false, hello_name())).unwrap();
+
+    let err = block_on(connection.send(&envelope(&["a@example.com", "b@example.com"]), EMAIL))
+        .unwrap_err();
+
+    assert!(matches!(err, Error::Permanent(_)));
+    assert!(server.messages().is_empty());
+}
+
+#[test]
+fn send_with_receipt() {
+    let server = MockServer::new().reply("RCPT", "550 5.1.1 No such user");
+    let mut connection = server.connection();
+    block_on(connection.handshake(false, hello_name())).unwrap();
+
+    let receipt = block_on(
+        connection.send_with_receipt(&envelope(&["a@example.com", "b@example.com"]), EMAIL),
+    )
+    .unwrap();
+
+    assert_eq!(receipt.queue_id.as_deref(), Some("MOCK1"));
+    assert_eq!(receipt.size, EMAIL.len());
+    assert_eq!(receipt.recipients.len(), 2);
+    assert!(matches!(
+        receipt.recipients[0].result,
+        Err(Error::Permanent(_))
+    ));
+    assert!(receipt.recipients[1].result.is_ok());
+    assert_eq!(server.messages()[0].to, vec!["b@example.com"]);
+}
+
+#[test]
+fn send_with_receipt_fails_without_accepted_recipient() {
+    let server = MockServer::new().reply("RCPT", "450 4.2.0 Mailbox busy");
+    let mut connection = server.connection();
+    block_on(connection.handshake(false, hello_name())).unwrap();
+
+    let err =
+        block_on(connection.send_with_receipt(&envelope(&["a@example.com"]), EMAIL)).unwrap_err();
+
+    assert!(matches!(err, Error::Transient(_)));
+    assert!(!connection.has_broken());
+    assert_eq!(server.commands().last().unwrap(), "RSET");
+}
+
+#[test]
+fn lmtp_per_recipient_replies() {
+    let server = MockServer::new()
+        .reply("RCPT", "250 2.1.5 Ok")
+        .reply("RCPT", "550 5.1.1 No such user")
+        .reply(".", "250 2.0.0 Delivered to a\r\n452 4.2.2 Mailbox full");
+    let mut connection = server.connection();
+    connection.set_lmtp(true);
+    block_on(connection.handshake(false, hello_name())).unwrap();
+
+    let results = block_on(connection.send_lmtp(
+        &envelope(&["a@example.com", "b@example.com", "c@example.com"]),
+        EMAIL,
+    ))
+    .unwrap();
+
+    assert_eq!(server.commands()[0], "LHLO client.example.com");
+    assert_eq!(results.len(), 3);
+    assert_eq!(results[0].recipient, address("a@example.com"));
+    assert!(results[0].result.is_ok());
+    assert!(matches!(results[1].result, Err(Error::Permanent(_))));
+    assert!(matches!(results[2].result, Err(Error::Transient(_))));
+}
+
+#[derive(Default)]
+struct DataLatencies(Mutex<Vec<bool>>);
+
+impl Metrics for DataLatencies {
+    fn latency(&self, phase: MetricsPhase, _duration: Duration, success: bool) {
+        if phase == MetricsPhase::Data {
+            self.0.lock().unwrap().push(success);
+        }
+    }
+}
+
+#[test]
+fn lmtp_data_metrics() {
+    let server = MockServer::new()
+        .reply(".", "250 2.0.0 Delivered to a\r\n452 4.2.2 Mailbox full")
+        .reply(".", "452 4.2.2 Mailbox full\r\n550 5.1.1 No such user");
+    let metrics = Arc::new(DataLatencies::default());
+    let mut connection = server.connection();
+    connection.set_lmtp(true);
+    connection.set_metrics(metrics.clone());
+    block_on(connection.handshake(false, hello_name())).unwrap();
+
+    let recipients = envelope(&["a@example.com", "b@example.com"]);
+    block_on(connection.send_lmtp(&recipients, EMAIL)).unwrap();
+    block_on(connection.send_lmtp(&recipients, EMAIL)).unwrap();
+
+    assert_eq!(*metrics.0.lock().unwrap(), vec![true, false]);
+}
+
+#[test]
+fn transcript_redacts_auth_and_data() {
+    let server = MockServer::new();
+    let mut connection = server.connection();
+    let transcript = Transcript::new();
+    connection.set_transcript_hook(transcript.clone());
+    block_on(connection.handshake(false, hello_name())).unwrap();
+
+    block_on(connection.auth(
+        &[Mechanism::Plain],
+        &Credentials::new("user".to_owned(), "secret".to_owned()),
+    ))
+    .unwrap();
+    block_on(connection.send(&envelope(&["a@example.com"]), EMAIL)).unwrap();
+
+    let sent: Vec<_> = transcript
+        .entries()
+        .into_iter()
+        .filter(|entry| entry.direction == TranscriptDirection::Sent)
+        .map(|entry| entry.line)
+        .collect();
+    assert!(sent.contains(&"AUTH PLAIN <redacted>".to_owned()));
+    assert!(sent.contains(&format!("<redacted> ({} bytes)", EMAIL.len())));
+    assert!(!sent.iter().any(|line| line.contains("Hello")));
+    assert!(!sent.iter().any(|line| line.contains("AHVzZXIAc2VjcmV0")));
+}
+
+#[test]
+fn transcript_with_data() {
+    let server = MockServer::new();
+    let mut connection = server.connection();
+    let transcript = Transcript::new();
+    connection.set_transcript_hook(transcript.clone());
+    connection.set_transcript_data(true);
+    block_on(connection.handshake(false, hello_name())).unwrap();
+
+    block_on(connection.send(&envelope(&["a@example.com"]), EMAIL)).unwrap();
+
+    assert!(transcript
+        .entries()
+        .iter()
+        .any(|entry| entry.line.contains("Hello")));
+}
+
+#[test]
+fn verp_per_recipient_transactions() {
+    let server = MockServer::new()
+        .reply("RCPT", "250 2.1.5 Ok")
+        .reply("RCPT", "550 5.1.1 No such user");
+    let mut connection = server.connection();
+    connection.set_verp(Verp::new());
+    block_on(connection.handshake(false, hello_name())).unwrap();
+
+    let receipt = block_on(connection.send_with_receipt(
+        &envelope(&["a@example.com", "b@example.net", "c@example.org"]),
+        EMAIL,
+    ))
+    .unwrap();
+
+    assert!(receipt.recipients[0].result.is_ok());
+    assert!(matches!(
+        receipt.recipients[1].result,
+        Err(Error::Permanent(_))
+    ));
+    assert!(receipt.recipients[2].result.is_ok());
+    assert_eq!(
+        server.commands()[1..],
+        [
+            "MAIL FROM:<sender+a=example.com@example.com> BODY=8BITMIME",
+            "RCPT TO:<a@example.com>",
+            "DATA",
+            ".",
+            "MAIL FROM:<sender+b=example.net@example.com> BODY=8BITMIME",
+            "RCPT TO:<b@example.net>",
+            "RSET",
+            "MAIL FROM:<sender+c=example.org@example.com> BODY=8BITMIME",
+            "RCPT TO:<c@example.org>",
+            "DATA",
+            ".",
+        ]
+    );
+    let messages = server.messages();
+    assert_eq!(messages.len(), 2);
+    assert_eq!(messages[1].from, "sender+c=example.org@example.com");
+}
+
+#[test]
+fn verp_send_returns_first_rejection() {
+    let server = MockServer::new()
+        .reply("RCPT", "250 2.1.5 Ok")
+        .reply("RCPT", "550 5.1.1 No such user");
+    let mut connection = server.connection();
+    connection.set_verp(Verp::new());
+    block_on(connection.handshake(false, hello_name())).unwrap();
+
+    let err = block_on(connection.send(&envelope(&["a@example.com", "b@example.net"]), EMAIL))
+        .unwrap_err();
+
+    assert!(matches!(err, Error::Permanent(_)));
+    assert_eq!(server.messages().len(), 1);
+}
+
+#[test]
+fn verp_resets_after_rejected_data() {
+    let server = MockServer::new().reply(".", "554 5.7.1 Message rejected");
+    let mut connection = server.connection();
+    connection.set_verp(Verp::new());
+    block_on(connection.handshake(false, hello_name())).unwrap();
+
+    let receipt = block_on(
+        connection.send_with_receipt(&envelope(&["a@example.com", "b@example.net"]), EMAIL),
+    )
+    .unwrap();
+
+    assert!(matches!(
+        receipt.recipients[0].result,
+        Err(Error::Permanent(_))
+    ));
+    assert!(receipt.recipients[1].result.is_ok());
+    assert_eq!(
+        server.commands()[1..],
+        [
+            "MAIL FROM:<sender+a=example.com@example.com> BODY=8BITMIME",
+            "RCPT TO:<a@example.com>",
+            "DATA",
+            ".",
+            "RSET",
+            "MAIL FROM:<sender+b=example.net@example.com> BODY=8BITMIME",
+            "RCPT TO:<b@example.net>",
+            "DATA",
+            ".",
+        ]
+    );
+}
+
+#[test]
+fn verp_skips_recipient_over_the_local_part_limit() {
+    let server = MockServer::new();
+    let mut connection = server.connection();
+    connection.set_verp(Verp::new());
+    block_on(connection.handshake(false, hello_name())).unwrap();
+
+    let long = format!("{}@example.com", "a".repeat(60));
+    let receipt =
+        block_on(connection.send_with_receipt(&envelope(&[long.as_str(), "b@example.net"]), EMAIL))
+            .unwrap();
+
+    assert!(matches!(
+        receipt.recipients[0].result,
+        Err(Error::Client(_))
+    ));
+    assert!(receipt.recipients[1].result.is_ok());
+    assert_eq!(
+        server.commands()[1],
+        "MAIL FROM:<sender+b=example.net@example.com> BODY=8BITMIME"
+    );
+    assert_eq!(server.messages().len(), 1);
+}
@@ -0,0 +1,404 @@
+#![cfg(all(feature = "spool", feature = "testing"))]
+
+use std::cell::RefCell;
+use std::env;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, UNIX_EPOCH};
+
+use async_io::{block_on, Timer};
+use async_smtp_lite::connector::Runtime;
+use async_smtp_lite::lettre::{Address, ClientId, Envelope};
+use async_smtp_lite::testing::{MockServer, MockStream};
+use async_smtp_lite::{
+    ConnectOptions, FileSpool, NoTls, RetrySchedule, SpoolEntry, SpoolOutcome, SpoolStorage,
+    SpoolWorker,
+};
+use async_trait::async_trait;
+
+const EMAIL: &[u8] = b"Subject: Hi\r\n\r\nHello";
+
+thread_local! {
+    static SERVER: RefCell<Option<Arc<MockServer>>> = const { RefCell::new(None) };
+}
+
+// Every connection goes to the thread's mock server
+struct MockRuntime;
+
+#[async_trait]
+impl Runtime for MockRuntime {
+    type TcpStream = MockStream;
+
+    async fn resolve(_host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        Ok(vec![SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port)])
+    }
+
+    async fn connect(_addr: SocketAddr) -> io::Result<Self::TcpStream> {
+        SERVER.with(|server| match &*server.borrow() {
+            Some(server) => Ok(server.stream()),
+            None => Err(io::ErrorKind::ConnectionRefused.into()),
+        })
+    }
+
+    async fn connect_from(_local: IpAddr, addr: SocketAddr) -> io::Result<Self::TcpStream> {
+        Self::connect(addr).await
+    }
+
+    fn local_addr(_stream: &Self::TcpStream) -> io::Result<SocketAddr> {
+        Ok(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0))
+    }
+
+    async fn sleep(duration: Duration) {
+        Timer::after(duration).await;
+    }
+}
+
+#[derive(Default)]
+struct MemorySpool {
+    entries: Mutex<Vec<SpoolEntry>>,
+}
+
+impl MemorySpool {
+    fn entries(&self) -> Vec<SpoolEntry> {
+        self.entries.lock().unwrap().clone()
+    }
+}
+
+#[async_trait]
+impl SpoolStorage for MemorySpool {
+    async fn store(&self, entry: &SpoolEntry) -> io::Result<()> {
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|stored| stored.id != entry.id);
+        entries.push(entry.clone());
+        Ok(())
+    }
+
+    async fn list(&self) -> io::Result<Vec<SpoolEntry>> {
+        Ok(self.entries())
+    }
+
+    async fn remove(&self, id: &str) -> io::Result<()> {
+        self.entries
+            .lock()
+            .unwrap()
+            .retain(|stored| stored.id != id);
+        Ok(())
+    }
+}
+
+fn address(address: &str) -> Address {
+    address.parse().unwrap()
+}
+
+fn worker(
+    server: MockServer,
+    storage: Arc<MemorySpool>,
+    schedule: RetrySchedule,
+) -> SpoolWorker<MockRuntime, impl Fn(&str) -> NoTls> {
+    SERVER.with(|current| *current.borrow_mut() = Some(Arc::new(server)));
+    let options = ConnectOptions::new(ClientId::Domain("client.example.com".to_owned()));
+    SpoolWorker::new(storage, "relay.example.com", 25, options, |_| NoTls)
+        .schedule(schedule)
+        .reporting_mta("client.example.com")
+}
+
+fn mock_server() -> Arc<MockServer> {
+    SERVER.with(|server| server.borrow().clone().unwrap())
+}
+
+fn enqueue(storage: &MemorySpool, to: &[&str]) -> String {
+    let envelope = Envelope::new(
+        Some(address("sender@example.com")),
+        to.iter().map(|to| address(to)).collect(),
+    )
+    .unwrap();
+    let entry = SpoolEntry::new(&envelope, EMAIL.to_vec());
+    block_on(storage.store(&entry)).unwrap();
+    entry.id
+}
+
+fn retry_now() -> RetrySchedule {
+    RetrySchedule::new(vec![Duration::from_secs(0)], Duration::from_secs(3600))
+}
+
+#[test]
+fn delivers_and_removes() {
+    let storage = Arc::new(MemorySpool::default());
+    let id = enqueue(&storage, &["a@example.com", "b@example.com"]);
+    let worker = worker(MockServer::new(), storage.clone(), retry_now());
+
+    let outcomes = block_on(worker.run_once()).unwrap();
+
+    assert_eq!(outcomes.len(), 1);
+    match &outcomes[0] {
+        SpoolOutcome::Delivered {
+            id: delivered_id,
+            recipients,
+            ..
+        } => {
+            assert_eq!(delivered_id, &id);
+            assert_eq!(recipients.len(), 2);
+        }
+        outcome => panic!("{:?}", outcome),
+    }
+    assert!(storage.entries().is_empty());
+    assert_eq!(mock_server().messages().len(), 1);
+}
+
+#[test]
+fn splits_the_recipients_by_their_result() {
+    let storage = Arc::new(MemorySpool::default());
+    let id = enqueue(
+        &storage,
+        &["a@example.com", "b@example.com", "c@example.com"],
+    );
+    let server = MockServer::new()
+        .reply("RCPT", "250 2.1.5 Ok")
+        .reply("RCPT", "550 5.1.1 No such user")
+        .reply("RCPT", "450 4.2.0 Mailbox busy");
+    let worker = worker(server, storage.clone(), retry_now());
+
+    let outcomes = block_on(worker.run_once()).unwrap();
+
+    assert_eq!(outcomes.len(), 3);
+    assert!(matches!(
+        &outcomes[0],
+        SpoolOutcome::Delivered { recipients, .. } if recipients == &[address("a@example.com")]
+    ));
+    let bounce_id = match &outcomes[1] {
+        SpoolOutcome::Failed {
+            recipients,
+            bounce_id,
+            ..
+        } => {
+            assert_eq!(recipients, &[address("b@example.com")]);
+            bounce_id.clone().unwrap()
+        }
+        outcome => panic!("{:?}", outcome),
+    };
+    assert!(matches!(
+        &outcomes[2],
+        SpoolOutcome::Deferred { recipients, .. } if recipients == &[address("c@example.com")]
+    ));
+    assert_eq!(mock_server().messages()[0].to, vec!["a@example.com"]);
+
+    let entries = storage.entries();
+    assert_eq!(entries.len(), 2);
+    let deferred = entries.iter().find(|entry| entry.id == id).unwrap();
+    assert_eq!(deferred.to, vec![address("c@example.com")]);
+    assert_eq!(deferred.attempts, 1);
+    assert!(deferred.last_error.is_some());
+
+    // The DSN lists the rejected recipient only
+    let bounce = entries.iter().find(|entry| entry.id == bounce_id).unwrap();
+    assert_eq!(bounce.from, None);
+    assert_eq!(bounce.to, vec![address("sender@example.com")]);
+    let dsn = String::from_utf8_lossy(&bounce.message);
+    assert!(dsn.contains("Final-Recipient: rfc822; b@example.com"));
+    assert!(!dsn.contains("Final-Recipient: rfc822; a@example.com"));
+    assert!(!dsn.contains("Final-Recipient: rfc822; c@example.com"));
+
+    // The retry is for the deferred recipient only, the DSN is sent too
+    let outcomes = block_on(worker.run_once()).unwrap();
+    assert_eq!(outcomes.len(), 2);
+    assert!(storage.entries().is_empty());
+    let messages = mock_server().messages();
+    assert_eq!(messages.len(), 3);
+    assert!(
+        messages
+            .iter()
+            .any(|message| message.from == "sender@example.com"
+                && message.to == vec!["c@example.com"])
+    );
+}
+
+#[test]
+fn bounces_the_deferred_recipients_once_expired() {
+    let storage = Arc::new(MemorySpool::default());
+    let id = enqueue(&storage, &["a@example.com", "b@example.com"]);
+    let server = MockServer::new()
+        .reply("RCPT", "250 2.1.5 Ok")
+        .reply("RCPT", "450 Mailbox busy");
+    let schedule = RetrySchedule::new(vec![Duration::from_secs(0)], Duration::from_secs(0));
+    let worker = worker(server, storage.clone(), schedule);
+
+    let outcomes = block_on(worker.run_once()).unwrap();
+
+    assert_eq!(outcomes.len(), 2);
+    assert!(
+        matches!(&outcomes[1], SpoolOutcome::Failed { recipients, .. } if recipients == &[address("b@example.com")])
+    );
+
+    let entries = storage.entries();
+    assert_eq!(entries.len(), 1);
+    assert_ne!(entries[0].id, id);
+    let dsn = String::from_utf8_lossy(&entries[0].message);
+    assert!(dsn.contains("Status: 4.4.7"));
+}
+
+#[test]
+fn defers_all_the_recipients_on_a_failed_transaction() {
+    let storage = Arc::new(MemorySpool::default());
+    let id = enqueue(&storage, &["a@example.com", "b@example.com"]);
+    let server = MockServer::new().reply("MAIL", "451 4.3.0 Try again later");
+    let worker = worker(server, storage.clone(), retry_now());
+
+    let outcomes = block_on(worker.run_once()).unwrap();
+
+    assert_eq!(outcomes.len(), 1);
+    assert!(
+        matches!(&outcomes[0], SpoolOutcome::Deferred { recipients, .. } if recipients.len() == 2)
+    );
+    let entries = storage.entries();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].id, id);
+    assert_eq!(entries[0].to.len(), 2);
+}
+
+#[test]
+fn bounce_of_a_bounce_is_dropped() {
+    let storage = Arc::new(MemorySpool::default());
+    let envelope = Envelope::new(None, vec![address("a@example.com")]).unwrap();
+    block_on(storage.store(&SpoolEntry::new(&envelope, EMAIL.to_vec()))).unwrap();
+    let server = MockServer::new().reply("RCPT", "550 5.1.1 No such user");
+    let worker = worker(server, storage.clone(), retry_now());
+
+    let outcomes = block_on(worker.run_once()).unwrap();
+
+    assert!(matches!(
+        &outcomes[0],
+        SpoolOutcome::Failed {
+            bounce_id: None,
+            ..
+        }
+    ));
+    assert!(storage.entries().is_empty());
+}
+
+//
+//
+//
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new(name: &str) -> Self {
+        let path = env::temp_dir().join(format!("async-smtp-lite-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&path);
+        Self(path)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+fn entry(from: Option<&str>, to: &[&str]) -> SpoolEntry {
+    let envelope =
+        Envelope::new(from.map(address), to.iter().map(|to| address(to)).collect()).unwrap();
+    SpoolEntry::new(&envelope, EMAIL.to_vec())
+}
+
+#[test]
+fn file_spool_round_trip() {
+    let dir = TempDir::new("round-trip");
+    let spool = FileSpool::new(&dir.0).unwrap();
+    let first = entry(
+        Some("sender@example.com"),
+        &["a@example.com", "b@example.com"],
+    );
+    let second = entry(None, &["sender@example.com"]);
+    block_on(spool.store(&first)).unwrap();
+    block_on(spool.store(&second)).unwrap();
+
+    let entries = block_on(spool.list()).unwrap();
+
+    assert_eq!(entries.len(), 2);
+    let stored = entries.iter().find(|entry| entry.id == first.id).unwrap();
+    assert_eq!(stored.from, Some(address("sender@example.com")));
+    assert_eq!(stored.to, first.to);
+    assert_eq!(stored.message, EMAIL);
+    assert_eq!(stored.attempts, 0);
+    assert_eq!(stored.last_error, None);
+    // Stored in seconds
+    assert_eq!(
+        stored.created.duration_since(UNIX_EPOCH).unwrap().as_secs(),
+        first.created.duration_since(UNIX_EPOCH).unwrap().as_secs()
+    );
+
+    // The null reverse-path
+    let bounce = entries.iter().find(|entry| entry.id == second.id).unwrap();
+    assert_eq!(bounce.from, None);
+    assert_eq!(bounce.envelope().unwrap().from(), None);
+
+    block_on(spool.remove(&first.id)).unwrap();
+    block_on(spool.remove(&first.id)).unwrap();
+    let entries = block_on(spool.list()).unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].id, second.id);
+}
+
+#[test]
+fn file_spool_updates_the_delivery_state() {
+    let dir = TempDir::new("state");
+    let spool = FileSpool::new(&dir.0).unwrap();
+    let mut entry = entry(
+        Some("sender@example.com"),
+        &["a@example.com", "b@example.com"],
+    );
+    block_on(spool.store(&entry)).unwrap();
+
+    entry.to = vec![address("b@example.com")];
+    entry.attempts = 2;
+    entry.next_attempt = UNIX_EPOCH + Duration::from_secs(2_000_000_000);
+    entry.last_error = Some("451 4.3.0 Try\nagain later".to_owned());
+    block_on(spool.store(&entry)).unwrap();
+
+    let entries = block_on(spool.list()).unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].to, vec![address("b@example.com")]);
+    assert_eq!(entries[0].attempts, 2);
+    assert_eq!(entries[0].next_attempt, entry.next_attempt);
+    assert_eq!(
+        entries[0].last_error.as_deref(),
+        Some("451 4.3.0 Try again later")
+    );
+}
+
+#[test]
+fn file_spool_rejects_invalid_ids() {
+    let dir = TempDir::new("ids");
+    let spool = FileSpool::new(&dir.0).unwrap();
+
+    for id in &["", "../escape", "a/b", "a\\b", "a.b"] {
+        let mut entry = entry(Some("sender@example.com"), &["a@example.com"]);
+        entry.id = id.to_string();
+        let err = block_on(spool.store(&entry)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput, "{}", id);
+    }
+    assert!(block_on(spool.list()).unwrap().is_empty());
+}
+
+#[test]
+fn file_spool_skips_incomplete_entries() {
+    let dir = TempDir::new("incomplete");
+    let spool = FileSpool::new(&dir.0).unwrap();
+    let entry = entry(Some("sender@example.com"), &["a@example.com"]);
+    block_on(spool.store(&entry)).unwrap();
+
+    // Interrupted writes
+    fs::write(dir.0.join("other.eml.tmp"), EMAIL).unwrap();
+    fs::write(dir.0.join("other.env.tmp"), "from \nto a@example.com\n").unwrap();
+    // An envelope without its message
+    fs::write(dir.0.join("missing.env"), "from \nto a@example.com\n").unwrap();
+
+    let entries = block_on(spool.list()).unwrap();
+
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].id, entry.id);
+}